@@ -29,26 +29,57 @@ fn main() {
     //
     // Declaring the manifest also turns ACL checking on for local origins — hence iOS, which loads
     // its assets locally, grants its commands in `capabilities/ios.json` too.
+    let mut commands = vec![
+        "cache_plugin_assets",
+        "evict_plugin",
+        "resolve_cached_url",
+        "resolve_cached_urls",
+        "plugin_import_map",
+        "list_cached_plugins",
+        "plugin_cache_stats",
+        "reset_plugin_cache_stats",
+        "set_plugin_cache_read_through",
+        "set_plugin_revalidation",
+        "set_plugin_cache_encryption",
+        "rotate_plugin_cache_key",
+        "pin_plugin",
+        "unpin_plugin",
+        "start_oauth_server",
+        "stop_oauth_server",
+        "take_oauth_result",
+        "wait_for_oauth_result",
+        "cancel_oauth_wait",
+        "get_oauth_recovery_result",
+        "wait_for_oauth_recovery_result",
+        "cancel_oauth_recovery_wait",
+        "initiate_oauth_flow",
+        "start_native_oauth_flow",
+        "refresh_oauth_token",
+        "store_credential",
+        "get_credential",
+        "list_credentials",
+        "delete_credential",
+        "credential_vault_backend",
+        "get_xattr",
+        "set_xattr",
+        "remove_xattr",
+        "hide_spotlight",
+        "list_audio_inputs",
+        "set_preferred_audio_input",
+        "start_microphone_bridge",
+        "stop_microphone_bridge",
+    ];
+    // The dev-plugin commands exist in debug builds only (see `asset_cache::dev`), and so do their
+    // permissions, granted at runtime in `lib.rs`. Cargo describes the crate being built, not this
+    // script, in `CARGO_CFG_*`.
+    if std::env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some() {
+        commands.extend(["register_dev_plugin", "unregister_dev_plugin"]);
+    }
+    // The manifest wants a `'static` list; this script exits right after.
+    let commands: &'static [&'static str] = commands.leak();
     tauri_build::try_build(
-        tauri_build::Attributes::new().app_manifest(tauri_build::AppManifest::new().commands(&[
-            "cache_plugin_assets",
-            "evict_plugin",
-            "resolve_cached_url",
-            "list_cached_plugins",
-            "start_oauth_server",
-            "stop_oauth_server",
-            "get_oauth_result",
-            "get_oauth_recovery_result",
-            "initiate_oauth_flow",
-            "get_xattr",
-            "set_xattr",
-            "remove_xattr",
-            "hide_spotlight",
-            "list_audio_inputs",
-            "set_preferred_audio_input",
-            "start_microphone_bridge",
-            "stop_microphone_bridge",
-        ])),
+        tauri_build::Attributes::new()
+            .app_manifest(tauri_build::AppManifest::new().commands(commands)),
     )
     .expect("failed to run tauri-build");
 }
@@ -0,0 +1,531 @@
+//! Maintenance of a plugin cache from outside the app, for the `plugin-cache` command-line tool
+//! (`src/bin/plugin-cache.rs`).
+//!
+//! `PluginCache` works on the directories Tauri would hand the app (`app_data_dir` for the cache,
+//! `app_config_dir` for its key file) and goes through the same layout, sealing and migration code
+//! as the Tauri commands. It takes no lock the app would see, so it should only be pointed at the
+//! cache of an app that isn't running.
+//!
+//! Opening a cache never changes it. A cache in an older format (one the app hasn't run against
+//! since an upgrade) can be inspected as is, but must be migrated explicitly before anything
+//! writes to it.
+//!
+//! Exports are plaintext copies of a plugin directory — the same layout, with every file opened —
+//! so they can be inspected by hand and imported on a machine with different keys.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::layout::{
+    asset_path, digest, files_under, hash, indexed_plugins, meta_path, plugin_dirs, read_index,
+    read_meta, remove_plugin, url_path, write_atomic, Index, CACHE_DIR, INDEX_FILE, KEY_FILE,
+};
+use super::migrate;
+use super::seal::Keys;
+
+/// Extension of the directory an import is assembled in before it is renamed into place.
+pub(super) const STAGING_EXTENSION: &str = "import";
+
+/// A plugin cache opened from its app's directories.
+pub struct PluginCache {
+    root: PathBuf,
+    keys: Keys,
+    outdated: bool,
+}
+
+/// One cached plugin, as listed by `PluginCache::plugins`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginInfo {
+    pub plugin_id: String,
+    /// Directory name under `plugin-cache/`, which is also the `dxos-plugin://` host.
+    pub dir: String,
+    pub pinned: bool,
+    /// URLs listed in the plugin's index.
+    pub urls: usize,
+    /// Cached files, listed or fetched by read-through, not counting sidecars.
+    pub files: usize,
+    /// Bytes on disk, sidecars and index included.
+    pub bytes: u64,
+}
+
+/// One cached (or listed but missing) file of a plugin.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetInfo {
+    pub url: String,
+    /// Path within the plugin directory.
+    pub path: String,
+    /// Whether both the file and its sidecar are present.
+    pub cached: bool,
+    pub mime: Option<String>,
+    /// Size on disk, which includes the sealing overhead when the cache is encrypted.
+    pub bytes: Option<u64>,
+    pub fetched_at: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// Something `PluginCache::verify` found wrong.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    /// Plugin id, or the directory name when the index can't be read.
+    pub plugin: String,
+    /// Path within the plugin directory.
+    pub path: String,
+    pub issue: String,
+}
+
+impl PluginCache {
+    /// Opens the cache under `data_dir` with the keys under `config_dir`, as it is. Fails for a
+    /// cache written by a newer build.
+    pub fn open(data_dir: &Path, config_dir: &Path) -> Result<Self, String> {
+        if !data_dir.is_dir() {
+            return Err(format!("{} is not a directory", data_dir.display()));
+        }
+        let root = data_dir.join(CACHE_DIR);
+        let keys = Keys::load(&config_dir.join(KEY_FILE))?;
+        let outdated = migrate::outdated(&root, &keys)?;
+        Ok(Self {
+            root,
+            keys,
+            outdated,
+        })
+    }
+
+    /// Whether some plugin is in an older format than this build writes, so the cache needs
+    /// `migrate` before it can be changed.
+    pub fn outdated(&self) -> bool {
+        self.outdated
+    }
+
+    /// Brings the cache up to the current format, as the app does at launch.
+    pub fn migrate(&mut self) -> Result<(), String> {
+        migrate::migrate(&self.root, &self.keys)?;
+        self.outdated = false;
+        Ok(())
+    }
+
+    /// Every cached plugin, by id.
+    pub async fn plugins(&self) -> Result<Vec<PluginInfo>, String> {
+        let mut plugins = Vec::new();
+        for (dir, index) in indexed_plugins(&self.keys, &self.root).await? {
+            let files = files_under(&dir).await?;
+            let mut bytes = 0;
+            for file in &files {
+                bytes += tokio::fs::metadata(file)
+                    .await
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+            }
+            plugins.push(PluginInfo {
+                plugin_id: index.plugin_id,
+                dir: dir_name(&dir),
+                pinned: index.pinned,
+                urls: index.urls.len(),
+                files: files.iter().filter(|file| is_asset(&dir, file)).count(),
+                bytes,
+            });
+        }
+        plugins.sort_by(|a, b| a.plugin_id.cmp(&b.plugin_id));
+        Ok(plugins)
+    }
+
+    /// The files of `plugin` (an id or directory name): every listed URL, cached or not, then
+    /// whatever else read-through stored.
+    pub async fn assets(&self, plugin: &str) -> Result<Vec<AssetInfo>, String> {
+        let (dir, index) = self.find(plugin).await?;
+        let mut assets = Vec::new();
+        let mut seen = HashSet::new();
+        for url in &index.urls {
+            seen.insert(asset_path(&dir, url)?);
+            assets.push(self.asset(&dir, url).await?);
+        }
+        let mut extra = Vec::new();
+        for file in files_under(&dir).await? {
+            if !is_sidecar(&file) || seen.contains(&file.with_extension("")) {
+                continue;
+            }
+            if let Some(meta) = read_meta(&self.keys, &file).await {
+                extra.push(self.asset(&dir, &meta.url).await?);
+            }
+        }
+        extra.sort_by(|a, b| a.path.cmp(&b.path));
+        assets.extend(extra);
+        Ok(assets)
+    }
+
+    async fn asset(&self, dir: &Path, url: &str) -> Result<AssetInfo, String> {
+        let bytes = tokio::fs::metadata(asset_path(dir, url)?)
+            .await
+            .ok()
+            .map(|metadata| metadata.len());
+        let meta = read_meta(&self.keys, &meta_path(dir, url)?).await;
+        Ok(AssetInfo {
+            url: url.to_string(),
+            path: url_path(url)?,
+            cached: bytes.is_some() && meta.is_some(),
+            mime: meta.as_ref().map(|meta| meta.mime.clone()),
+            bytes,
+            fetched_at: meta.as_ref().map(|meta| meta.fetched_at),
+            sha256: meta.and_then(|meta| meta.sha256),
+        })
+    }
+
+    /// Checks `plugin`, or every plugin directory when `None`: listed files are present, every file
+    /// has a readable sidecar and opens with the current keys, and content matches its recorded
+    /// digest. Returns what is wrong; an empty list means the cache is sound.
+    pub async fn verify(&self, plugin: Option<&str>) -> Result<Vec<Problem>, String> {
+        let dirs = match plugin {
+            Some(plugin) => vec![self.find(plugin).await?.0],
+            None => plugin_dirs(&self.root).await?,
+        };
+        let mut problems = Vec::new();
+        for dir in dirs {
+            self.verify_plugin(&dir, &mut problems).await?;
+        }
+        Ok(problems)
+    }
+
+    async fn verify_plugin(&self, dir: &Path, problems: &mut Vec<Problem>) -> Result<(), String> {
+        let Some(index) = read_index(&self.keys, dir).await else {
+            problems.push(problem(&dir_name(dir), INDEX_FILE, "missing or unreadable"));
+            return Ok(());
+        };
+        let plugin = index.plugin_id.as_str();
+        if hash(plugin) != dir_name(dir) {
+            problems.push(problem(
+                plugin,
+                INDEX_FILE,
+                "names a plugin that belongs in another directory",
+            ));
+        }
+        for url in &index.urls {
+            let cached = tokio::fs::metadata(asset_path(dir, url)?).await.is_ok()
+                && tokio::fs::metadata(meta_path(dir, url)?).await.is_ok();
+            if !cached {
+                problems.push(problem(plugin, &url_path(url)?, "listed but not cached"));
+            }
+        }
+        for file in files_under(dir).await? {
+            let path = relative(dir, &file);
+            if path == INDEX_FILE {
+                continue;
+            }
+            if is_tmp(&file) {
+                problems.push(problem(
+                    plugin,
+                    &path,
+                    "left over from an interrupted write",
+                ));
+                continue;
+            }
+            if is_sidecar(&file) {
+                if tokio::fs::metadata(file.with_extension("")).await.is_err() {
+                    problems.push(problem(plugin, &path, "sidecar without a file"));
+                }
+                continue;
+            }
+            let mut sidecar = file.clone();
+            sidecar.as_mut_os_string().push(".meta");
+            let Some(meta) = read_meta(&self.keys, &sidecar).await else {
+                problems.push(problem(plugin, &path, "sidecar missing or unreadable"));
+                continue;
+            };
+            if asset_path(dir, &meta.url).ok().as_ref() != Some(&file) {
+                problems.push(problem(plugin, &path, "sidecar records another file's url"));
+            }
+            let stored = tokio::fs::read(&file).await.map_err(|e| e.to_string())?;
+            match self.keys.open(stored) {
+                Ok(bytes)
+                    if meta
+                        .sha256
+                        .as_deref()
+                        .is_some_and(|sha256| sha256 != digest(&bytes)) =>
+                {
+                    problems.push(problem(
+                        plugin,
+                        &path,
+                        "content doesn't match its recorded sha256",
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(problem(plugin, &path, &e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `plugin` from the cache. Returns its id.
+    pub async fn evict(&self, plugin: &str) -> Result<String, String> {
+        self.writable()?;
+        let (dir, index) = self.find(plugin).await?;
+        remove_plugin(&dir).await?;
+        Ok(index.plugin_id)
+    }
+
+    /// Writes a plaintext copy of `plugin` to `dest`, which must not exist yet. Returns its id.
+    pub async fn export(&self, plugin: &str, dest: &Path) -> Result<String, String> {
+        let (dir, index) = self.find(plugin).await?;
+        if tokio::fs::metadata(dest).await.is_ok() {
+            return Err(format!("{} already exists", dest.display()));
+        }
+        for file in files_under(&dir).await? {
+            if is_tmp(&file) {
+                continue;
+            }
+            let target = dest.join(relative(&dir, &file));
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            let stored = tokio::fs::read(&file).await.map_err(|e| e.to_string())?;
+            let plaintext = self
+                .keys
+                .open(stored)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            tokio::fs::write(&target, plaintext)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(index.plugin_id)
+    }
+
+    /// Imports a plugin written by `export` (or copied from another cache) from `src`, sealed
+    /// with this cache's keys and migrated to the current format, replacing any cached copy.
+    /// Returns its id.
+    pub async fn import(&self, src: &Path) -> Result<String, String> {
+        self.writable()?;
+        // Exports are plaintext, which this cache's keys may refuse once everything is sealed.
+        let source = self.keys.decrypt_only();
+        // An older export's index still carries the id and URLs every format has had.
+        let plugin_id = read_index(&source, src)
+            .await
+            .map(|index| index.plugin_id)
+            .ok_or_else(|| format!("{} has no readable {}", src.display(), INDEX_FILE))?;
+        let version = migrate::plugin_version(&source, src)?.unwrap_or(migrate::UNVERSIONED);
+
+        let dest = self.root.join(hash(&plugin_id));
+        let mut staging = dest.clone();
+        staging.set_extension(STAGING_EXTENSION);
+        remove_plugin(&staging).await?;
+        // The index goes last, so a staging directory is never mistaken for a complete plugin.
+        let mut files = files_under(src).await?;
+        files.retain(|file| !is_tmp(file));
+        files.sort_by_key(|file| relative(src, file) == INDEX_FILE);
+        for file in files {
+            let target = staging.join(relative(src, &file));
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            let stored = tokio::fs::read(&file).await.map_err(|e| e.to_string())?;
+            let plaintext = source
+                .open(stored)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            write_atomic(&target, &self.keys.seal(&plaintext)?).await?;
+        }
+
+        let (keys, dir) = (self.keys.clone(), staging.clone());
+        tokio::task::spawn_blocking(move || migrate::migrate_plugin(&keys, &dir, version))
+            .await
+            .map_err(|e| e.to_string())??;
+        remove_plugin(&dest).await?;
+        tokio::fs::rename(&staging, &dest)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(plugin_id)
+    }
+
+    /// Refuses changes to a cache `migrate` hasn't brought up to the current format.
+    fn writable(&self) -> Result<(), String> {
+        if self.outdated {
+            return Err("the cache is in an older format; run `migrate` first".to_string());
+        }
+        Ok(())
+    }
+
+    /// Directory and index of `plugin`: a plugin id, or the hashed directory name itself.
+    async fn find(&self, plugin: &str) -> Result<(PathBuf, Index), String> {
+        let mut candidates = vec![self.root.join(hash(plugin))];
+        if plugin.len() == 64 && plugin.chars().all(|c| c.is_ascii_hexdigit()) {
+            candidates.push(self.root.join(plugin));
+        }
+        for dir in candidates {
+            if let Some(index) = read_index(&self.keys, &dir).await {
+                return Ok((dir, index));
+            }
+        }
+        Err(format!("plugin {} is not cached", plugin))
+    }
+}
+
+fn problem(plugin: &str, path: &str, issue: &str) -> Problem {
+    Problem {
+        plugin: plugin.to_string(),
+        path: path.to_string(),
+        issue: issue.to_string(),
+    }
+}
+
+fn dir_name(dir: &Path) -> String {
+    dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// `file`'s path below `dir`, `/`-separated like the (encoded) URL paths it mirrors.
+fn relative(dir: &Path, file: &Path) -> String {
+    let path = file.strip_prefix(dir).unwrap_or(file);
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_tmp(file: &Path) -> bool {
+    file.extension().is_some_and(|ext| ext == "tmp")
+}
+
+fn is_sidecar(file: &Path) -> bool {
+    file.extension().is_some_and(|ext| ext == "meta")
+}
+
+/// Whether `file` holds cached content, as opposed to a sidecar, the index or write debris.
+fn is_asset(dir: &Path, file: &Path) -> bool {
+    !is_sidecar(file) && !is_tmp(file) && relative(dir, file) != INDEX_FILE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::fixture;
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A data dir holding the `v1` fixture as its plugin cache, with keys under `config`.
+    fn data_dir() -> (TempDir, PathBuf) {
+        let dir = fixture("v1");
+        let data = dir.path();
+        let root = data.join(CACHE_DIR);
+        std::fs::create_dir_all(&root).unwrap();
+        for entry in std::fs::read_dir(data).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name() != CACHE_DIR {
+                std::fs::rename(entry.path(), root.join(entry.file_name())).unwrap();
+            }
+        }
+        let config = data.join("config");
+        std::fs::create_dir_all(&config).unwrap();
+        (dir, config)
+    }
+
+    #[tokio::test]
+    async fn opens_without_migrating() {
+        let (dir, config) = data_dir();
+        let data = dir.path();
+        let index = data
+            .join(CACHE_DIR)
+            .join(hash("example-plugin"))
+            .join(INDEX_FILE);
+        let before = std::fs::read(&index).unwrap();
+        let mut cache = PluginCache::open(data, &config).unwrap();
+        assert!(cache.outdated());
+        assert_eq!(
+            cache.plugins().await.unwrap()[0].plugin_id,
+            "example-plugin"
+        );
+        assert!(cache.evict("example-plugin").await.is_err());
+        assert_eq!(std::fs::read(&index).unwrap(), before);
+
+        cache.migrate().unwrap();
+        assert!(!cache.outdated() && !PluginCache::open(data, &config).unwrap().outdated());
+        assert_ne!(std::fs::read(&index).unwrap(), before);
+        assert_eq!(
+            cache.evict("example-plugin").await.unwrap(),
+            "example-plugin"
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_and_shows_plugins() {
+        let (dir, config) = data_dir();
+        let data = dir.path();
+        let mut cache = PluginCache::open(data, &config).unwrap();
+        cache.migrate().unwrap();
+
+        let plugins = cache.plugins().await.unwrap();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].plugin_id, "example-plugin");
+        assert_eq!(plugins[0].dir, hash("example-plugin"));
+        assert_eq!(plugins[0].files, 2);
+
+        let assets = cache.assets(&plugins[0].dir).await.unwrap();
+        let paths: Vec<_> = assets.iter().map(|asset| asset.path.as_str()).collect();
+        assert_eq!(paths, ["manifest.json", "chunks/index-1a2b3c4d.js"]);
+        assert!(assets
+            .iter()
+            .all(|asset| asset.cached && asset.sha256.is_some()));
+    }
+
+    #[tokio::test]
+    async fn verify_reports_damage() {
+        let (dir, config) = data_dir();
+        let data = dir.path();
+        let mut cache = PluginCache::open(data, &config).unwrap();
+        cache.migrate().unwrap();
+        assert!(cache.verify(None).await.unwrap().is_empty());
+
+        let plugin = data.join(CACHE_DIR).join(hash("example-plugin"));
+        std::fs::write(plugin.join("chunks/index-1a2b3c4d.js"), "tampered").unwrap();
+        std::fs::remove_file(plugin.join("manifest.json")).unwrap();
+        let problems = cache.verify(Some("example-plugin")).await.unwrap();
+        let issues: Vec<_> = problems
+            .iter()
+            .map(|problem| (problem.path.as_str(), problem.issue.as_str()))
+            .collect();
+        assert!(issues.contains(&("manifest.json", "listed but not cached")));
+        assert!(issues.contains(&("manifest.json.meta", "sidecar without a file")));
+        assert!(issues.contains(&(
+            "chunks/index-1a2b3c4d.js",
+            "content doesn't match its recorded sha256"
+        )));
+    }
+
+    #[tokio::test]
+    async fn export_and_import_round_trip_across_keys() {
+        let (dir, config) = data_dir();
+        let data = dir.path();
+        let export = data.join("export");
+        let mut cache = PluginCache::open(data, &config).unwrap();
+        cache.migrate().unwrap();
+        assert_eq!(
+            cache.export("example-plugin", &export).await.unwrap(),
+            "example-plugin"
+        );
+        assert!(cache.export("example-plugin", &export).await.is_err());
+        assert_eq!(
+            cache.evict("example-plugin").await.unwrap(),
+            "example-plugin"
+        );
+        assert!(cache.plugins().await.unwrap().is_empty());
+
+        // Imported into an encrypted cache: sealed on the way in, and sound afterwards.
+        Keys::default()
+            .rotated()
+            .sealed_only()
+            .save(&config.join(KEY_FILE))
+            .unwrap();
+        let cache = PluginCache::open(data, &config).unwrap();
+        assert_eq!(cache.import(&export).await.unwrap(), "example-plugin");
+        let plugin = data.join(CACHE_DIR).join(hash("example-plugin"));
+        assert!(std::fs::read(plugin.join(INDEX_FILE))
+            .unwrap()
+            .starts_with(b"\0dxpc"));
+        assert!(cache.verify(None).await.unwrap().is_empty());
+        assert_eq!(cache.plugins().await.unwrap()[0].files, 2);
+    }
+}
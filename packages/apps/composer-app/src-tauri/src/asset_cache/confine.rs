@@ -0,0 +1,299 @@
+//! Keeping request-derived paths inside the directory they are meant for.
+//!
+//! Every path the cache reads or writes on behalf of a URL (a plugin's cached files, a dev
+//! plugin's directory, a `DirFetcher` origin) is built by `confine`, never by joining strings
+//! onto a directory. It takes the path as separate segments, so an encoded separator can't
+//! introduce a new one, and refuses:
+//!
+//!   - traversal (`..`), `.` and empty segments, which a lexical prefix check lets through;
+//!   - separators (`/`, `\`) and NULs inside a segment;
+//!   - anything the platform parses as more than a plain name: a root, or a drive prefix;
+//!   - symlinks (and hard-to-spot things like a dangling link) leading outside the directory.
+//!
+//! The last check resolves the deepest part of the path that exists; what doesn't exist yet can
+//! only be created inside. It can't rule out a symlink planted between the check and the use —
+//! nothing short of `openat`-style APIs can — but whoever can do that already owns the cache.
+
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// `root` followed by `segments`, provided the result names something inside `root`.
+pub fn confine<'a>(
+    root: &Path,
+    segments: impl IntoIterator<Item = &'a str>,
+) -> Result<PathBuf, String> {
+    let mut path = root.to_path_buf();
+    for segment in segments {
+        check_segment(segment)
+            .map_err(|e| format!("{} in {:?} under {}", e, segment, root.display()))?;
+        path.push(segment);
+    }
+    check_links(root, &path)?;
+    Ok(path)
+}
+
+fn check_segment(segment: &str) -> Result<(), &'static str> {
+    if segment.is_empty() || segment == "." || segment == ".." {
+        return Err("empty or relative segment");
+    }
+    if segment.contains('\0') {
+        return Err("NUL");
+    }
+    if segment.contains(['/', '\\']) || (cfg!(windows) && segment.contains(':')) {
+        return Err("separator");
+    }
+    let mut components = Path::new(segment).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == segment => Ok(()),
+        _ => Err("not a plain name"),
+    }
+}
+
+/// Fails if what exists of `path` resolves outside `root`. A missing `root` holds nothing to
+/// escape through.
+fn check_links(root: &Path, path: &Path) -> Result<(), String> {
+    let real_root = match std::fs::canonicalize(root) {
+        Ok(real_root) => real_root,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("resolve {}: {}", root.display(), e)),
+    };
+    for existing in path.ancestors().take_while(|ancestor| *ancestor != root) {
+        match std::fs::symlink_metadata(existing) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("inspect {}: {}", existing.display(), e)),
+        }
+        // A link that resolves nowhere would be followed wherever it points once written through.
+        let real = std::fs::canonicalize(existing)
+            .map_err(|e| format!("resolve {}: {}", existing.display(), e))?;
+        if !real.starts_with(&real_root) {
+            return Err(format!(
+                "{} leads outside {}",
+                existing.display(),
+                root.display()
+            ));
+        }
+        return Ok(());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::fetch::{DirFetcher, Fetcher, MemoryFetcher};
+    use super::super::layout::{asset_path, hash, local_path, plain_path, store_asset};
+    use super::super::seal::Keys;
+    use super::super::store::AssetCache;
+    use super::super::URI_SCHEME;
+    use super::*;
+    use tempfile::TempDir;
+
+    const PLUGIN: &str = "example-plugin";
+    const SECRET: &str = "secret outside the cache";
+
+    /// A temporary directory holding a cache under `cache/` and a file outside it (`secret`) that
+    /// requests try to reach.
+    fn sandbox() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("cache")).unwrap();
+        std::fs::write(dir.path().join("secret"), SECRET).unwrap();
+        dir
+    }
+
+    async fn cached_plugin(dir: &Path) -> AssetCache {
+        let fetcher = Arc::new(MemoryFetcher::default());
+        fetcher.insert("https://plugins.example.com/p/index.js", "export {}");
+        let cache = AssetCache::new(dir.join("cache"), dir.join("cache.key"), fetcher);
+        cache
+            .cache_plugin(
+                PLUGIN,
+                vec!["https://plugins.example.com/p/index.js".to_string()],
+            )
+            .await
+            .unwrap();
+        cache
+    }
+
+    /// `GET`s `raw_path` (not normalized in any way) from the plugin, or from `host` if given.
+    async fn get(
+        cache: &AssetCache,
+        host: Option<&str>,
+        raw_path: &str,
+    ) -> Option<http::Response<Vec<u8>>> {
+        let uri = format!(
+            "{}://{}/{}",
+            URI_SCHEME,
+            host.map_or_else(|| hash(PLUGIN), str::to_string),
+            raw_path
+        );
+        let request = http::Request::builder().uri(uri).body(Vec::new()).ok()?;
+        Some(cache.serve(&request).await.0)
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        let root = Path::new("/cache/plugin");
+        assert_eq!(
+            confine(root, ["chunks", "a.js"]).unwrap(),
+            root.join("chunks").join("a.js")
+        );
+        let odd = ["..a", "a..", "~2e~2e", ".hidden"];
+        assert_eq!(
+            confine(root, odd).unwrap(),
+            root.join(odd.iter().collect::<PathBuf>())
+        );
+    }
+
+    #[test]
+    fn rejects_hostile_segments() {
+        let root = Path::new("/cache/plugin");
+        for segments in [
+            vec![".."],
+            vec!["chunks", "..", "..", "secret"],
+            vec!["."],
+            vec![""],
+            vec!["a", ""],
+            vec!["/etc/passwd"],
+            vec!["a/../../b"],
+            vec!["..\\..\\secret"],
+            vec!["a\0.js"],
+            vec!["C:\\Windows"],
+        ] {
+            assert!(
+                confine(root, segments.iter().copied()).is_err(),
+                "{:?}",
+                segments
+            );
+        }
+        if cfg!(windows) {
+            assert!(confine(root, ["C:"]).is_err());
+            assert!(confine(root, ["a.js:stream"]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_stays_inside_the_plugin() {
+        let sandbox = sandbox();
+        let dir = sandbox.path();
+        let cache = cached_plugin(dir).await;
+        assert_eq!(get(&cache, None, "p/index.js").await.unwrap().status(), 200);
+
+        let attempts = [
+            "../../secret",
+            "p/../../../secret",
+            "%2e%2e/%2e%2e/secret",
+            "..%2f..%2fsecret",
+            "..%5c..%5csecret",
+            "%2fetc%2fpasswd",
+            "/../secret",
+            "p/index.js%00.png",
+            "index.json",
+            "p/index.js.meta",
+        ];
+        for path in attempts {
+            if let Some(response) = get(&cache, None, path).await {
+                assert_eq!(response.status(), 404, "{}", path);
+                assert_ne!(response.body(), SECRET.as_bytes(), "{}", path);
+            }
+        }
+        for host in ["..", ".", "%2e%2e", "cache"] {
+            if let Some(response) = get(&cache, Some(host), "secret").await {
+                assert_eq!(response.status(), 404, "{}", host);
+            }
+        }
+    }
+
+    #[test]
+    fn plain_paths_stay_inside_their_directory() {
+        let root = Path::new("/build/plugin");
+        assert_eq!(
+            plain_path(root, "chunks/My%20Font.woff2").unwrap(),
+            root.join("chunks").join("My Font.woff2")
+        );
+        for path in [
+            "../secret",
+            "%2e%2e/secret",
+            "a%2f..%2f..%2fsecret",
+            "a%5c..",
+            "a%00",
+            "",
+            "a//b",
+            "/abs",
+        ] {
+            assert!(plain_path(root, path).is_none(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn dir_fetcher_stays_inside_its_directory() {
+        let sandbox = sandbox();
+        let dir = sandbox.path();
+        std::fs::write(dir.join("cache/a.js"), "export {}").unwrap();
+        let fetcher = DirFetcher::new(dir.join("cache"));
+        assert!(fetcher
+            .fetch("https://example.com/a.js", None)
+            .await
+            .is_ok());
+        for url in [
+            "https://example.com/%2e%2e/secret",
+            "https://example.com/..%2fsecret",
+        ] {
+            assert!(fetcher.fetch(url, None).await.is_err(), "{}", url);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_cache_are_refused() {
+        use std::os::unix::fs::symlink;
+
+        let sandbox = sandbox();
+        let dir = sandbox.path();
+        let cache = cached_plugin(dir).await;
+        let plugin_dir = dir.join("cache").join(hash(PLUGIN));
+
+        // Read: a cached file, a directory, and the plugin directory itself swapped for links out.
+        symlink(
+            dir.join("secret"),
+            local_path(&plugin_dir, "leak.js").unwrap(),
+        )
+        .unwrap();
+        symlink(dir, local_path(&plugin_dir, "outside").unwrap()).unwrap();
+        assert_eq!(get(&cache, None, "leak.js").await.unwrap().status(), 404);
+        assert_eq!(
+            get(&cache, None, "outside/secret").await.unwrap().status(),
+            404
+        );
+        let elsewhere = dir.join("elsewhere");
+        std::fs::rename(&plugin_dir, &elsewhere).unwrap();
+        symlink(&elsewhere, &plugin_dir).unwrap();
+        assert_eq!(get(&cache, None, "p/index.js").await.unwrap().status(), 404);
+        std::fs::remove_file(&plugin_dir).unwrap();
+        std::fs::rename(&elsewhere, &plugin_dir).unwrap();
+
+        // Write: through a linked directory, and through a dangling link whose target is outside.
+        let keys = Keys::default();
+        assert!(asset_path(&plugin_dir, "https://plugins.example.com/outside/new.js").is_err());
+        symlink(
+            dir.join("planted"),
+            local_path(&plugin_dir, "dangling.js").unwrap(),
+        )
+        .unwrap();
+        let url = "https://plugins.example.com/dangling.js";
+        let origin = MemoryFetcher::default();
+        origin.insert(url, "export {}");
+        let fetched = origin.fetch(url, None).await.unwrap().unwrap();
+        assert!(store_asset(&keys, &plugin_dir, url, fetched).await.is_err());
+        assert!(!dir.join("planted").exists());
+
+        // Links that stay inside are fine.
+        symlink(
+            local_path(&plugin_dir, "p/index.js").unwrap(),
+            local_path(&plugin_dir, "alias.js").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(get(&cache, None, "alias.js").await.unwrap().status(), 200);
+    }
+}
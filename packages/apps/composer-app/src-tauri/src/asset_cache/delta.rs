@@ -0,0 +1,147 @@
+//! Updating a cached plugin from its previous version instead of from scratch.
+//!
+//! A new version of a plugin mostly repeats the last one, but not always under the same URLs: a
+//! versioned path (`/v1.2.0/chunks/…`) moves every file, and a changed chunk gets a new name. So
+//! `cache_plugin_assets` takes a `Delta` describing both versions, and builds each missing file
+//! locally where it can:
+//!
+//!   - copied, when a file of the previous version still cached has the same digest;
+//!   - patched, when the publisher put a binary diff from a previous file next to the bundle
+//!     (`zstd --patch-from=<old> <new>`), and the result has the digest the new version declares;
+//!   - fetched in full otherwise, including whenever copying or patching fails.
+//!
+//! Cached files are only trusted as a source if their bytes still match the digest in their
+//! sidecar.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::fetch::Fetched;
+use super::layout::{asset_path, digest, meta_path, read_meta};
+use super::seal::Keys;
+
+/// Largest file a patch may produce, so a malformed one can't exhaust memory.
+const MAX_PATCHED: u64 = 64 * 1024 * 1024;
+
+/// What the caller knows of the new version of a plugin and the one before it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delta {
+    /// Hex SHA-256 of the new version's files, by URL.
+    #[serde(default)]
+    pub digests: HashMap<String, String>,
+    /// The previous version's manifest: hex SHA-256 of its files, by URL.
+    #[serde(default)]
+    pub previous: HashMap<String, String>,
+    /// Binary diffs published alongside the bundle, by the URL of the file they produce.
+    #[serde(default)]
+    pub patches: HashMap<String, Patch>,
+}
+
+/// A `zstd --patch-from` diff at `url`, producing a new file from the previous version's `from`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Patch {
+    pub from: String,
+    pub url: String,
+}
+
+/// How caching a plugin got its files.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateReport {
+    pub fetched: usize,
+    pub copied: usize,
+    pub patched: usize,
+    /// Bytes downloaded, patches included.
+    pub bytes_fetched: u64,
+    /// Bytes that would have been downloaded on top without `Delta`.
+    pub bytes_saved: u64,
+}
+
+/// Files of the previous version cached under `plugin_dir` with the digest its manifest gives
+/// them: URL by digest.
+pub async fn previous_by_digest(
+    keys: &Keys,
+    plugin_dir: &Path,
+    delta: &Delta,
+) -> HashMap<String, String> {
+    let mut by_digest = HashMap::new();
+    for (url, expected) in &delta.previous {
+        let Ok(meta_path) = meta_path(plugin_dir, url) else {
+            continue;
+        };
+        let Some(meta) = read_meta(keys, &meta_path).await else {
+            continue;
+        };
+        if meta.url == *url && meta.sha256.as_deref() == Some(expected.as_str()) {
+            by_digest.insert(expected.clone(), url.clone());
+        }
+    }
+    by_digest
+}
+
+/// `url` as cached under `plugin_dir`, provided its bytes still match the sidecar's digest.
+pub async fn read_previous(keys: &Keys, plugin_dir: &Path, url: &str) -> Option<Fetched> {
+    let meta = read_meta(keys, &meta_path(plugin_dir, url).ok()?).await?;
+    if meta.url != url {
+        return None;
+    }
+    let bytes = keys
+        .open(
+            tokio::fs::read(asset_path(plugin_dir, url).ok()?)
+                .await
+                .ok()?,
+        )
+        .ok()?;
+    if meta.sha256.as_deref() != Some(digest(&bytes).as_str()) {
+        return None;
+    }
+    Some(Fetched {
+        bytes,
+        mime: Some(meta.mime),
+        etag: None,
+        last_modified: None,
+    })
+}
+
+/// Applies a `zstd --patch-from` diff to `old`.
+pub fn apply_patch(old: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let decoder =
+        zstd::stream::read::Decoder::with_ref_prefix(patch, old).map_err(|e| e.to_string())?;
+    let mut patched = Vec::new();
+    decoder
+        .take(MAX_PATCHED + 1)
+        .read_to_end(&mut patched)
+        .map_err(|e| e.to_string())?;
+    if patched.len() as u64 > MAX_PATCHED {
+        return Err(format!("patch produces more than {} bytes", MAX_PATCHED));
+    }
+    Ok(patched)
+}
+
+#[cfg(test)]
+pub fn make_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), 19, old).unwrap();
+    encoder.write_all(new).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_patches() {
+        let old = "export const a = 1;\n".repeat(200);
+        let new = format!("{}export const b = 2;\n", old);
+        let patch = make_patch(old.as_bytes(), new.as_bytes());
+        assert!(patch.len() < new.len() / 10);
+        assert_eq!(apply_patch(old.as_bytes(), &patch).unwrap(), new.as_bytes());
+        assert!(apply_patch(old.as_bytes(), b"not a patch").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Local-directory plugin sources, for plugin development.
+//!
+//! A plugin registered with `register_dev_plugin` is served by `dxos-plugin://` straight from a
+//! directory (typically the plugin's build output) rather than from the cache, at the same host
+//! its cached copy would use. The directory is watched, and every burst of changes under it is
+//! reported as one `DEV_PLUGIN_CHANGED_EVENT` listing the changed paths, so the frontend can
+//! reload the plugin after a rebuild.
+//!
+//! Only debug builds register these commands: they let the frontend read any local directory and
+//! replace a plugin's code.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use serde::Serialize;
+
+/// Event emitted when files under a registered dev plugin's directory change.
+pub const DEV_PLUGIN_CHANGED_EVENT: &str = "dxos:plugin-dev-changed";
+
+/// Quiet period closing a burst of changes: a rebuild rewrites many files in quick succession,
+/// and the frontend should reload once, after the last of them.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Payload of `DEV_PLUGIN_CHANGED_EVENT`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DevPluginChanged {
+    pub plugin_id: String,
+    /// Changed paths, relative to the plugin's directory and `/`-separated like the
+    /// `dxos-plugin://` URLs serving them.
+    pub paths: Vec<String>,
+}
+
+/// Watches a directory until dropped.
+pub struct DevWatcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+/// Watches `dir` recursively, calling `on_change` with the paths changed in each burst of activity.
+/// `dir` should be canonical, as watcher backends report canonical paths.
+pub fn watch(
+    dir: &Path,
+    on_change: impl Fn(Vec<String>) + Send + 'static,
+) -> Result<DevWatcher, String> {
+    let (sender, receiver) = mpsc::channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        for path in event.paths {
+            let _ = sender.send(path);
+        }
+    })
+    .map_err(|e| format!("watch {}: {}", dir.display(), e))?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .map_err(|e| format!("watch {}: {}", dir.display(), e))?;
+
+    // Dropping the watcher drops the sender, which ends this thread.
+    let root = dir.to_path_buf();
+    std::thread::spawn(move || {
+        while let Ok(first) = receiver.recv() {
+            let mut changed = BTreeSet::new();
+            changed.extend(relative(&root, &first));
+            loop {
+                match receiver.recv_timeout(DEBOUNCE) {
+                    Ok(path) => changed.extend(relative(&root, &path)),
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            if !changed.is_empty() {
+                on_change(changed.into_iter().collect());
+            }
+        }
+    });
+    Ok(DevWatcher { _watcher: watcher })
+}
+
+/// `path` below `root`, `/`-separated; `None` for `root` itself or anything outside it.
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<_> = relative
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reports_changes_relative_to_the_directory() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("chunks")).unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let watcher = watch(&dir, move |paths| {
+            let _ = sender.send(paths);
+        })
+        .unwrap();
+
+        std::fs::write(dir.join("chunks/a.js"), "export {}").unwrap();
+        std::fs::write(dir.join("index.js"), "export {}").unwrap();
+        let mut seen = BTreeSet::new();
+        while !(seen.contains("chunks/a.js") && seen.contains("index.js")) {
+            let paths = receiver
+                .recv_timeout(Duration::from_secs(5))
+                .expect("no change reported");
+            seen.extend(paths);
+        }
+
+        drop(watcher);
+    }
+
+    #[test]
+    fn relative_paths_are_slash_separated() {
+        let root = Path::new("/build/plugin");
+        assert_eq!(
+            relative(root, &root.join("chunks").join("a.js")).as_deref(),
+            Some("chunks/a.js")
+        );
+        assert_eq!(relative(root, root), None);
+        assert_eq!(relative(root, Path::new("/elsewhere/a.js")), None);
+    }
+}
@@ -0,0 +1,290 @@
+//! How URL paths map to file paths inside a plugin directory.
+//!
+//! A cached file lives at its URL's path-within-origin, but that path can't be used verbatim:
+//! `Chunk.js` and `chunk.js` are one file on case-insensitive volumes (macOS, iOS, Windows),
+//! Windows refuses names like `con.js` or `a.`, a long enough segment exceeds the filesystem's
+//! name limit, and the same path reaches us percent-encoded one way when caching (from `url`)
+//! and another when serving (from the webview). So every `/`-separated segment is
+//! percent-decoded and re-encoded into a name all of those store as-is:
+//!
+//!   - `a-z`, `0-9`, `-`, `_` and `.` stand for themselves;
+//!   - an uppercase letter is `^` and its lowercase (`Chunk.js` is `^chunk.js`);
+//!   - any other byte is `~` and two lowercase hex digits (`a b` is `a~20b`);
+//!   - an empty segment (the origin root, or a trailing `/`) is a lone `~`.
+//!
+//! A byte that would still make a name misbehave is escaped as well: the first byte of a Windows
+//! device name (`con.js` is `~63on.js`), a trailing dot, the dot before a suffix the cache uses
+//! for its own files (`.meta`, `.tmp`), and the dot of a top-level `index.json`. Names are thus
+//! lowercase ASCII, and `decode` recovers exactly the segment each came from.
+//!
+//! The exception is a segment whose name would exceed `MAX_NAME`: it is cut short and ends in `~~`
+//! and a digest of the whole segment. Such a name can't be decoded, and two segments sharing both
+//! prefix and digest would share a file; the URL recorded in each sidecar settles either, so reads
+//! and writes check it (see `layout::same_path`).
+
+use super::layout::{digest, INDEX_FILE};
+
+/// Longest name a segment encodes to: under the 255 bytes most filesystems allow, with room for
+/// the `.meta` and `.tmp` suffixes the cache appends.
+const MAX_NAME: usize = 200;
+
+/// Hex digits of the segment digest ending a shortened name.
+const DIGEST_LEN: usize = 32;
+
+/// Suffixes of the cache's own files, which no asset name may end in.
+const RESERVED_SUFFIXES: &[&[u8]] = &[b"meta", b"tmp"];
+
+/// Names Windows reserves for devices, with any extension.
+const DEVICE_NAMES: &[&[u8]] = &[
+    b"con", b"prn", b"aux", b"nul", b"com1", b"com2", b"com3", b"com4", b"com5", b"com6", b"com7",
+    b"com8", b"com9", b"lpt1", b"lpt2", b"lpt3", b"lpt4", b"lpt5", b"lpt6", b"lpt7", b"lpt8",
+    b"lpt9",
+];
+
+/// The `/`-separated path, relative to its plugin directory, of the file caching the URL path
+/// `path` (leading slash trimmed, percent-encoded or not).
+pub fn encode(path: &str) -> String {
+    let names: Vec<String> = path
+        .split('/')
+        .enumerate()
+        .map(|(i, segment)| encode_segment(&percent_decode(segment), i == 0))
+        .collect();
+    names.join("/")
+}
+
+/// The URL path an `encode`d path stands for, in `canonical` form. `None` for a path holding a
+/// shortened name, or anything `encode` doesn't produce. Nothing reads names back yet; the tests
+/// use it to check the encoding is reversible.
+#[cfg(test)]
+pub fn decode(encoded: &str) -> Option<String> {
+    let segments: Option<Vec<String>> = encoded.split('/').map(decode_segment).collect();
+    Some(segments?.join("/"))
+}
+
+/// `path` with every segment percent-encoded one fixed way, so two spellings of a path compare
+/// equal exactly when they name the same file.
+pub fn canonical(path: &str) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|segment| percent_encode(&percent_decode(segment)))
+        .collect();
+    segments.join("/")
+}
+
+/// Resolves `%XX` escapes; a `%` not followed by two hex digits stands for itself, as in a URL.
+pub fn percent_decode(segment: &str) -> Vec<u8> {
+    let raw = segment.as_bytes();
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        let escaped = match raw[i..] {
+            [b'%', high, low, ..] => hex_pair(high.to_ascii_lowercase(), low.to_ascii_lowercase()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(raw[i]);
+                i += 1;
+            }
+        }
+    }
+    bytes
+}
+
+fn encode_segment(bytes: &[u8], top: bool) -> String {
+    if bytes.is_empty() {
+        return "~".to_string();
+    }
+    let mut names: Vec<String> = bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => char::from(byte).to_string(),
+            b'A'..=b'Z' => format!("^{}", char::from(byte.to_ascii_lowercase())),
+            _ => escape(byte),
+        })
+        .collect();
+
+    let last = bytes.len() - 1;
+    if bytes[last] == b'.' {
+        names[last] = escape(b'.');
+    }
+    let stem = bytes.split(|&byte| byte == b'.').next().unwrap_or_default();
+    if DEVICE_NAMES.contains(&stem) {
+        names[0] = escape(bytes[0]);
+    }
+    if let Some(dot) = bytes.iter().rposition(|&byte| byte == b'.') {
+        if RESERVED_SUFFIXES.contains(&&bytes[dot + 1..]) || (top && bytes == INDEX_FILE.as_bytes())
+        {
+            names[dot] = escape(b'.');
+        }
+    }
+
+    if names.iter().map(String::len).sum::<usize>() <= MAX_NAME {
+        return names.concat();
+    }
+    let mut shortened = String::with_capacity(MAX_NAME);
+    for name in names {
+        if shortened.len() + name.len() > MAX_NAME - DIGEST_LEN - 2 {
+            break;
+        }
+        shortened.push_str(&name);
+    }
+    shortened.push_str("~~");
+    shortened.push_str(&digest(bytes)[..DIGEST_LEN]);
+    shortened
+}
+
+#[cfg(test)]
+fn decode_segment(name: &str) -> Option<String> {
+    if name == "~" {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut raw = name.bytes();
+    while let Some(byte) = raw.next() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => bytes.push(byte),
+            b'^' => bytes.push(
+                raw.next()
+                    .filter(u8::is_ascii_lowercase)?
+                    .to_ascii_uppercase(),
+            ),
+            b'~' => bytes.push(hex_pair(raw.next()?, raw.next()?)?),
+            _ => return None,
+        }
+    }
+    (!bytes.is_empty()).then(|| percent_encode(&bytes))
+}
+
+fn escape(byte: u8) -> String {
+    format!("~{:02x}", byte)
+}
+
+/// Percent-encodes everything but the characters RFC 3986 allows unescaped in a path segment.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(char::from(byte))
+            }
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':'
+            | b'@' => encoded.push(char::from(byte)),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The byte two lowercase hex digits spell.
+fn hex_pair(high: u8, low: u8) -> Option<u8> {
+    let value = |digit: u8| match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        _ => None,
+    };
+    Some((value(high)? << 4) | value(low)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn encodes_portable_names() {
+        assert_eq!(
+            encode("chunks/index-1a2b3c4d.js"),
+            "chunks/index-1a2b3c4d.js"
+        );
+        assert_eq!(encode("chunks/index-BxT4.js"), "chunks/index-^bx^t4.js");
+        assert_eq!(encode("assets/My%20Font.woff2"), "assets/^my~20^font.woff2");
+        assert_eq!(encode("assets/My Font.woff2"), "assets/^my~20^font.woff2");
+        assert_eq!(encode("con.js/aux"), "~63on.js/~61ux");
+        assert_eq!(encode("a./../."), "a~2e/.~2e/~2e");
+        assert_eq!(encode("index.json"), "index~2ejson");
+        assert_eq!(encode("sub/index.json"), "sub/index.json");
+        assert_eq!(encode("a.js.meta/b.tmp"), "a.js~2emeta/b~2etmp");
+        assert_eq!(encode(""), "~");
+        assert_eq!(encode("docs/"), "docs/~");
+        assert_eq!(encode("a%2Fb"), "a~2fb");
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let long = "x".repeat(300);
+        let name = encode(&long);
+        assert_eq!(name.len(), MAX_NAME);
+        assert!(name.contains("~~"));
+        assert_ne!(encode(&format!("{}y", long)), name);
+        assert_eq!(decode(&name), None);
+    }
+
+    #[test]
+    fn decodes_to_canonical_paths() {
+        assert_eq!(
+            decode("assets/^my~20^font.woff2").as_deref(),
+            Some("assets/My%20Font.woff2")
+        );
+        assert_eq!(decode("docs/~").as_deref(), Some("docs/"));
+        assert_eq!(decode("a~2fb").as_deref(), Some("a%2Fb"));
+        assert_eq!(
+            canonical("%7euser/%c3%a9t%C3%A9%"),
+            "~user/%C3%A9t%C3%A9%25"
+        );
+        for bogus in ["A.js", "a~zz", "a^", "a^1", "a~~0123", "a b"] {
+            assert_eq!(decode(bogus), None, "{}", bogus);
+        }
+    }
+
+    /// A URL path segment: any characters but `/`, with percent escapes in either case mixed in.
+    fn segment() -> impl Strategy<Value = String> {
+        proptest::collection::vec(prop_oneof!["[^/]", "%[0-9a-fA-F]{2}", "[aA.%~^]"], 0..12)
+            .prop_map(|parts| parts.concat())
+    }
+
+    fn url_path() -> impl Strategy<Value = String> {
+        proptest::collection::vec(segment(), 1..5).prop_map(|segments| segments.join("/"))
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(path in url_path()) {
+            let encoded = encode(&path);
+            prop_assert_eq!(decode(&encoded), Some(canonical(&path)));
+            prop_assert_eq!(encode(&canonical(&path)), encoded);
+        }
+
+        #[test]
+        fn names_are_portable(path in url_path()) {
+            for (i, name) in encode(&path).split('/').enumerate() {
+                let bytes = name.as_bytes();
+                prop_assert!(!name.is_empty() && name.len() <= MAX_NAME);
+                prop_assert!(bytes.iter().all(|byte| matches!(byte, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'^' | b'~')));
+                prop_assert!(!name.ends_with('.') && !name.ends_with(".meta") && !name.ends_with(".tmp"));
+                prop_assert!(!DEVICE_NAMES.contains(&name.split('.').next().unwrap_or_default().as_bytes()));
+                prop_assert!(i > 0 || name != INDEX_FILE);
+            }
+        }
+
+        #[test]
+        fn distinct_paths_get_distinct_names(a in "[aAbB.%2fF/]{0,6}", b in "[aAbB.%2fF/]{0,6}") {
+            // A small alphabet, so that near-misses (case, escapes) come up. Names are lowercase, so
+            // distinct ones stay distinct on case-insensitive volumes too.
+            prop_assert_eq!(encode(&a) == encode(&b), canonical(&a) == canonical(&b));
+        }
+
+        #[test]
+        fn matches_url_parsing(path in "[a-zA-Z0-9 _\"<>`{}|é-]{1,16}(/[a-zA-Z0-9 _\"<>`{}|é-]{1,16}){0,3}") {
+            // What `url` makes of a path when caching names the same file as the raw path served.
+            // (The suffix keeps trailing spaces, which URL parsing trims off the whole input, inside.)
+            let path = format!("{}.js", path);
+            let parsed = url::Url::parse(&format!("https://example.com/{}", path)).unwrap();
+            prop_assert_eq!(encode(parsed.path().trim_start_matches('/')), encode(&path));
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Where cached plugin files come from.
+//!
+//! `AssetCache` reaches plugin origins only through a `Fetcher`: `HttpFetcher` in the app,
+//! `DirFetcher` to serve a local build output as if it were an origin, and `MemoryFetcher` for
+//! tests. Fetchers report what the origin said; deciding the MIME type to store (sniffing when the
+//! origin was vague) is left to the cache, so every source gets the same treatment.
+
+#[cfg(test)]
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::layout::{digest, plain_path, url_path};
+
+/// A successful origin response, ready to store.
+pub struct Fetched {
+    pub bytes: Vec<u8>,
+    /// `Content-Type` as declared by the origin, if it declared one.
+    pub mime: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Validators of a cached copy, replayed as `If-None-Match` / `If-Modified-Since`.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Why a fetch failed, and whether asking again could help.
+#[derive(Debug, Clone)]
+pub struct FetchError {
+    message: String,
+    /// The origin answered, and refused (a 404, say): the same request will fail the same way.
+    /// Anything else — no connection, a timeout, a server error — may pass on a later attempt.
+    pub permanent: bool,
+}
+
+impl FetchError {
+    pub fn transient(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            permanent: false,
+        }
+    }
+
+    pub fn permanent(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            permanent: true,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<FetchError> for String {
+    fn from(error: FetchError) -> Self {
+        error.message
+    }
+}
+
+pub type FetchFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<Fetched>, FetchError>> + Send + 'a>>;
+
+pub type SizeFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<u64>, String>> + Send + 'a>>;
+
+pub trait Fetcher: Send + Sync {
+    /// Fetches `url`, conditionally on `cached` when given. `Ok(None)` means the origin answered
+    /// "not modified" and is only ever returned for a conditional fetch.
+    fn fetch<'a>(&'a self, url: &'a str, cached: Option<&'a Validators>) -> FetchFuture<'a>;
+
+    /// Size in bytes of what fetching `url` would return, without fetching it; `Ok(None)` when
+    /// the origin won't say.
+    fn size<'a>(&'a self, url: &'a str) -> SizeFuture<'a>;
+}
+
+/// How long `HttpFetcher` waits for a connection to an origin.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long `HttpFetcher` waits on a response that has stopped making progress.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches over HTTP(S). An unreachable or stalled origin fails the fetch after `CONNECT_TIMEOUT` or
+/// `READ_TIMEOUT` rather than holding it (and whoever waits on it) open indefinitely.
+pub struct HttpFetcher {
+    client: reqwest::Client,
+}
+
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self { client }
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch<'a>(&'a self, url: &'a str, cached: Option<&'a Validators>) -> FetchFuture<'a> {
+        Box::pin(async move {
+            let mut request = self.client.get(url);
+            if let Some(etag) = cached.and_then(|cached| cached.etag.as_deref()) {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = cached.and_then(|cached| cached.last_modified.as_deref()) {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|e| FetchError::transient(format!("fetch {}: {}", url, e)))?;
+            let status = response.status();
+            if cached.is_some() && status == reqwest::StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            if !status.is_success() {
+                let message = format!("fetch {}: status {}", url, status);
+                // Timeouts and rate limiting are the client errors worth retrying.
+                let retryable = status.is_server_error()
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                return Err(if retryable {
+                    FetchError::transient(message)
+                } else {
+                    FetchError::permanent(message)
+                });
+            }
+            let header = |name: reqwest::header::HeaderName| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+            };
+            let mime = header(reqwest::header::CONTENT_TYPE);
+            let etag = header(reqwest::header::ETAG);
+            let last_modified = header(reqwest::header::LAST_MODIFIED);
+            let bytes = response
+                .bytes()
+                .await
+                .map_err(|e| FetchError::transient(format!("read body {}: {}", url, e)))?;
+            Ok(Some(Fetched {
+                bytes: bytes.to_vec(),
+                mime,
+                etag,
+                last_modified,
+            }))
+        })
+    }
+
+    /// Asks with `HEAD`, trusting `Content-Length`.
+    fn size<'a>(&'a self, url: &'a str) -> SizeFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .head(url)
+                .send()
+                .await
+                .map_err(|e| format!("head {}: {}", url, e))?;
+            if !response.status().is_success() {
+                return Err(format!("head {}: status {}", url, response.status()));
+            }
+            Ok(response
+                .headers()
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok()))
+        })
+    }
+}
+
+/// Serves a local directory as an origin: a URL's path-within-origin is looked up under `dir`,
+/// whatever the URL's host.
+pub struct DirFetcher {
+    dir: PathBuf,
+}
+
+impl DirFetcher {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl Fetcher for DirFetcher {
+    fn fetch<'a>(&'a self, url: &'a str, cached: Option<&'a Validators>) -> FetchFuture<'a> {
+        Box::pin(async move {
+            let not_found = || FetchError::permanent(format!("fetch {}: not found", url));
+            let path = plain_path(&self.dir, &url_path(url).map_err(FetchError::permanent)?)
+                .ok_or_else(not_found)?;
+            let bytes = tokio::fs::read(&path).await.map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => not_found(),
+                _ => FetchError::transient(format!("fetch {}: {}", url, e)),
+            })?;
+            Ok(unless_unchanged(bytes, cached))
+        })
+    }
+
+    fn size<'a>(&'a self, url: &'a str) -> SizeFuture<'a> {
+        Box::pin(async move {
+            let path = plain_path(&self.dir, &url_path(url)?)
+                .ok_or_else(|| format!("head {}: not found", url))?;
+            let metadata = tokio::fs::metadata(&path)
+                .await
+                .map_err(|e| format!("head {}: {}", url, e))?;
+            Ok(Some(metadata.len()))
+        })
+    }
+}
+
+/// Serves files held in memory, counting requests. For tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryFetcher {
+    files: std::sync::Mutex<HashMap<String, Vec<u8>>>,
+    /// URLs answered as gone for good.
+    gone: std::sync::Mutex<HashSet<String>>,
+    requests: AtomicUsize,
+}
+
+#[cfg(test)]
+impl MemoryFetcher {
+    /// Serves `bytes` at `url` from now on.
+    pub fn insert(&self, url: &str, bytes: impl Into<Vec<u8>>) {
+        if let Ok(mut files) = self.files.lock() {
+            files.insert(url.to_string(), bytes.into());
+        }
+    }
+
+    /// Answers `url` with an error from now on, as an unreachable origin would.
+    pub fn remove(&self, url: &str) {
+        if let Ok(mut files) = self.files.lock() {
+            files.remove(url);
+        }
+    }
+
+    /// Answers `url` with a permanent error from now on, as an origin that no longer has it would.
+    pub fn refuse(&self, url: &str) {
+        self.remove(url);
+        if let Ok(mut gone) = self.gone.lock() {
+            gone.insert(url.to_string());
+        }
+    }
+
+    /// Fetches attempted so far, conditional or not, successful or not.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl Fetcher for MemoryFetcher {
+    fn fetch<'a>(&'a self, url: &'a str, cached: Option<&'a Validators>) -> FetchFuture<'a> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let bytes = self
+            .files
+            .lock()
+            .ok()
+            .and_then(|files| files.get(url).cloned());
+        let gone = self.gone.lock().is_ok_and(|gone| gone.contains(url));
+        Box::pin(async move {
+            let message = format!("fetch {}: not found", url);
+            let error = if gone {
+                FetchError::permanent(message)
+            } else {
+                FetchError::transient(message)
+            };
+            Ok(unless_unchanged(bytes.ok_or(error)?, cached))
+        })
+    }
+
+    /// Not counted in `requests`.
+    fn size<'a>(&'a self, url: &'a str) -> SizeFuture<'a> {
+        let size = self
+            .files
+            .lock()
+            .ok()
+            .and_then(|files| files.get(url).map(|bytes| bytes.len() as u64));
+        Box::pin(async move {
+            size.map(Some)
+                .ok_or_else(|| format!("head {}: not found", url))
+        })
+    }
+}
+
+/// Response for content we hold ourselves: the digest serves as the `ETag`, and a conditional
+/// fetch naming it gets "not modified".
+fn unless_unchanged(bytes: Vec<u8>, cached: Option<&Validators>) -> Option<Fetched> {
+    let etag = format!("\"{}\"", digest(&bytes));
+    if cached.and_then(|cached| cached.etag.as_deref()) == Some(etag.as_str()) {
+        return None;
+    }
+    Some(Fetched {
+        bytes,
+        mime: None,
+        etag: Some(etag),
+        last_modified: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::Origin;
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn http_fetcher_reports_origin_headers() {
+        let origin = Origin::start();
+        origin.set("/p/index.js", "text/javascript", b"export {}");
+        let fetcher = HttpFetcher::default();
+
+        let fetched = fetcher
+            .fetch(&origin.url("/p/index.js"), None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.bytes, b"export {}");
+        assert_eq!(fetched.mime.as_deref(), Some("text/javascript"));
+        assert_eq!(
+            fetcher.size(&origin.url("/p/index.js")).await.unwrap(),
+            Some(9)
+        );
+        let etag = fetched.etag.clone().unwrap();
+
+        let cached = Validators {
+            etag: Some(etag),
+            last_modified: None,
+        };
+        assert!(fetcher
+            .fetch(&origin.url("/p/index.js"), Some(&cached))
+            .await
+            .unwrap()
+            .is_none());
+        origin.set("/p/index.js", "text/javascript", b"export default 1");
+        assert!(fetcher
+            .fetch(&origin.url("/p/index.js"), Some(&cached))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(fetcher
+            .fetch(&origin.url("/p/missing.js"), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn dir_fetcher_serves_a_directory() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("chunks")).unwrap();
+        std::fs::write(dir.path().join("chunks/a.js"), "export {}").unwrap();
+        let fetcher = DirFetcher::new(dir.path());
+
+        let fetched = fetcher
+            .fetch("https://example.com/chunks/a.js", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.bytes, b"export {}");
+        assert_eq!(
+            fetcher
+                .size("https://example.com/chunks/a.js")
+                .await
+                .unwrap(),
+            Some(9)
+        );
+        let cached = Validators {
+            etag: fetched.etag,
+            last_modified: None,
+        };
+        assert!(fetcher
+            .fetch("https://example.com/chunks/a.js", Some(&cached))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(fetcher
+            .fetch("https://example.com/chunks/b.js", None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_fetcher_counts_requests() {
+        let fetcher = MemoryFetcher::default();
+        fetcher.insert("https://example.com/a.js", "export {}");
+        assert_eq!(
+            fetcher.size("https://example.com/a.js").await.unwrap(),
+            Some(9)
+        );
+        assert!(fetcher
+            .fetch("https://example.com/a.js", None)
+            .await
+            .unwrap()
+            .is_some());
+        fetcher.remove("https://example.com/a.js");
+        assert!(fetcher
+            .fetch("https://example.com/a.js", None)
+            .await
+            .is_err());
+        assert_eq!(fetcher.requests(), 2);
+    }
+}
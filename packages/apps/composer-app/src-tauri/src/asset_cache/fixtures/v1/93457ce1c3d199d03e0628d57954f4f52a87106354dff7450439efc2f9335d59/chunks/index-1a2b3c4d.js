@@ -0,0 +1 @@
+export default { id: "example-plugin" };
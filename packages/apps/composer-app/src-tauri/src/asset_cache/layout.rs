@@ -0,0 +1,391 @@
+//! On-disk layout of the plugin cache: where each plugin's files, sidecars and index live, and
+//! the helpers every part of the cache reads and writes them through (see the parent module for the
+//! layout itself).
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::confine::confine;
+use super::disk_path;
+use super::fetch::Fetched;
+use super::mime::{detect_mime, mime_essence, with_charset, OCTET_STREAM};
+use super::response::is_hashed_filename;
+use super::seal::Keys;
+
+pub const CACHE_DIR: &str = "plugin-cache";
+pub const INDEX_FILE: &str = "index.json";
+/// Encryption key file, under the app config dir so it never travels with the cache itself.
+pub const KEY_FILE: &str = "plugin-cache.key";
+
+/// Age after which an unhashed file is revalidated when no rule in the plugin's index says
+/// otherwise.
+pub const DEFAULT_REVALIDATE_TTL_SECS: u64 = 60 * 60;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Index {
+    pub plugin_id: String,
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revalidate: Vec<RevalidateRule>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// On-disk format the plugin's files are in (see `migrate`).
+    #[serde(default)]
+    pub format: u32,
+    /// Content digest of the bundled copy last considered for seeding (see `AssetCache::seed`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_digest: Option<String>,
+}
+
+/// How long a cached path is served before `handle_uri` refetches it in the background.
+/// Rules are matched in order against the path-within-origin; the first match wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevalidateRule {
+    /// Exact path (e.g. `manifest.json`), or a prefix ending in `*` (e.g. `locales/*`).
+    pub path: String,
+    /// Seconds before a cached copy counts as stale; `None` serves it as first fetched.
+    pub ttl_secs: Option<u64>,
+}
+
+impl RevalidateRule {
+    fn matches(&self, path: &str) -> bool {
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => self.path == path,
+        }
+    }
+}
+
+/// TTL for `path` under `rules`. Without a matching rule, content-hashed files never go stale
+/// and everything else gets `DEFAULT_REVALIDATE_TTL_SECS`.
+pub fn revalidate_ttl(rules: &[RevalidateRule], path: &str) -> Option<u64> {
+    match rules.iter().find(|rule| rule.matches(path)) {
+        Some(rule) => rule.ttl_secs,
+        None if is_hashed_filename(path) => None,
+        None => Some(DEFAULT_REVALIDATE_TTL_SECS),
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AssetMeta {
+    pub url: String,
+    pub mime: String,
+    pub fetched_at: u64,
+    /// Hex SHA-256 of the cached bytes, served as the `ETag`. Absent in sidecars written before
+    /// it was recorded; those entries are digested when served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Origin validators, replayed as `If-None-Match` / `If-Modified-Since` on revalidation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+pub fn hash(input: &str) -> String {
+    digest(input.as_bytes())
+}
+
+pub fn digest(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Every plugin directory under `root` with a readable index.
+pub async fn indexed_plugins(keys: &Keys, root: &Path) -> Result<Vec<(PathBuf, Index)>, String> {
+    let mut plugins = Vec::new();
+    for dir in plugin_dirs(root).await? {
+        if let Some(index) = read_index(keys, &dir).await {
+            plugins.push((dir, index));
+        }
+    }
+    Ok(plugins)
+}
+
+/// Every directory directly under `root`, indexed or not. A missing root holds none.
+pub async fn plugin_dirs(root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut entries = match tokio::fs::read_dir(root).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        if entry.file_type().await.map_err(|e| e.to_string())?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Every file below `dir`, at any depth.
+pub async fn files_under(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await.map_err(|e| e.to_string())?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            if entry.file_type().await.map_err(|e| e.to_string())?.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Bytes held by the files below `dir`.
+pub async fn dir_size(dir: &Path) -> Result<u64, String> {
+    let mut size = 0u64;
+    for path in files_under(dir).await? {
+        size += tokio::fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+    }
+    Ok(size)
+}
+
+pub async fn remove_plugin(dir: &Path) -> Result<(), String> {
+    match tokio::fs::remove_dir_all(dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path-within-origin for a given URL, with the leading slash trimmed.
+/// Used as the path component of the `dxos-plugin://` URI, and (encoded, see `local_path`) as
+/// the on-disk filename.
+pub fn url_path(url: &str) -> Result<String, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+    Ok(parsed.path().trim_start_matches('/').to_string())
+}
+
+pub fn asset_path(plugin_dir: &Path, url: &str) -> Result<PathBuf, String> {
+    local_path(plugin_dir, &url_path(url)?)
+}
+
+/// Where the plugin directory `plugin_dir` keeps the file for the URL path `path`, however
+/// `path` happens to be percent-encoded. Both caching and serving go through here, and so
+/// through `confine`.
+pub fn local_path(plugin_dir: &Path, path: &str) -> Result<PathBuf, String> {
+    confine(plugin_dir, disk_path::encode(path).split('/'))
+}
+
+/// Whether `url` has the path-within-origin `path`. Spellings of one path compare equal, and
+/// paths sharing a shortened file name (see `disk_path`) don't.
+pub fn same_path(url: &str, path: &str) -> bool {
+    url_path(url).is_ok_and(|listed| disk_path::canonical(&listed) == disk_path::canonical(path))
+}
+
+/// The file the URL path `path` names under `dir`, a directory of plain (unencoded) files such as
+/// a plugin's build output. `None` for a path that can't name a file there (see `confine`).
+pub fn plain_path(dir: &Path, path: &str) -> Option<PathBuf> {
+    let segments: Result<Vec<String>, _> = path
+        .split('/')
+        .map(|segment| String::from_utf8(disk_path::percent_decode(segment)))
+        .collect();
+    confine(dir, segments.ok()?.iter().map(String::as_str)).ok()
+}
+
+pub fn meta_path(plugin_dir: &Path, url: &str) -> Result<PathBuf, String> {
+    let mut path = asset_path(plugin_dir, url)?;
+    path.as_mut_os_string().push(".meta");
+    Ok(path)
+}
+
+pub fn now_secs() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Writes an entry's bytes and then its sidecar, each via a temporary sibling renamed into place,
+/// so a concurrent `handle_uri` sees the old file or the new one but never a partial write.
+pub async fn store_asset(
+    keys: &Keys,
+    dir: &Path,
+    url: &str,
+    fetched: Fetched,
+) -> Result<(), String> {
+    let bytes_path = asset_path(dir, url)?;
+    if let Some(existing) = read_meta(keys, &meta_path(dir, url)?).await {
+        if !same_path(&existing.url, &url_path(url)?) {
+            return Err(format!("{} collides with cached {}", url, existing.url));
+        }
+    }
+    if let Some(parent) = bytes_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    write_atomic(&bytes_path, &keys.seal(&fetched.bytes)?).await?;
+    // Origins that don't know what they're serving say `application/octet-stream` (or nothing);
+    // that's no better than not answering, so it falls through to our own detection.
+    let mime = match fetched
+        .mime
+        .filter(|mime| mime_essence(mime) != OCTET_STREAM)
+    {
+        Some(declared) => with_charset(&declared),
+        None => detect_mime(&url_path(url)?, &fetched.bytes),
+    };
+    let meta = AssetMeta {
+        url: url.to_string(),
+        mime,
+        fetched_at: now_secs(),
+        sha256: Some(digest(&fetched.bytes)),
+        etag: fetched.etag,
+        last_modified: fetched.last_modified,
+    };
+    write_meta(keys, dir, &meta).await
+}
+
+pub async fn write_meta(keys: &Keys, dir: &Path, meta: &AssetMeta) -> Result<(), String> {
+    let meta_json = serde_json::to_vec(meta).map_err(|e| e.to_string())?;
+    write_atomic(&meta_path(dir, &meta.url)?, &keys.seal(&meta_json)?).await
+}
+
+pub async fn read_meta(keys: &Keys, meta_path: &Path) -> Option<AssetMeta> {
+    let raw = keys.open(tokio::fs::read(meta_path).await.ok()?).ok()?;
+    serde_json::from_slice(&raw).ok()
+}
+
+pub async fn read_index(keys: &Keys, plugin_dir: &Path) -> Option<Index> {
+    let raw = keys
+        .open(tokio::fs::read(plugin_dir.join(INDEX_FILE)).await.ok()?)
+        .ok()?;
+    serde_json::from_slice(&raw).ok()
+}
+
+pub async fn write_index(keys: &Keys, plugin_dir: &Path, index: &Index) -> Result<(), String> {
+    let index_json = serde_json::to_vec(index).map_err(|e| e.to_string())?;
+    write_atomic(&plugin_dir.join(INDEX_FILE), &keys.seal(&index_json)?).await
+}
+
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), String> {
+    // Every write gets a temporary file of its own, so writers racing on one path never rename
+    // each other's partial output into place.
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.to_path_buf();
+    let unique = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+    tmp.as_mut_os_string()
+        .push(format!(".{}-{}.tmp", std::process::id(), unique));
+    if let Err(e) = tokio::fs::write(&tmp, contents).await {
+        // A partial write (out of space, say) shouldn't linger until the next startup.
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.to_string());
+    }
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.to_string());
+    }
+    Ok(())
+}
+
+/// Original URL for `path` within an indexed plugin: a listed URL with exactly that path if there
+/// is one (its file went missing), otherwise `path` on the plugin's origin — provided every listed
+/// URL shares one, since a path alone can't choose between origins.
+pub fn origin_url(index: &Index, path: &str) -> Option<String> {
+    if let Some(url) = index.urls.iter().find(|url| same_path(url, path)) {
+        return Some(url.clone());
+    }
+    let mut origins = index
+        .urls
+        .iter()
+        .filter_map(|url| Url::parse(url).ok())
+        .map(|url| url.origin());
+    let origin = origins.next()?;
+    if !origin.is_tuple() || !origins.all(|other| other == origin) {
+        return None;
+    }
+    Some(format!("{}/{}", origin.ascii_serialization(), path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::migrate::FORMAT_VERSION;
+    use super::*;
+
+    fn index_of(urls: &[&str]) -> Index {
+        Index {
+            plugin_id: "example".to_string(),
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            revalidate: Vec::new(),
+            pinned: false,
+            format: FORMAT_VERSION,
+            seed_digest: None,
+        }
+    }
+
+    #[test]
+    fn resolves_listed_urls_exactly() {
+        let index = index_of(&[
+            "https://cdn.example.com/p/index.js?v=2",
+            "https://other.example.com/p/a.js",
+        ]);
+        assert_eq!(
+            origin_url(&index, "p/index.js").as_deref(),
+            Some("https://cdn.example.com/p/index.js?v=2")
+        );
+    }
+
+    #[test]
+    fn resolves_unlisted_paths_against_a_single_origin() {
+        let index = index_of(&[
+            "https://cdn.example.com/p/index.js",
+            "https://cdn.example.com/p/style.css",
+        ]);
+        assert_eq!(
+            origin_url(&index, "p/chunks/foo-1a2b3c4d.js").as_deref(),
+            Some("https://cdn.example.com/p/chunks/foo-1a2b3c4d.js")
+        );
+    }
+
+    #[test]
+    fn refuses_unlisted_paths_across_origins() {
+        let index = index_of(&[
+            "https://cdn.example.com/p/index.js",
+            "https://other.example.com/p/a.js",
+        ]);
+        assert_eq!(origin_url(&index, "p/chunks/foo.js"), None);
+        assert_eq!(origin_url(&index_of(&[]), "p/chunks/foo.js"), None);
+    }
+
+    #[test]
+    fn revalidates_unhashed_files_by_default() {
+        assert_eq!(
+            revalidate_ttl(&[], "manifest.json"),
+            Some(DEFAULT_REVALIDATE_TTL_SECS)
+        );
+        assert_eq!(revalidate_ttl(&[], "chunks/index-BxT4a9Qz.js"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [
+            RevalidateRule {
+                path: "manifest.json".to_string(),
+                ttl_secs: Some(60),
+            },
+            RevalidateRule {
+                path: "locales/*".to_string(),
+                ttl_secs: Some(600),
+            },
+            RevalidateRule {
+                path: "*".to_string(),
+                ttl_secs: None,
+            },
+        ];
+        assert_eq!(revalidate_ttl(&rules, "manifest.json"), Some(60));
+        assert_eq!(revalidate_ttl(&rules, "locales/en-US.json"), Some(600));
+        assert_eq!(revalidate_ttl(&rules, "index.js"), None);
+    }
+}
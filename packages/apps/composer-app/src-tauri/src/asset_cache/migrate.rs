@@ -0,0 +1,503 @@
+//! On-disk format versioning for the plugin cache.
+//!
+//! The format version is recorded twice: in `format.json` at the cache root, and as `format` in
+//! every plugin's `index.json`. The root marker says what the cache as a whole was last brought
+//! up to; the per-plugin stamp lets a single bundle (restored from a backup, imported from another
+//! machine) be upgraded on its own.
+//!
+//! Caches from before versioning carry neither and count as version 1.
+//!
+//! Migration runs once per launch, before anything else reads or writes the cache. Steps work on
+//! raw JSON rather than the current `Index`/`AssetMeta` types, so a historical step keeps meaning
+//! what it meant when it was written however those types evolve. A cache (or any plugin in it)
+//! written by a newer build is refused outright, before a single file is touched.
+//!
+//! To change the layout: bump `FORMAT_VERSION`, append the step upgrading from the previous
+//! version to `STEPS`, and add a fixture of the previous version under `fixtures/`.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::layout::{asset_path, digest, INDEX_FILE};
+use super::mime::with_charset;
+use super::seal::Keys;
+
+/// The format this build reads and writes.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Root marker recording the cache's format version. Never sealed: it has to be readable before
+/// anything else is.
+pub const FORMAT_FILE: &str = "format.json";
+
+/// Version of a cache or index that predates versioning, and the oldest there is: a lower stamp
+/// counts as this.
+pub const UNVERSIONED: u32 = 1;
+
+/// Upgrades one plugin directory by a single format version.
+type Step = fn(&Keys, &Path) -> Result<(), String>;
+
+/// `STEPS[n]` upgrades one plugin directory from format `n + 1` to `n + 2`.
+const STEPS: &[Step] = &[v1_to_v2, v2_to_v3];
+
+#[derive(Serialize, Deserialize)]
+struct Format {
+    version: u32,
+}
+
+/// Brings the cache under `root` up to `FORMAT_VERSION`.
+pub fn migrate(root: &Path, keys: &Keys) -> Result<(), String> {
+    let (root_version, pending) = scan(root, keys)?;
+    for (dir, version) in pending {
+        migrate_plugin(keys, &dir, version)?;
+    }
+
+    if root_version != FORMAT_VERSION {
+        std::fs::create_dir_all(root).map_err(|e| e.to_string())?;
+        let marker = serde_json::to_vec(&Format {
+            version: FORMAT_VERSION,
+        })
+        .map_err(|e| e.to_string())?;
+        write(&root.join(FORMAT_FILE), &marker)?;
+    }
+    Ok(())
+}
+
+/// Whether any plugin in the cache under `root` is older than `FORMAT_VERSION`. Reads only; fails
+/// like `migrate` for a cache written by a newer build.
+pub fn outdated(root: &Path, keys: &Keys) -> Result<bool, String> {
+    Ok(!scan(root, keys)?.1.is_empty())
+}
+
+/// The cache's root format version, and the plugin directories below `FORMAT_VERSION` with theirs.
+fn scan(root: &Path, keys: &Keys) -> Result<(u32, Vec<(PathBuf, u32)>), String> {
+    let root_version = match std::fs::read(root.join(FORMAT_FILE)) {
+        Ok(raw) => {
+            serde_json::from_slice::<Format>(&raw)
+                .map_err(|e| format!("parse {}: {}", FORMAT_FILE, e))?
+                .version
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => UNVERSIONED,
+        Err(e) => return Err(format!("read {}: {}", FORMAT_FILE, e)),
+    };
+    check_supported("plugin cache", root_version)?;
+
+    // Every plugin is checked before any is migrated, so a refusal leaves the cache as found.
+    let mut pending = Vec::new();
+    for dir in plugin_dirs(root)? {
+        let Some(version) = plugin_version(keys, &dir)? else {
+            // Interrupted before its first index write; startup garbage collection removes it.
+            continue;
+        };
+        if version < FORMAT_VERSION {
+            pending.push((dir, version));
+        }
+    }
+    Ok((root_version, pending))
+}
+
+/// Format of the plugin directory `dir`, or `None` if it has no index. Fails for a plugin written
+/// by a newer build.
+pub fn plugin_version(keys: &Keys, dir: &Path) -> Result<Option<u32>, String> {
+    let Some(index) = read_json(keys, &dir.join(INDEX_FILE))? else {
+        return Ok(None);
+    };
+    let version = index
+        .get("format")
+        .and_then(Value::as_u64)
+        .map_or(UNVERSIONED, |version| {
+            u32::try_from(version).unwrap_or(u32::MAX)
+        })
+        .max(UNVERSIONED);
+    check_supported(&format!("plugin cache entry {}", dir.display()), version)?;
+    Ok(Some(version))
+}
+
+/// Upgrades the plugin directory `dir` from `version` (see `plugin_version`) to `FORMAT_VERSION`.
+pub fn migrate_plugin(keys: &Keys, dir: &Path, version: u32) -> Result<(), String> {
+    let done = version.saturating_sub(UNVERSIONED) as usize;
+    for (step, from) in STEPS.iter().zip(UNVERSIONED..).skip(done) {
+        step(keys, dir).map_err(|e| format!("migrate {} from v{}: {}", dir.display(), from, e))?;
+        log::info!(
+            "[asset-cache] migrated {} from v{} to v{}",
+            dir.display(),
+            from,
+            from + 1
+        );
+    }
+    Ok(())
+}
+
+fn check_supported(what: &str, version: u32) -> Result<(), String> {
+    if version > FORMAT_VERSION {
+        return Err(format!(
+            "{} is format v{}, newer than this build understands (v{}); leaving it untouched",
+            what, version, FORMAT_VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// v1 -> v2: sidecars gain the `sha256` content digest served as the `ETag`, and MIME types
+/// carry a charset; the index gains its `format` stamp. Entries that can't be read are dropped.
+fn v1_to_v2(keys: &Keys, dir: &Path) -> Result<(), String> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if path.extension() != Some(OsStr::new("meta")) {
+                continue;
+            }
+            let bytes_path = path.with_extension("");
+            let mut meta = match read_json(keys, &path) {
+                Ok(Some(meta)) => meta,
+                Ok(None) => continue,
+                Err(e) => {
+                    drop_entry(&bytes_path, &path, &e)?;
+                    continue;
+                }
+            };
+            let Ok(stored) = std::fs::read(&bytes_path) else {
+                // The body is gone; the entry is re-fetched like any other missing file.
+                continue;
+            };
+            let bytes = match keys.open(stored) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    drop_entry(&bytes_path, &path, &e)?;
+                    continue;
+                }
+            };
+            if meta.get("sha256").is_none() {
+                meta["sha256"] = Value::String(digest(&bytes));
+            }
+            if let Some(mime) = meta.get("mime").and_then(Value::as_str) {
+                meta["mime"] = Value::String(with_charset(mime));
+            }
+            write_json(keys, &path, &meta)?;
+        }
+    }
+    stamp_index(keys, dir, 2)
+}
+
+/// Removes an entry a step can't read (a corrupt sidecar, a body sealed with a lost key) rather
+/// than failing the whole migration over it; it is re-fetched like any other missing file.
+fn drop_entry(bytes_path: &Path, sidecar: &Path, error: &str) -> Result<(), String> {
+    log::warn!(
+        "[asset-cache] dropping unreadable {}: {}",
+        bytes_path.display(),
+        error
+    );
+    remove(bytes_path)?;
+    remove(sidecar)
+}
+
+/// v2 -> v3: files move from their URL path, used verbatim, to its portable encoding (see
+/// `disk_path`). Where each belongs is read off its sidecar; a file without a readable sidecar, or
+/// a sidecar without its file, is dropped, to be re-fetched like any other incomplete entry.
+fn v2_to_v3(keys: &Keys, dir: &Path) -> Result<(), String> {
+    let (mut files, mut dirs) = (Vec::new(), Vec::new());
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.is_dir() {
+                pending.push(path.clone());
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut moves = Vec::new();
+    for path in files {
+        let extension = path.extension();
+        if path == dir.join(INDEX_FILE) || extension == Some(OsStr::new("tmp")) {
+            continue;
+        }
+        let mut sidecar = path.clone();
+        sidecar.as_mut_os_string().push(".meta");
+        if extension == Some(OsStr::new("meta")) {
+            if !path.with_extension("").exists() {
+                remove(&path)?;
+            }
+            continue;
+        }
+        let url = read_json(keys, &sidecar)
+            .ok()
+            .flatten()
+            .and_then(|meta| meta.get("url")?.as_str().map(str::to_string));
+        let Some(target) = url.and_then(|url| asset_path(dir, &url).ok()) else {
+            remove(&path)?;
+            remove(&sidecar)?;
+            continue;
+        };
+        if target != path {
+            moves.push((path, sidecar, target));
+        }
+    }
+
+    // Everything moving is staged first, so no file lands on an old name another has yet to leave.
+    // Staged names end in `.tmp`: should this be interrupted, they are cleaned up like any other
+    // partial write, and their entries re-fetched.
+    let staged: Vec<_> = (0..moves.len())
+        .map(|i| dir.join(format!("{}.v3.tmp", i)))
+        .collect();
+    for ((path, sidecar, _), staged) in moves.iter().zip(&staged) {
+        std::fs::rename(path, staged).map_err(|e| e.to_string())?;
+        std::fs::rename(sidecar, staged.with_extension("meta.tmp")).map_err(|e| e.to_string())?;
+    }
+    for ((_, _, target), staged) in moves.iter().zip(&staged) {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut sidecar = target.clone();
+        sidecar.as_mut_os_string().push(".meta");
+        std::fs::rename(staged.with_extension("meta.tmp"), sidecar).map_err(|e| e.to_string())?;
+        std::fs::rename(staged, target).map_err(|e| e.to_string())?;
+    }
+
+    // Deepest first; only directories the moves emptied go.
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for emptied in dirs {
+        let _ = std::fs::remove_dir(emptied);
+    }
+    stamp_index(keys, dir, 3)
+}
+
+/// Records `version` in a plugin's index, the last thing each step does.
+fn stamp_index(keys: &Keys, dir: &Path, version: u32) -> Result<(), String> {
+    let path = dir.join(INDEX_FILE);
+    let mut index =
+        read_json(keys, &path)?.ok_or_else(|| "index vanished mid-migration".to_string())?;
+    index["format"] = Value::from(version);
+    write_json(keys, &path, &index)
+}
+
+fn remove(path: &Path) -> Result<(), String> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(format!("remove {}: {}", path.display(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn plugin_dirs(root: &Path) -> Result<Vec<PathBuf>, String> {
+    match std::fs::read_dir(root) {
+        Ok(entries) => Ok(entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn read_json(keys: &Keys, path: &Path) -> Result<Option<Value>, String> {
+    let stored = match std::fs::read(path) {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("read {}: {}", path.display(), e)),
+    };
+    let raw = keys.open(stored)?;
+    serde_json::from_slice(&raw)
+        .map(Some)
+        .map_err(|e| format!("parse {}: {}", path.display(), e))
+}
+
+fn write_json(keys: &Keys, path: &Path, value: &Value) -> Result<(), String> {
+    let raw = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+    write(path, &keys.seal(&raw)?)
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut tmp = path.to_path_buf();
+    tmp.as_mut_os_string().push(".tmp");
+    std::fs::write(&tmp, contents).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::layout::hash;
+    use super::super::testing::fixture;
+    use super::*;
+    use tempfile::TempDir;
+
+    fn json(path: &Path) -> Value {
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn migrates_v1() {
+        let dir = fixture("v1");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        migrate(root, &Keys::default()).unwrap();
+
+        assert_eq!(json(&root.join(FORMAT_FILE))["version"], FORMAT_VERSION);
+        assert_eq!(json(&plugin.join(INDEX_FILE))["format"], FORMAT_VERSION);
+
+        let meta = json(&plugin.join("chunks/index-1a2b3c4d.js.meta"));
+        let bytes = std::fs::read(plugin.join("chunks/index-1a2b3c4d.js")).unwrap();
+        assert_eq!(meta["sha256"], digest(&bytes));
+        assert_eq!(meta["mime"], "text/javascript; charset=utf-8");
+        assert_eq!(
+            json(&plugin.join("manifest.json.meta"))["mime"],
+            "application/json; charset=utf-8"
+        );
+
+        // Already current: a second run changes nothing.
+        let before = std::fs::read(plugin.join(INDEX_FILE)).unwrap();
+        migrate(root, &Keys::default()).unwrap();
+        assert_eq!(std::fs::read(plugin.join(INDEX_FILE)).unwrap(), before);
+    }
+
+    #[test]
+    fn migrates_an_index_stamped_format_0_as_v1() {
+        let dir = fixture("v1");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        let mut index = json(&plugin.join(INDEX_FILE));
+        index["format"] = 0.into();
+        std::fs::write(plugin.join(INDEX_FILE), serde_json::to_vec(&index).unwrap()).unwrap();
+        assert_eq!(
+            plugin_version(&Keys::default(), &plugin).unwrap(),
+            Some(UNVERSIONED)
+        );
+        migrate(root, &Keys::default()).unwrap();
+
+        assert_eq!(json(&plugin.join(INDEX_FILE))["format"], FORMAT_VERSION);
+        assert_eq!(
+            json(&plugin.join("manifest.json.meta"))["mime"],
+            "application/json; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn drops_unreadable_v1_entries() {
+        let dir = fixture("v1");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        std::fs::write(plugin.join("manifest.json.meta"), "{not json").unwrap();
+        // Sealed with a key the cache no longer has.
+        let lost = Keys::default().rotated();
+        let chunk = plugin.join("chunks/index-1a2b3c4d.js");
+        std::fs::write(&chunk, lost.seal(&std::fs::read(&chunk).unwrap()).unwrap()).unwrap();
+        migrate(root, &Keys::default()).unwrap();
+
+        assert_eq!(json(&plugin.join(INDEX_FILE))["format"], FORMAT_VERSION);
+        assert!(
+            !plugin.join("manifest.json").exists() && !plugin.join("manifest.json.meta").exists()
+        );
+        assert!(!chunk.exists() && !plugin.join("chunks/index-1a2b3c4d.js.meta").exists());
+    }
+
+    #[test]
+    fn migrates_sealed_v1() {
+        let dir = fixture("v1");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        let keys = Keys::default().rotated();
+        for file in [INDEX_FILE, "manifest.json", "manifest.json.meta"] {
+            let path = plugin.join(file);
+            std::fs::write(&path, keys.seal(&std::fs::read(&path).unwrap()).unwrap()).unwrap();
+        }
+        migrate(root, &keys).unwrap();
+
+        let meta = read_json(&keys, &plugin.join("manifest.json.meta"))
+            .unwrap()
+            .unwrap();
+        let bytes = keys
+            .open(std::fs::read(plugin.join("manifest.json")).unwrap())
+            .unwrap();
+        assert_eq!(meta["sha256"], digest(&bytes));
+        assert_eq!(
+            read_json(&keys, &plugin.join(INDEX_FILE)).unwrap().unwrap()["format"],
+            FORMAT_VERSION
+        );
+    }
+
+    #[test]
+    fn migrates_v2() {
+        let dir = fixture("v2");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        migrate(root, &Keys::default()).unwrap();
+        assert_eq!(json(&plugin.join(INDEX_FILE))["format"], FORMAT_VERSION);
+
+        for (url, moved_from) in [
+            ("https://plugins.example.com/manifest.json", None),
+            (
+                "https://plugins.example.com/chunks/index-BxT4a9Qz.js",
+                Some("chunks/index-BxT4a9Qz.js"),
+            ),
+            (
+                "https://plugins.example.com/assets/My%20Font.woff2",
+                Some("assets/My%20Font.woff2"),
+            ),
+        ] {
+            let path = asset_path(&plugin, url).unwrap();
+            let mut sidecar = path.clone();
+            sidecar.as_mut_os_string().push(".meta");
+            assert_eq!(json(&sidecar)["url"], url);
+            assert_eq!(
+                json(&sidecar)["sha256"],
+                digest(&std::fs::read(&path).unwrap())
+            );
+            if let Some(old) = moved_from {
+                assert_ne!(plugin.join(old), path);
+                assert!(
+                    !plugin.join(old).exists() && !plugin.join(format!("{}.meta", old)).exists()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stamps_fresh_caches() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("cache");
+        migrate(&root, &Keys::default()).unwrap();
+        assert_eq!(json(&root.join(FORMAT_FILE))["version"], FORMAT_VERSION);
+    }
+
+    #[test]
+    fn refuses_newer_caches_untouched() {
+        let dir = fixture("v1");
+        let root = dir.path();
+        let plugin = root.join(hash("example-plugin"));
+        let newer = format!(r#"{{"version":{}}}"#, FORMAT_VERSION + 1);
+        std::fs::write(root.join(FORMAT_FILE), &newer).unwrap();
+        assert!(migrate(root, &Keys::default()).is_err());
+        assert_eq!(
+            std::fs::read_to_string(root.join(FORMAT_FILE)).unwrap(),
+            newer
+        );
+        assert!(json(&plugin.join("manifest.json.meta"))
+            .get("sha256")
+            .is_none());
+
+        // A single newer plugin is enough to refuse, and nothing else gets migrated either.
+        std::fs::remove_file(root.join(FORMAT_FILE)).unwrap();
+        let other = root.join(hash("newer-plugin"));
+        std::fs::create_dir_all(&other).unwrap();
+        let index = format!(
+            r#"{{"plugin_id":"newer-plugin","urls":[],"format":{}}}"#,
+            FORMAT_VERSION + 1
+        );
+        std::fs::write(other.join(INDEX_FILE), index).unwrap();
+        assert!(migrate(root, &Keys::default()).is_err());
+        assert!(!root.join(FORMAT_FILE).exists());
+        assert!(json(&plugin.join(INDEX_FILE)).get("format").is_none());
+    }
+}
@@ -0,0 +1,233 @@
+//! MIME types for cached files: the origin's word where it gave a useful one, otherwise the
+//! path's extension, otherwise the bytes themselves.
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Extension -> MIME type for everything a plugin bundle plausibly ships. Matched against the
+/// lowercased extension of the last path segment, so query strings never get in the way.
+const MIME_TYPES: &[(&str, &str)] = &[
+    // Scripts and styles.
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("css", "text/css"),
+    // Documents and data.
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    // Images.
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    // Fonts.
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // Media.
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("m4a", "audio/mp4"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+];
+
+/// MIME type implied by a path's extension, if it has one we know.
+fn guess_mime(path: &str) -> Option<&'static str> {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    MIME_TYPES
+        .iter()
+        .find(|(candidate, _)| *candidate == extension)
+        .map(|(_, mime)| *mime)
+}
+
+/// Last-resort MIME type from the leading bytes of the body, for extensionless or unknown paths.
+/// Only signatures that can't be mistaken for anything else are trusted; other valid UTF-8 is
+/// served as plain text and everything else as an opaque blob.
+fn sniff_mime(bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"\0asm", "application/wasm"),
+        (b"%PDF-", "application/pdf"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OTTO", "font/otf"),
+        (b"\x00\x01\x00\x00", "font/ttf"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+    {
+        return mime;
+    }
+    // RIFF containers name their payload at offset 8; `ftyp` boxes sit at offset 4.
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        match &bytes[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => {}
+        }
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"avif" | b"avis" => "image/avif",
+            b"M4A " => "audio/mp4",
+            b"qt  " => "video/quicktime",
+            _ => "video/mp4",
+        };
+    }
+
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return OCTET_STREAM;
+    };
+    let head = text.trim_start_matches('\u{feff}').trim_start();
+    let lowered = head
+        .get(..head.len().min(256))
+        .unwrap_or(head)
+        .to_ascii_lowercase();
+    if lowered.starts_with("<!doctype html") || lowered.starts_with("<html") {
+        "text/html"
+    } else if lowered.starts_with("<svg")
+        || (lowered.starts_with("<?xml") && lowered.contains("<svg"))
+    {
+        "image/svg+xml"
+    } else if lowered.starts_with("<?xml") {
+        "application/xml"
+    } else if (head.starts_with('{') || head.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+    {
+        "application/json"
+    } else {
+        "text/plain"
+    }
+}
+
+/// Best MIME type we can give a body without an origin's word for it: the path's extension first,
+/// then the bytes themselves. Always carries a charset when the type is textual.
+pub fn detect_mime(path: &str, bytes: &[u8]) -> String {
+    with_charset(guess_mime(path).unwrap_or_else(|| sniff_mime(bytes)))
+}
+
+/// The type/subtype of a `Content-Type` value, lowercased and without parameters.
+pub fn mime_essence(mime: &str) -> String {
+    mime.split(';')
+        .next()
+        .unwrap_or(mime)
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether a MIME type carries text, and so needs a charset to decode reliably.
+fn is_textual(essence: &str) -> bool {
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence,
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+/// Appends `charset=utf-8` to textual types that don't already declare a charset. Bundlers emit
+/// UTF-8, and without the parameter WebKit falls back to Latin-1 for anything non-ASCII.
+/// Parameters the origin sent (including a different charset) are kept as-is.
+pub fn with_charset(mime: &str) -> String {
+    let mime = mime.trim();
+    let has_charset = mime
+        .split(';')
+        .skip(1)
+        .any(|param| param.trim().to_ascii_lowercase().starts_with("charset="));
+    if is_textual(&mime_essence(mime)) && !has_charset {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_mime_from_extension() {
+        assert_eq!(
+            guess_mime("chunks/index-abc123.js"),
+            Some("text/javascript")
+        );
+        assert_eq!(guess_mime("assets/Inter.WOFF2"), Some("font/woff2"));
+        assert_eq!(guess_mime("chunks/index.js.map"), Some("application/json"));
+        assert_eq!(guess_mime("media/intro.webm"), Some("video/webm"));
+        assert_eq!(guess_mime("v1.2/LICENSE"), None);
+        assert_eq!(guess_mime("README"), None);
+    }
+
+    #[test]
+    fn sniffs_mime_from_content() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(sniff_mime(b"\0asm\x01\0\0\0"), "application/wasm");
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime(b"  <!DOCTYPE html><html></html>"), "text/html");
+        assert_eq!(sniff_mime(br#"{"name":"plugin"}"#), "application/json");
+        assert_eq!(sniff_mime(b"{ not json"), "text/plain");
+        assert_eq!(sniff_mime(&[0xfe, 0xed, 0xfa, 0xce]), OCTET_STREAM);
+    }
+
+    #[test]
+    fn adds_charset_to_textual_types_only() {
+        assert_eq!(
+            with_charset("text/javascript"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset("application/manifest+json"),
+            "application/manifest+json; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset("text/css; charset=iso-8859-1"),
+            "text/css; charset=iso-8859-1"
+        );
+        assert_eq!(with_charset("image/png"), "image/png");
+        assert_eq!(with_charset("font/woff2"), "font/woff2");
+    }
+
+    #[test]
+    fn detection_prefers_extension_over_content() {
+        assert_eq!(
+            detect_mime("chunks/foo.mjs", b"export {}"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            detect_mime("assets/logo", b"\x89PNG\r\n\x1a\n"),
+            "image/png"
+        );
+    }
+}
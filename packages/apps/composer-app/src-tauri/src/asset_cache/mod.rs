@@ -0,0 +1,329 @@
+//! Offline cache for third-party plugin assets.
+//!
+//! Bundle layout under `app_data_dir/plugin-cache/<sha(plugin_id)>/`:
+//!   <url-path>          -- raw bytes, mirroring the URL's path-within-origin
+//!                          (e.g. `chunks/foo.js`, `assets/style.css`, `manifest.json`),
+//!                          each segment encoded into a portable file name (see `disk_path`)
+//!   <url-path>.meta     -- JSON sidecar { url, mime, fetched_at, sha256, etag, last_modified }
+//!   index.json          -- { plugin_id, urls: [...], revalidate: [...], pinned, format } for
+//!                          diagnostics, listing, and the plugin's revalidation and pinning
+//!
+//! plus `plugin-cache/format.json` recording the layout version (see `migrate`).
+//!
+//! Whatever a request or URL says, every file read or written stays inside its plugin's
+//! directory (see `confine`).
+//!
+//! Path-based filenames (rather than `sha(url)`) are load-bearing: the webview's
+//! relative-URL resolution treats `dxos-plugin://<plugin_hash>/<file>` like any
+//! other URL, so a sibling import like `import('./chunks/foo.js')` from the entry
+//! resolves to `dxos-plugin://<plugin_hash>/chunks/foo.js`. Hashed filenames
+//! would 404 every sibling import. Using URL paths makes resolution natural.
+//!
+//! Identical code path on desktop and mobile (iOS). Storage purge resilience:
+//! a missing file at lookup time triggers a re-fetch on the next online load,
+//! which lets us survive iOS Settings -> Offload App without manual reinstall.
+//!
+//! The cache itself is `store::AssetCache`, which knows nothing of Tauri and reaches origins
+//! through a `fetch::Fetcher`; the commands and `handle_uri` below bind one to the app, and `admin`
+//! exposes it to the `plugin-cache` command-line tool. Each remaining submodule owns one concern —
+//! path confinement, delta updates, dev sources, format migration, encryption, free space and
+//! counters — and documents it.
+
+pub mod admin;
+mod confine;
+pub mod delta;
+pub mod dev;
+mod disk_path;
+pub mod fetch;
+mod layout;
+mod migrate;
+mod mime;
+mod response;
+mod seal;
+pub mod stats;
+mod storage;
+pub mod store;
+#[cfg(test)]
+mod testing;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+#[cfg(debug_assertions)]
+use dev::{DevPluginChanged, DevWatcher, DEV_PLUGIN_CHANGED_EVENT};
+use fetch::HttpFetcher;
+pub use layout::RevalidateRule;
+use layout::{CACHE_DIR, KEY_FILE};
+use response::not_found;
+use store::AssetCache;
+
+/// Resource directory holding the plugin bundles shipped with the app (see `AssetCache::seed`).
+const SEED_DIR: &str = "plugin-seeds";
+pub const URI_SCHEME: &str = "dxos-plugin";
+
+/// Event emitted when a background revalidation replaced a cached file with new content.
+pub const ASSET_UPDATED_EVENT: &str = "dxos:plugin-asset-updated";
+
+#[derive(Default)]
+pub struct AssetCacheState {
+    /// The app's cache, created on first use: its directories come from the `AppHandle`, which
+    /// doesn't exist yet when the state is registered.
+    cache: OnceLock<Arc<AssetCache>>,
+    /// Watchers of registered dev plugins' directories, by plugin id.
+    #[cfg(debug_assertions)]
+    dev_watchers: std::sync::Mutex<HashMap<String, DevWatcher>>,
+}
+
+/// The app's `AssetCache`, under `app_data_dir` with its key in `app_config_dir`.
+fn cache<R: Runtime>(app: &AppHandle<R>) -> Result<Arc<AssetCache>, String> {
+    let state = app.state::<AssetCacheState>();
+    if let Some(cache) = state.cache.get() {
+        return Ok(Arc::clone(cache));
+    }
+    let cache = AssetCache::new(
+        root_dir(app)?,
+        key_path(app)?,
+        Arc::new(HttpFetcher::default()),
+    );
+    Ok(Arc::clone(state.cache.get_or_init(|| Arc::new(cache))))
+}
+
+fn root_dir<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, String> {
+    let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    Ok(dir.join(CACHE_DIR))
+}
+
+fn key_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, String> {
+    let dir = app.path().app_config_dir().map_err(|e| e.to_string())?;
+    Ok(dir.join(KEY_FILE))
+}
+
+/// Caches a plugin's files. `sizes` may declare the size of some of them (by URL), sparing the
+/// origin a `HEAD` each when checking they fit; if they don't, the error is `insufficientStorage`.
+/// `delta` describes the version cached before, so unchanged files are copied and changed ones
+/// patched rather than downloaded (see `delta`); the report says how much that saved.
+#[tauri::command]
+pub async fn cache_plugin_assets<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+    urls: Vec<String>,
+    sizes: Option<HashMap<String, u64>>,
+    delta: Option<delta::Delta>,
+) -> Result<delta::UpdateReport, store::CacheError> {
+    let sizes = sizes.unwrap_or_default();
+    cache(&app)?
+        .cache_plugin_with(&plugin_id, urls, &sizes, &delta.unwrap_or_default())
+        .await
+}
+
+#[tauri::command]
+pub async fn evict_plugin<R: Runtime>(app: AppHandle<R>, plugin_id: String) -> Result<(), String> {
+    cache(&app)?.evict(&plugin_id).await
+}
+
+#[tauri::command]
+pub async fn resolve_cached_url<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+    url: String,
+) -> Result<Option<String>, String> {
+    cache(&app)?.resolve(&plugin_id, &url).await
+}
+
+/// `resolve_cached_url` for many URLs of one plugin in a single round-trip: one answer per URL, in
+/// the order given.
+#[tauri::command]
+pub async fn resolve_cached_urls<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+    urls: Vec<String>,
+) -> Result<Vec<Option<String>>, String> {
+    cache(&app)?.resolve_many(&plugin_id, &urls).await
+}
+
+/// An import map (`{ "imports": { <remote url>: <dxos-plugin:// url> } }`) covering every cached
+/// file of `plugin_id`, or of every cached plugin when omitted, for the frontend to install once
+/// instead of resolving module by module.
+#[tauri::command]
+pub async fn plugin_import_map<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: Option<String>,
+) -> Result<store::ImportMap, String> {
+    cache(&app)?.import_map(plugin_id.as_deref()).await
+}
+
+#[tauri::command]
+pub async fn list_cached_plugins<R: Runtime>(app: AppHandle<R>) -> Result<Vec<String>, String> {
+    cache(&app)?.plugins().await
+}
+
+/// Hits, misses, 404s by reason, bytes served and origin fetches, per plugin and in total, since
+/// startup or the last `reset_plugin_cache_stats`.
+#[tauri::command]
+pub async fn plugin_cache_stats<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<stats::CacheStats, String> {
+    Ok(cache(&app)?.stats().await)
+}
+
+#[tauri::command]
+pub fn reset_plugin_cache_stats<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    cache(&app)?.reset_stats();
+    Ok(())
+}
+
+/// Replaces a cached plugin's revalidation rules (see `RevalidateRule`). An empty list restores
+/// the default policy.
+#[tauri::command]
+pub async fn set_plugin_revalidation<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+    rules: Vec<RevalidateRule>,
+) -> Result<(), String> {
+    cache(&app)?.set_revalidation(&plugin_id, rules).await
+}
+
+/// Pins a cached plugin so it stays offline-available (see the module comment).
+#[tauri::command]
+pub async fn pin_plugin<R: Runtime>(app: AppHandle<R>, plugin_id: String) -> Result<(), String> {
+    cache(&app)?.set_pinned(&plugin_id, true).await
+}
+
+/// Reverses `pin_plugin`; the plugin's files stay cached but lose their guarantees.
+#[tauri::command]
+pub async fn unpin_plugin<R: Runtime>(app: AppHandle<R>, plugin_id: String) -> Result<(), String> {
+    cache(&app)?.set_pinned(&plugin_id, false).await
+}
+
+/// Serves `plugin_id` from the local directory `dir` (a plugin's build output) in place of its
+/// cached copy until unregistered or the app quits, emitting `DEV_PLUGIN_CHANGED_EVENT` whenever
+/// files under it change. Returns the `dxos-plugin://` base URL the plugin is served at.
+/// Debug builds only.
+#[cfg(debug_assertions)]
+#[tauri::command]
+pub async fn register_dev_plugin<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+    dir: String,
+) -> Result<String, String> {
+    let dir = tokio::fs::canonicalize(&dir)
+        .await
+        .map_err(|e| format!("{}: {}", dir, e))?;
+    if !tokio::fs::metadata(&dir)
+        .await
+        .map_err(|e| e.to_string())?
+        .is_dir()
+    {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let (emitter, id) = (app.clone(), plugin_id.clone());
+    let watcher = dev::watch(&dir, move |paths| {
+        let _ = emitter.emit(
+            DEV_PLUGIN_CHANGED_EVENT,
+            DevPluginChanged {
+                plugin_id: id.clone(),
+                paths,
+            },
+        );
+    })?;
+    cache(&app)?.set_dev_source(&plugin_id, Some(dir))?;
+    app.state::<AssetCacheState>()
+        .dev_watchers
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(plugin_id.clone(), watcher);
+    Ok(format!("{}://{}/", URI_SCHEME, layout::hash(&plugin_id)))
+}
+
+/// Reverses `register_dev_plugin`: the plugin is served from the cache again.
+#[cfg(debug_assertions)]
+#[tauri::command]
+pub async fn unregister_dev_plugin<R: Runtime>(
+    app: AppHandle<R>,
+    plugin_id: String,
+) -> Result<(), String> {
+    cache(&app)?.set_dev_source(&plugin_id, None)?;
+    app.state::<AssetCacheState>()
+        .dev_watchers
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(&plugin_id);
+    Ok(())
+}
+
+/// Startup maintenance: seeds the plugins bundled with the app, clears debris left by interrupted
+/// writes, then restores pinned plugins' missing files, retrying with backoff until the network
+/// lets every fetch through.
+pub async fn startup<R: Runtime>(app: AppHandle<R>) {
+    let cache = match cache(&app) {
+        Ok(cache) => cache,
+        Err(e) => {
+            log::warn!("[asset-cache] startup: {}", e);
+            return;
+        }
+    };
+    match app.path().resource_dir() {
+        Ok(resources) => match cache.seed(&resources.join(SEED_DIR)).await {
+            Ok(seeded) if !seeded.is_empty() => {
+                log::info!("[asset-cache] seeded {}", seeded.join(", "))
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("[asset-cache] seed: {}", e),
+        },
+        Err(e) => log::warn!("[asset-cache] seed: {}", e),
+    }
+    cache.startup().await
+}
+
+/// Turns encryption at rest on or off, converting everything already cached. Enabling generates
+/// a key under the app config dir; disabling decrypts the cache back to plaintext and deletes it.
+#[tauri::command]
+pub async fn set_plugin_cache_encryption<R: Runtime>(
+    app: AppHandle<R>,
+    enabled: bool,
+) -> Result<(), String> {
+    cache(&app)?.set_encryption(enabled).await
+}
+
+/// Replaces the encryption key, re-sealing every cached file under the new one before the old
+/// key is discarded.
+#[tauri::command]
+pub async fn rotate_plugin_cache_key<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    cache(&app)?.rotate_key().await
+}
+
+/// Turns read-through serving on or off. While on, a `dxos-plugin://` miss for a cached plugin
+/// is fetched from the plugin's origin rather than answered with a 404.
+#[tauri::command]
+pub fn set_plugin_cache_read_through<R: Runtime>(
+    app: AppHandle<R>,
+    enabled: bool,
+) -> Result<(), String> {
+    cache(&app)?.set_read_through(enabled);
+    Ok(())
+}
+
+/// Builds a response for a `dxos-plugin://<plugin_hash>/<url-path>` request (see
+/// `AssetCache::serve`). A stale hit is revalidated in the background, emitting
+/// `ASSET_UPDATED_EVENT` if its content changed.
+pub async fn handle_uri<R: Runtime>(
+    app: &AppHandle<R>,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let Ok(cache) = cache(app) else {
+        return not_found();
+    };
+    let (response, stale) = cache.serve(request).await;
+    if let Some(stale) = stale {
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Some(updated) = cache.revalidate(stale).await {
+                let _ = app.emit(ASSET_UPDATED_EVENT, updated);
+            }
+        });
+    }
+    response
+}
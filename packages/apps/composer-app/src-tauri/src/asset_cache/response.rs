@@ -0,0 +1,105 @@
+//! Static-file-server behaviour shared by every `dxos-plugin://` response: caching policy,
+//! `ETag` matching, CORS preflights and the bodiless error responses.
+
+/// `Cache-Control` for a cached path. Bundlers stamp a content hash into chunk filenames, so those
+/// bytes can never change under the same URL and are cached for good; anything else (`manifest.json`,
+/// the entry module) may be replaced by a later `cache_plugin_assets` and must be revalidated.
+pub fn cache_control(path: &str) -> &'static str {
+    if is_hashed_filename(path) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+/// Whether the file name carries a bundler content hash, e.g. `index-BxT4_a9Q.js` (Vite/Rollup)
+/// or `main.3f2a9c1e.js` (webpack). A hash-like token is at least eight URL-safe characters and
+/// mixes letters with digits, which keeps plain words like `component-material.js` out.
+pub fn is_hashed_filename(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let mut tokens: Vec<&str> = file_name.split(['-', '.']).collect();
+    // The first token is the chunk's name and the last its extension; a hash sits between.
+    tokens.pop();
+    tokens.iter().skip(1).any(|token| {
+        token.len() >= 8
+            && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && token.chars().any(|c| c.is_ascii_digit())
+            && token.chars().any(|c| c.is_ascii_alphabetic())
+    })
+}
+
+/// Whether an `If-None-Match` header value matches `etag`. Comparison is weak (RFC 9110 §13.1.2):
+/// a `W/` prefix on either side is ignored, and `*` matches any current representation.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = strip(etag);
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || strip(candidate) == etag)
+}
+
+/// Answers a CORS preflight. Plugin assets are public, read-only bytes, so any origin may read
+/// them with whatever request headers it asks for.
+pub fn preflight(request: &http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let allow_headers = request
+        .headers()
+        .get(http::header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("*")
+        .to_string();
+    http::Response::builder()
+        .status(204)
+        .header("access-control-allow-origin", "*")
+        .header("access-control-allow-methods", "GET, HEAD, OPTIONS")
+        .header("access-control-allow-headers", allow_headers)
+        .header("access-control-max-age", "86400")
+        .body(Vec::new())
+        .unwrap_or_else(|_| not_found())
+}
+
+pub fn method_not_allowed() -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(405)
+        .header("allow", "GET, HEAD, OPTIONS")
+        .header("access-control-allow-origin", "*")
+        .body(Vec::new())
+        .expect("405 response should always build")
+}
+
+/// 404 response. Sets `access-control-allow-origin: *` so the webview surfaces a clean
+/// 404 status to the caller instead of cascading into a "Cross-Origin Resource Sharing
+/// policy" error that obscures the real cause.
+pub fn not_found() -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(404)
+        .header("access-control-allow-origin", "*")
+        .body(b"plugin asset not found".to_vec())
+        .expect("404 response should always build")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_hashed_filenames() {
+        assert!(is_hashed_filename("chunks/index-BxT4a9Qz.js"));
+        assert!(is_hashed_filename("assets/style-4f2a9c1e.css"));
+        assert!(is_hashed_filename("main.3f2a9c1e.js"));
+        assert!(is_hashed_filename("chunks/index-BxT4a9Qz.js.map"));
+        assert!(!is_hashed_filename("manifest.json"));
+        assert!(!is_hashed_filename("index.js"));
+        assert!(!is_hashed_filename("chunks/component-material.js"));
+        assert!(!is_hashed_filename("a1b2c3d4e5f6"));
+    }
+
+    #[test]
+    fn matches_if_none_match_lists() {
+        let etag = "\"abc\"";
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("\"xyz\", W/\"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"abcd\"", etag));
+        assert!(!etag_matches("", etag));
+    }
+}
@@ -0,0 +1,303 @@
+//! Optional encryption at rest for cached plugin files.
+//!
+//! Every file the cache writes (asset bytes, `.meta` sidecars, `index.json`) passes through
+//! `Keys::seal` on the way to disk and `Keys::open` on the way back. With no key configured
+//! sealing is the identity, so an unencrypted cache costs nothing.
+//!
+//! Sealed file layout (see `crate::sealing`):
+//!   MAGIC (5) | key id (u32 LE) | nonce (24) | XChaCha20-Poly1305 ciphertext + tag
+//!
+//! The header is authenticated as associated data, so a file can't be re-labelled with another
+//! key id. Files without the magic are plaintext and returned as-is while a cache is legitimately
+//! mixed, that is while encryption is being switched on or off. Once every file has been sealed
+//! the key file records it (`sealed_only`), and from then on an unsealed file is refused: plaintext
+//! planted in the cache directory is never served as if the cache had written it.
+//!
+//! Keys live in a JSON file outside the cache directory (see `key_path` in the parent module),
+//! so copying `plugin-cache/` off a shared machine yields only ciphertext. The file keeps every
+//! key a sealed file may still reference; rotation adds the new key first and drops old ones
+//! only after every file has been re-sealed.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sealing::{self, decode_key, encode_key, write_private, Key, NONCE_LEN};
+
+const MAGIC: &[u8] = b"\0dxpc";
+const KEY_ID_LEN: usize = 4;
+/// What precedes the nonce: the magic and the key id.
+const PREFIX_LEN: usize = MAGIC.len() + KEY_ID_LEN;
+const HEADER_LEN: usize = PREFIX_LEN + NONCE_LEN;
+
+/// The key set a cache is sealed with. `Keys::default()` is the plaintext (disabled) state.
+#[derive(Clone, Default)]
+pub struct Keys {
+    /// Key new writes are sealed with; `None` writes plaintext.
+    current: Option<u32>,
+    /// Every key a sealed file may reference, by id.
+    keys: BTreeMap<u32, Key>,
+    /// Whether every file is known to be sealed, so an unsealed one is refused.
+    sealed_only: bool,
+}
+
+/// On-disk form of `Keys`, with keys hex-encoded.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    current: Option<u32>,
+    keys: BTreeMap<u32, String>,
+    #[serde(default)]
+    sealed_only: bool,
+}
+
+impl Keys {
+    /// Loads keys from `path`. A missing file means encryption is off.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = match std::fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("read key file: {}", e)),
+        };
+        let file: KeyFile =
+            serde_json::from_slice(&raw).map_err(|e| format!("parse key file: {}", e))?;
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|(id, hex)| {
+                Ok((
+                    id,
+                    decode_key(&hex).ok_or_else(|| "malformed key in key file".to_string())?,
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        if file.current.is_some_and(|id| !keys.contains_key(&id)) {
+            return Err("key file names a current key it doesn't hold".to_string());
+        }
+        Ok(Self {
+            current: file.current,
+            keys,
+            sealed_only: file.sealed_only && file.current.is_some(),
+        })
+    }
+
+    /// Writes keys to `path`, readable by the current user only. Writing the disabled state
+    /// removes the file instead.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if self.keys.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("remove key file: {}", e))
+                }
+                _ => Ok(()),
+            };
+        }
+        let file = KeyFile {
+            current: self.current,
+            keys: self
+                .keys
+                .iter()
+                .map(|(id, key)| (*id, encode_key(key)))
+                .collect(),
+            sealed_only: self.sealed_only,
+        };
+        let json = serde_json::to_vec_pretty(&file).map_err(|e| e.to_string())?;
+        write_private(path, &json).map_err(|e| format!("write key file: {}", e))
+    }
+
+    /// Whether new writes are encrypted.
+    pub fn enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether any key is held, for sealing or only for opening what is still sealed.
+    pub fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Whether every file is known to be sealed (see `sealed_only`).
+    pub fn all_sealed(&self) -> bool {
+        self.sealed_only
+    }
+
+    /// These keys plus a freshly generated one that becomes current.
+    pub fn rotated(&self) -> Self {
+        let id = self
+            .keys
+            .keys()
+            .next_back()
+            .map_or(1, |last| last.wrapping_add(1));
+        let mut keys = self.keys.clone();
+        keys.insert(id, sealing::generate_key());
+        Self {
+            current: Some(id),
+            keys,
+            sealed_only: self.sealed_only,
+        }
+    }
+
+    /// These keys with sealing turned off: new writes are plaintext, old files still open.
+    pub fn decrypt_only(&self) -> Self {
+        Self {
+            current: None,
+            keys: self.keys.clone(),
+            sealed_only: false,
+        }
+    }
+
+    /// These keys, recording that every file has been sealed: unsealed files are refused from now
+    /// on. Only meaningful while encryption is on.
+    pub fn sealed_only(&self) -> Self {
+        Self {
+            sealed_only: self.enabled(),
+            ..self.clone()
+        }
+    }
+
+    /// Only the current key, for once nothing references the others any more.
+    pub fn current_only(&self) -> Self {
+        Self {
+            current: self.current,
+            keys: self
+                .current
+                .and_then(|id| self.keys.get(&id).map(|key| (id, *key)))
+                .into_iter()
+                .collect(),
+            sealed_only: self.sealed_only,
+        }
+    }
+
+    /// Encrypts `plaintext` under the current key, or returns it unchanged when encryption is off.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let Some(id) = self.current else {
+            return Ok(plaintext.to_vec());
+        };
+        let mut prefix = MAGIC.to_vec();
+        prefix.extend_from_slice(&id.to_le_bytes());
+        sealing::seal(&self.keys[&id], &prefix, plaintext)
+    }
+
+    /// Decrypts a file written by `seal`; plaintext files come back unchanged unless every file
+    /// should be sealed. Fails if the file was tampered with or references a key we no longer hold.
+    pub fn open(&self, stored: Vec<u8>) -> Result<Vec<u8>, String> {
+        if !is_sealed(&stored) {
+            if self.sealed_only {
+                return Err("unsealed file in an encrypted cache".to_string());
+            }
+            return Ok(stored);
+        }
+        let id_bytes: [u8; KEY_ID_LEN] = stored[MAGIC.len()..PREFIX_LEN]
+            .try_into()
+            .expect("header slice has the key id's length");
+        let id = u32::from_le_bytes(id_bytes);
+        let key = self
+            .keys
+            .get(&id)
+            .ok_or_else(|| format!("sealed with unknown key {}", id))?;
+        sealing::open(key, &stored, PREFIX_LEN)
+            .map_err(|_| "decrypt failed: file is corrupt or was tampered with".to_string())
+    }
+}
+
+/// Whether `stored` carries a sealed-file header.
+fn is_sealed(stored: &[u8]) -> bool {
+    stored.len() >= HEADER_LEN && stored.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn disabled_keys_pass_plaintext_through() {
+        let keys = Keys::default();
+        assert_eq!(keys.seal(b"export {}").unwrap(), b"export {}");
+        assert_eq!(keys.open(b"export {}".to_vec()).unwrap(), b"export {}");
+    }
+
+    #[test]
+    fn seals_and_opens() {
+        let keys = Keys::default().rotated();
+        let sealed = keys.seal(b"export {}").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(9).any(|window| window == b"export {}"));
+        assert_eq!(keys.open(sealed).unwrap(), b"export {}");
+    }
+
+    #[test]
+    fn rejects_tampered_files() {
+        let keys = Keys::default().rotated();
+        let mut sealed = keys.seal(b"export {}").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(keys.open(sealed).is_err());
+
+        // Re-labelling the key id breaks authentication too, even when that key exists.
+        let keys = keys.rotated();
+        let mut sealed = keys.seal(b"export {}").unwrap();
+        sealed[MAGIC.len()] = 1;
+        assert!(keys.open(sealed).is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_old_files_readable_until_dropped() {
+        let old = Keys::default().rotated();
+        let sealed = old.seal(b"export {}").unwrap();
+
+        let rotated = old.rotated();
+        assert_eq!(rotated.open(sealed.clone()).unwrap(), b"export {}");
+        assert!(rotated.current_only().open(sealed).is_err());
+    }
+
+    #[test]
+    fn refuses_plaintext_once_everything_is_sealed() {
+        let converting = Keys::default().rotated();
+        assert_eq!(
+            converting.open(b"export {}".to_vec()).unwrap(),
+            b"export {}"
+        );
+
+        let sealed_only = converting.sealed_only();
+        assert!(sealed_only.open(b"export {}".to_vec()).is_err());
+        assert_eq!(
+            sealed_only
+                .open(converting.seal(b"export {}").unwrap())
+                .unwrap(),
+            b"export {}"
+        );
+        assert!(sealed_only.rotated().open(b"export {}".to_vec()).is_err());
+
+        // Decrypting back to plaintext lets unsealed files through again.
+        assert!(sealed_only
+            .decrypt_only()
+            .open(b"export {}".to_vec())
+            .is_ok());
+        assert!(!Keys::default().sealed_only().all_sealed());
+    }
+
+    #[test]
+    fn key_file_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("plugin-cache.key");
+        let keys = Keys::default().rotated().rotated().sealed_only();
+        keys.save(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+
+        let loaded = Keys::load(&path).unwrap();
+        let sealed = keys.seal(b"export {}").unwrap();
+        assert_eq!(loaded.open(sealed).unwrap(), b"export {}");
+        assert!(loaded.all_sealed());
+
+        Keys::default().save(&path).unwrap();
+        assert!(!Keys::load(&path).unwrap().enabled());
+    }
+}
@@ -0,0 +1,204 @@
+//! In-process counters for what the cache serves and fetches.
+//!
+//! Counted per `dxos-plugin://` host, the hash of a plugin id and all a request carries, and
+//! mapped back to plugin ids when read (see `AssetCache::stats`). Nothing is persisted: counters
+//! start from zero with the app, and again on `reset`.
+//!
+//! A hit is a request answered from a file already on disk; a miss, one for a file that wasn't
+//! (whether or not read-through then fetched it). Every 404 counts under its reason; those for
+//! hosts that can't name a plugin all count under `BAD_HOSTS`, so a page probing made-up hosts
+//! can't grow the counters without bound. Dev plugins (see `dev`) bypass the cache and aren't
+//! counted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::layout::now_secs;
+
+/// Key counting every request whose host can't name a plugin directory (`NotFound::BadHost`).
+/// Never a `dxos-plugin://` host, which is always hex.
+pub const BAD_HOSTS: &str = "(bad host)";
+
+/// Why `serve` answered 404.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NotFound {
+    /// The host names nothing that could be a plugin directory.
+    BadHost,
+    /// The path can't be mapped into the plugin directory.
+    BadPath,
+    /// The cache couldn't be opened (a failed migration, an unreadable key).
+    Unavailable,
+    /// No such file is cached.
+    NotCached,
+    /// The file is there but couldn't be read or opened.
+    Unreadable,
+    /// The file caches another path (see `disk_path`).
+    OtherPath,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Counters {
+    pub hits: u64,
+    pub misses: u64,
+    pub not_found: BTreeMap<NotFound, u64>,
+    /// Response body bytes, so nothing for `HEAD`s and 304s.
+    pub bytes_served: u64,
+    /// Requests to the plugin's origin, failed ones included.
+    pub fetches: u64,
+    pub fetch_failures: u64,
+    /// Time spent on those requests, in total and the longest.
+    pub fetch_millis: u64,
+    pub max_fetch_millis: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        for (reason, count) in &other.not_found {
+            *self.not_found.entry(*reason).or_default() += count;
+        }
+        self.bytes_served += other.bytes_served;
+        self.fetches += other.fetches;
+        self.fetch_failures += other.fetch_failures;
+        self.fetch_millis += other.fetch_millis;
+        self.max_fetch_millis = self.max_fetch_millis.max(other.max_fetch_millis);
+    }
+}
+
+/// What `plugin_cache_stats` reports.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    /// When counting started (Unix seconds): app start or the last reset.
+    pub since: u64,
+    pub total: Counters,
+    /// By plugin id, or by host for one the cache no longer knows, and `BAD_HOSTS`.
+    pub plugins: BTreeMap<String, Counters>,
+}
+
+pub struct Stats {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    since: u64,
+    hosts: HashMap<String, Counters>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                since: now_secs(),
+                hosts: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl Stats {
+    fn update(&self, host: &str, f: impl FnOnce(&mut Counters)) {
+        if let Ok(mut inner) = self.inner.lock() {
+            f(inner.hosts.entry(host.to_string()).or_default());
+        }
+    }
+
+    pub fn hit(&self, host: &str) {
+        self.update(host, |counters| counters.hits += 1);
+    }
+
+    pub fn miss(&self, host: &str) {
+        self.update(host, |counters| counters.misses += 1);
+    }
+
+    pub fn not_found(&self, host: &str, reason: NotFound) {
+        self.update(host, |counters| {
+            *counters.not_found.entry(reason).or_default() += 1
+        });
+    }
+
+    pub fn served(&self, host: &str, bytes: usize) {
+        self.update(host, |counters| counters.bytes_served += bytes as u64);
+    }
+
+    pub fn fetched(&self, host: &str, elapsed: Duration, ok: bool) {
+        let millis = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        self.update(host, |counters| {
+            counters.fetches += 1;
+            counters.fetch_failures += u64::from(!ok);
+            counters.fetch_millis = counters.fetch_millis.saturating_add(millis);
+            counters.max_fetch_millis = counters.max_fetch_millis.max(millis);
+        });
+    }
+
+    /// The counters so far, hosts named by `plugin_ids` where it knows them.
+    pub fn snapshot(&self, plugin_ids: &HashMap<String, String>) -> CacheStats {
+        let Ok(inner) = self.inner.lock() else {
+            return CacheStats::default();
+        };
+        let mut stats = CacheStats {
+            since: inner.since,
+            ..CacheStats::default()
+        };
+        for (host, counters) in &inner.hosts {
+            stats.total.add(counters);
+            let name = plugin_ids.get(host).unwrap_or(host);
+            stats.plugins.entry(name.clone()).or_default().add(counters);
+        }
+        stats
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            *inner = Inner {
+                since: now_secs(),
+                hosts: HashMap::new(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_plugin_and_in_total() {
+        let stats = Stats::default();
+        stats.hit("a");
+        stats.served("a", 10);
+        stats.miss("a");
+        stats.not_found("a", NotFound::NotCached);
+        stats.fetched("b", Duration::from_millis(30), true);
+        stats.fetched("b", Duration::from_millis(50), false);
+
+        let snapshot = stats.snapshot(&HashMap::from([("a".to_string(), "plugin-a".to_string())]));
+        let a = &snapshot.plugins["plugin-a"];
+        assert_eq!((a.hits, a.misses, a.bytes_served), (1, 1, 10));
+        assert_eq!(a.not_found[&NotFound::NotCached], 1);
+        let b = &snapshot.plugins["b"];
+        assert_eq!(
+            (
+                b.fetches,
+                b.fetch_failures,
+                b.fetch_millis,
+                b.max_fetch_millis
+            ),
+            (2, 1, 80, 50)
+        );
+        assert_eq!((snapshot.total.hits, snapshot.total.fetches), (1, 2));
+        assert_eq!(
+            serde_json::to_value(&snapshot.total.not_found).unwrap(),
+            serde_json::json!({ "notCached": 1 })
+        );
+
+        stats.reset();
+        assert!(stats.snapshot(&HashMap::new()).plugins.is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+//! Free space on the volume holding the cache.
+//!
+//! `AssetCache` keeps `DEFAULT_RESERVE` bytes free for everything else on the device: a download
+//! that wouldn't fit alongside the reserve is refused before it starts, and once free space drops
+//! under the reserve the cache is in low-storage mode, pausing background fetches and evicting
+//! unpinned plugins until there is room again.
+
+use std::path::Path;
+
+/// Space left free by default for the rest of the device.
+pub const DEFAULT_RESERVE: u64 = 256 * 1024 * 1024;
+
+/// Allowance per cached file for its sidecar, sealing overhead and block rounding, on top of the
+/// file's own size.
+pub const PER_FILE_OVERHEAD: u64 = 4096;
+
+/// Bytes available to us on the volume holding `path` (or, if it doesn't exist yet, its nearest
+/// existing ancestor). `None` where that can't be determined.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is NUL-terminated, and `statvfs` only writes into `stat`.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // Field widths vary by platform.
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let path: Vec<u16> = existing.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: `path` is NUL-terminated, and the call only writes into `available`; the totals it
+    // can also report are optional.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return None;
+    }
+    Some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn measures_the_nearest_existing_directory() {
+        let dir = TempDir::new().unwrap();
+        let available = available_space(dir.path()).unwrap();
+        assert!(available > 0);
+        assert!(available_space(&dir.path().join("not/created/yet")).is_some());
+    }
+}
@@ -0,0 +1,2245 @@
+//! The plugin cache itself, independent of Tauri.
+//!
+//! An `AssetCache` is a storage root, the key file sealing it, and a `Fetcher` to reach plugin
+//! origins through. The commands and URI handler in the parent module are thin wrappers binding
+//! one to the app's directories and event bus; tests bind one to a temporary directory and an
+//! in-memory origin.
+//!
+//! Beyond filling and serving the layout, it keeps cached plugins current and available:
+//! - read-through (off by default): a miss for a plugin with an index is fetched from its origin,
+//!   stored and served on the spot;
+//! - files without a content hash in their name are served stale-while-revalidate once older than
+//!   their `RevalidateRule` TTL, emitting `ASSET_UPDATED_EVENT` when a refetch changes them;
+//! - pinned plugins are spared by garbage collection and eviction, and files they are missing are
+//!   fetched again once the network is back;
+//! - bundles shipped under the `plugin-seeds` resource directory are seeded at startup.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::{Mutex, OnceCell, OwnedMutexGuard, RwLock, RwLockReadGuard};
+
+use super::confine::confine;
+use super::delta::{apply_patch, previous_by_digest, read_previous, Delta, UpdateReport};
+use super::fetch::{FetchError, Fetched, Fetcher, Validators};
+use super::layout::{
+    asset_path, digest, dir_size, files_under, hash, indexed_plugins, local_path, meta_path,
+    now_secs, origin_url, plain_path, plugin_dirs, read_index, read_meta, remove_plugin,
+    revalidate_ttl, same_path, store_asset, url_path, write_atomic, write_index, write_meta,
+    AssetMeta, Index, RevalidateRule, INDEX_FILE,
+};
+use super::migrate::{self, FORMAT_VERSION};
+use super::mime::{detect_mime, mime_essence, with_charset, OCTET_STREAM};
+use super::response::{cache_control, etag_matches, method_not_allowed, not_found, preflight};
+use super::seal::Keys;
+use super::stats::{CacheStats, NotFound, Stats, BAD_HOSTS};
+use super::storage::{available_space, DEFAULT_RESERVE, PER_FILE_OVERHEAD};
+use super::URI_SCHEME;
+
+/// First and longest wait between attempts to restore pinned plugins' missing files.
+const RESTORE_RETRY_MIN: Duration = Duration::from_secs(15);
+const RESTORE_RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+/// Restore passes per launch; with the backoff above, about an hour and a half of trying.
+const RESTORE_ATTEMPTS: u32 = 12;
+/// Origins asked for file sizes at once before caching a plugin (see `probe_sizes`).
+const SIZE_PROBES: usize = 8;
+
+pub struct AssetCache {
+    root: PathBuf,
+    key_file: PathBuf,
+    fetcher: Arc<dyn Fetcher>,
+    /// Taken shared by every write confined to one plugin (see `lock_plugin`), and exclusively by
+    /// passes over the whole cache: garbage collection, seeding, eviction for space, re-sealing.
+    cache_lock: RwLock<()>,
+    /// Serializes writes to each plugin directory, by `dxos-plugin://` host.
+    plugin_locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Whether `serve` fetches misses from the plugin's origin instead of answering 404.
+    read_through: AtomicBool,
+    /// Read-through fetches in progress, keyed by destination path, so parallel requests for
+    /// the same chunk share a single download.
+    in_flight: std::sync::Mutex<HashMap<PathBuf, InFlight>>,
+    /// Paths with a background revalidation under way, so a burst of loads refetches once.
+    revalidating: std::sync::Mutex<HashSet<PathBuf>>,
+    /// Encryption keys, loaded from `key_file` on first use.
+    keys: std::sync::RwLock<Option<Arc<Keys>>>,
+    /// Outcome of bringing the on-disk format up to date, settled once per instance.
+    migrated: OnceCell<Result<(), String>>,
+    /// Local directories served in place of the cache, by `dxos-plugin://` host (see `dev`).
+    dev_sources: std::sync::RwLock<HashMap<String, PathBuf>>,
+    /// Bytes to leave free on the cache's volume (see `storage`).
+    reserve: AtomicU64,
+    /// Whether free space was under `reserve` when last checked.
+    low_storage: AtomicBool,
+    /// Hits, misses and fetches since startup or the last `reset_stats`.
+    stats: Stats,
+}
+
+/// Why caching a plugin failed, for callers that handle running out of space differently.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum CacheError {
+    /// The plugin's files need `needed` bytes, but only `available` can be spared.
+    InsufficientStorage {
+        needed: u64,
+        available: u64,
+    },
+    Other {
+        message: String,
+    },
+}
+
+impl From<String> for CacheError {
+    fn from(message: String) -> Self {
+        Self::Other { message }
+    }
+}
+
+impl From<FetchError> for CacheError {
+    fn from(error: FetchError) -> Self {
+        String::from(error).into()
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InsufficientStorage { needed, available } => {
+                write!(
+                    f,
+                    "insufficient storage: {} bytes needed, {} available",
+                    needed, available
+                )
+            }
+            Self::Other { message } => f.write_str(message),
+        }
+    }
+}
+
+/// A file `AssetCache::reuse` built from the previous version of its plugin.
+enum Reused {
+    Copied(Fetched),
+    /// Patched, with the size of the patch downloaded.
+    Patched(Fetched, u64),
+}
+
+/// Held while writing to one plugin directory (see `AssetCache::lock_plugin`).
+struct PluginGuard<'a> {
+    _cache: RwLockReadGuard<'a, ()>,
+    _plugin: OwnedMutexGuard<()>,
+}
+
+/// Outcome of a read-through fetch, shared by every request waiting on it.
+type InFlight = Arc<OnceCell<Result<(), String>>>;
+
+/// A cached entry `serve` found past its TTL, to be handed to `AssetCache::revalidate`.
+pub struct Stale {
+    plugin_root: PathBuf,
+    host: String,
+    path: String,
+    meta: AssetMeta,
+}
+
+/// A revalidation that replaced a cached file with new content.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetUpdated {
+    pub plugin_id: String,
+    pub url: String,
+    /// The updated file's `dxos-plugin://` URL.
+    pub cached_url: String,
+}
+
+/// A browser import map (`<script type="importmap">`): module specifiers, here remote URLs, to the
+/// URLs to load them from instead.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportMap {
+    pub imports: BTreeMap<String, String>,
+}
+
+impl AssetCache {
+    /// A cache stored under `root`, sealed with the keys in `key_file`, filled through `fetcher`.
+    /// Nothing is read until first use.
+    pub fn new(root: PathBuf, key_file: PathBuf, fetcher: Arc<dyn Fetcher>) -> Self {
+        Self {
+            root,
+            key_file,
+            fetcher,
+            cache_lock: RwLock::default(),
+            plugin_locks: std::sync::Mutex::default(),
+            read_through: AtomicBool::default(),
+            in_flight: std::sync::Mutex::default(),
+            revalidating: std::sync::Mutex::default(),
+            keys: std::sync::RwLock::default(),
+            migrated: OnceCell::new(),
+            dev_sources: std::sync::RwLock::default(),
+            reserve: AtomicU64::new(DEFAULT_RESERVE),
+            low_storage: AtomicBool::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    fn plugin_dir(&self, plugin_id: &str) -> PathBuf {
+        self.root.join(hash(plugin_id))
+    }
+
+    /// Waits until nothing else writes to the plugin served at `host`, nor runs a pass over the
+    /// whole cache, and keeps it so until the guard is dropped.
+    async fn lock_plugin(&self, host: &str) -> PluginGuard<'_> {
+        let cache = self.cache_lock.read().await;
+        let lock = match self.plugin_locks.lock() {
+            Ok(mut locks) => {
+                // Locks nobody holds or waits on are dropped, so the map stays as small as the
+                // number of plugins being written to.
+                locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+                Arc::clone(locks.entry(host.to_string()).or_default())
+            }
+            Err(_) => Arc::default(),
+        };
+        PluginGuard {
+            _cache: cache,
+            _plugin: lock.lock_owned().await,
+        }
+    }
+
+    /// The keys cache files are currently sealed and opened with.
+    fn keys(&self) -> Result<Arc<Keys>, String> {
+        if let Some(keys) = self.keys.read().map_err(|e| e.to_string())?.as_ref() {
+            return Ok(Arc::clone(keys));
+        }
+        let keys = Arc::new(Keys::load(&self.key_file)?);
+        *self.keys.write().map_err(|e| e.to_string())? = Some(Arc::clone(&keys));
+        Ok(keys)
+    }
+
+    fn set_keys(&self, keys: Keys) -> Result<(), String> {
+        *self.keys.write().map_err(|e| e.to_string())? = Some(Arc::new(keys));
+        Ok(())
+    }
+
+    /// Keys for a cache that has been migrated to `FORMAT_VERSION`: the gate every disk access
+    /// goes through. Fails for good if the cache was written by a newer build.
+    async fn ready(&self) -> Result<Arc<Keys>, String> {
+        let keys = self.keys()?;
+        let (root, migration_keys) = (self.root.clone(), Arc::clone(&keys));
+        self.migrated
+            .get_or_init(|| async move {
+                let migrated =
+                    tokio::task::spawn_blocking(move || migrate::migrate(&root, &migration_keys))
+                        .await;
+                let result = migrated
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result);
+                if let Err(e) = &result {
+                    log::error!("[asset-cache] {}", e);
+                }
+                result
+            })
+            .await
+            .clone()?;
+        Ok(keys)
+    }
+
+    async fn fetch_one(&self, host: &str, url: &str) -> Result<Fetched, FetchError> {
+        let unexpected = || FetchError::transient(format!("fetch {}: unexpected 304", url));
+        self.fetch(host, url, None).await?.ok_or_else(unexpected)
+    }
+
+    /// `fetcher.fetch`, counted in the stats of the plugin served at `host`.
+    async fn fetch(
+        &self,
+        host: &str,
+        url: &str,
+        validators: Option<&Validators>,
+    ) -> Result<Option<Fetched>, FetchError> {
+        let started = Instant::now();
+        let result = self.fetcher.fetch(url, validators).await;
+        self.stats.fetched(host, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// Hit, miss and fetch counters, by plugin id (see `stats`).
+    pub async fn stats(&self) -> CacheStats {
+        let mut plugin_ids = HashMap::new();
+        if let Ok(keys) = self.ready().await {
+            for (_, index) in indexed_plugins(&keys, &self.root).await.unwrap_or_default() {
+                plugin_ids.insert(hash(&index.plugin_id), index.plugin_id);
+            }
+        }
+        self.stats.snapshot(&plugin_ids)
+    }
+
+    /// Starts the counters `stats` reports over from zero.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Fetches every URL of a plugin not cached yet and records them in its index.
+    pub async fn cache_plugin(
+        &self,
+        plugin_id: &str,
+        urls: Vec<String>,
+    ) -> Result<UpdateReport, CacheError> {
+        self.cache_plugin_with(plugin_id, urls, &HashMap::new(), &Delta::default())
+            .await
+    }
+
+    /// `cache_plugin`, with the sizes of some files declared up front (by URL) rather than asked
+    /// of their origin, and the files `delta` lets us build from the previous version built
+    /// locally. Nothing is fetched unless every missing file fits in the space to spare.
+    pub async fn cache_plugin_with(
+        &self,
+        plugin_id: &str,
+        urls: Vec<String>,
+        sizes: &HashMap<String, u64>,
+        delta: &Delta,
+    ) -> Result<UpdateReport, CacheError> {
+        let dir = self.plugin_dir(plugin_id);
+        // Sizes neither declared nor known from a file `delta` copies are asked of the origin
+        // before taking the plugin's lock, so a slow origin holds up nothing else.
+        let mut sizes = sizes.clone();
+        let keys = self.ready().await?;
+        let by_digest = previous_by_digest(&keys, &dir, delta).await;
+        let unknown = missing_urls(&dir, &urls)
+            .await?
+            .into_iter()
+            .filter(|url| !sizes.contains_key(*url))
+            .filter(|url| {
+                delta
+                    .digests
+                    .get(*url)
+                    .and_then(|digest| by_digest.get(digest))
+                    .is_none()
+            })
+            .map(str::to_string)
+            .collect();
+        sizes.extend(self.probe_sizes(unknown).await);
+
+        let guard = self.lock_plugin(&hash(plugin_id)).await;
+        let keys = self.ready().await?;
+        let mut paths = HashMap::new();
+        for url in &urls {
+            if let Some(other) = paths.insert(asset_path(&dir, url)?, url) {
+                if !same_path(other, &url_path(url)?) {
+                    return Err(format!("{} and {} would share a cached file", other, url).into());
+                }
+            }
+        }
+        let missing = missing_urls(&dir, &urls).await?;
+        let by_digest = previous_by_digest(&keys, &dir, delta).await;
+        for url in &missing {
+            let copy_from = delta
+                .digests
+                .get(*url)
+                .and_then(|digest| by_digest.get(digest));
+            if let Some(from) = copy_from {
+                if let Ok(metadata) = tokio::fs::metadata(asset_path(&dir, from)?).await {
+                    sizes.entry(url.to_string()).or_insert(metadata.len());
+                }
+            }
+        }
+        if let Err(e) = self.check_fits(&missing, &sizes) {
+            // Out of room: free what can be freed for next time, as the startup check would.
+            drop(guard);
+            self.check_storage().await;
+            return Err(e);
+        }
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut report = UpdateReport::default();
+        for url in &urls {
+            let bytes_path = asset_path(&dir, url)?;
+            let meta_path = meta_path(&dir, url)?;
+            // Require BOTH the bytes and the meta sidecar before considering an entry cached.
+            // If a previous run died after writing bytes but before writing meta (network error
+            // or process crash), the lone bytes file alone shouldn't trick us into skipping —
+            // re-fetch the URL so the meta is always present alongside the body. Hashed asset
+            // filenames in the manifest mean real plugin updates produce fresh entries naturally.
+            if tokio::fs::metadata(&bytes_path).await.is_ok()
+                && tokio::fs::metadata(&meta_path).await.is_ok()
+            {
+                continue;
+            }
+            let fetched = match self
+                .reuse(&keys, &dir, plugin_id, url, delta, &by_digest)
+                .await
+            {
+                Some(Reused::Copied(copied)) => {
+                    report.copied += 1;
+                    report.bytes_saved += copied.bytes.len() as u64;
+                    copied
+                }
+                Some(Reused::Patched(patched, patch_len)) => {
+                    report.patched += 1;
+                    report.bytes_fetched += patch_len;
+                    report.bytes_saved += (patched.bytes.len() as u64).saturating_sub(patch_len);
+                    patched
+                }
+                None => {
+                    let fetched = self.fetch_one(&hash(plugin_id), url).await?;
+                    report.fetched += 1;
+                    report.bytes_fetched += fetched.bytes.len() as u64;
+                    fetched
+                }
+            };
+            store_asset(&keys, &dir, url, fetched).await?;
+        }
+
+        // Revalidation rules and pinning outlive re-caching: they're set per plugin, not per URL list.
+        let previous = read_index(&keys, &dir).await;
+        let index = Index {
+            plugin_id: plugin_id.to_string(),
+            format: FORMAT_VERSION,
+            urls,
+            revalidate: previous
+                .as_ref()
+                .map(|index| index.revalidate.clone())
+                .unwrap_or_default(),
+            pinned: previous.as_ref().is_some_and(|index| index.pinned),
+            seed_digest: previous.and_then(|index| index.seed_digest),
+        };
+        write_index(&keys, &dir, &index).await?;
+        Ok(report)
+    }
+
+    /// The new content of `url` built from the previous version of the plugin, if `delta` allows:
+    /// copied when a previous file has the digest it declares, else patched. `None` leaves it to
+    /// a full fetch.
+    async fn reuse(
+        &self,
+        keys: &Keys,
+        dir: &Path,
+        plugin_id: &str,
+        url: &str,
+        delta: &Delta,
+        by_digest: &HashMap<String, String>,
+    ) -> Option<Reused> {
+        let expected = delta.digests.get(url)?;
+        if let Some(from) = by_digest.get(expected) {
+            if let Some(copied) = read_previous(keys, dir, from).await {
+                return Some(Reused::Copied(copied));
+            }
+        }
+
+        let patch = delta.patches.get(url)?;
+        let old = read_previous(keys, dir, &patch.from).await?;
+        let diff = match self.fetch_one(&hash(plugin_id), &patch.url).await {
+            Ok(diff) => diff.bytes,
+            Err(e) => {
+                log::debug!("[asset-cache] patch for {}: {}", url, e);
+                return None;
+            }
+        };
+        match apply_patch(&old.bytes, &diff) {
+            Ok(bytes) if digest(&bytes) == *expected => {
+                let patched = Fetched {
+                    bytes,
+                    mime: None,
+                    etag: None,
+                    last_modified: None,
+                };
+                Some(Reused::Patched(patched, diff.len() as u64))
+            }
+            Ok(_) => {
+                log::warn!("[asset-cache] patch {} doesn't produce {}", patch.url, url);
+                None
+            }
+            Err(e) => {
+                log::warn!("[asset-cache] patch {}: {}", patch.url, e);
+                None
+            }
+        }
+    }
+
+    /// Sizes of `urls` as their origins report them, asking at most `SIZE_PROBES` at a time.
+    /// URLs whose size isn't reported are left out, and nothing is asked where free space can't be
+    /// measured anyway.
+    async fn probe_sizes(&self, urls: Vec<String>) -> HashMap<String, u64> {
+        let mut sizes = HashMap::new();
+        if available_space(&self.root).is_none() {
+            return sizes;
+        }
+        let mut urls = urls.into_iter();
+        let mut probes = tokio::task::JoinSet::new();
+        loop {
+            while probes.len() < SIZE_PROBES {
+                let Some(url) = urls.next() else {
+                    break;
+                };
+                let fetcher = self.fetcher.clone();
+                probes.spawn(async move {
+                    let size = fetcher.size(&url).await;
+                    (url, size)
+                });
+            }
+            let Some(probed) = probes.join_next().await else {
+                return sizes;
+            };
+            if let Ok((url, Ok(Some(size)))) = probed {
+                sizes.insert(url, size);
+            }
+        }
+    }
+
+    /// Fails if fetching `urls` would eat into the reserve. A size not in `sizes` (neither declared
+    /// nor reported by the origin) counts as nothing: the fetch itself is then the first to know.
+    fn check_fits(&self, urls: &[&str], sizes: &HashMap<String, u64>) -> Result<(), CacheError> {
+        let Some(available) = available_space(&self.root) else {
+            return Ok(());
+        };
+        let mut needed = 0u64;
+        for url in urls {
+            let size = sizes.get(*url).copied().unwrap_or(0);
+            needed = needed
+                .saturating_add(size)
+                .saturating_add(PER_FILE_OVERHEAD);
+        }
+        let spare = available.saturating_sub(self.reserve.load(Ordering::Relaxed));
+        if needed > spare {
+            return Err(CacheError::InsufficientStorage {
+                needed,
+                available: spare,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets how many bytes to leave free on the cache's volume (`storage::DEFAULT_RESERVE` unless
+    /// changed).
+    pub fn set_storage_reserve(&self, bytes: u64) {
+        self.reserve.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Whether the cache was in low-storage mode when last checked (see `check_storage`).
+    pub fn low_storage(&self) -> bool {
+        self.low_storage.load(Ordering::Relaxed)
+    }
+
+    /// Checks free space against the reserve. Under it, the cache is in low-storage mode: unpinned
+    /// plugins are evicted, least recently cached first, until there is room again, and background
+    /// fetches (revalidation, restoring pinned plugins) pause while it lasts. Returns whether the
+    /// cache is still short of space.
+    pub async fn check_storage(&self) -> bool {
+        let reserve = self.reserve.load(Ordering::Relaxed);
+        let deficit =
+            || available_space(&self.root).map_or(0, |available| reserve.saturating_sub(available));
+        let missing = deficit();
+        if missing == 0 {
+            if self.low_storage.swap(false, Ordering::Relaxed) {
+                log::info!("[asset-cache] storage recovered; resuming background fetches");
+            }
+            return false;
+        }
+        if !self.low_storage.swap(true, Ordering::Relaxed) {
+            log::warn!("[asset-cache] low on storage; pausing background fetches");
+        }
+        if let Err(e) = self.evict_unpinned(missing).await {
+            log::warn!("[asset-cache] evict for space: {}", e);
+        }
+        let still_short = deficit() > 0;
+        self.low_storage.store(still_short, Ordering::Relaxed);
+        still_short
+    }
+
+    /// Evicts unpinned plugins, least recently cached first, until they have freed `missing` bytes.
+    /// If all of them together hold less, none is evicted: the cache would be lost without getting
+    /// back to the reserve.
+    async fn evict_unpinned(&self, missing: u64) -> Result<(), String> {
+        let _guard = self.cache_lock.write().await;
+        let keys = self.ready().await?;
+        let mut unpinned = Vec::new();
+        for (dir, index) in indexed_plugins(&keys, &self.root).await? {
+            if !index.pinned {
+                let cached_at = tokio::fs::metadata(dir.join(INDEX_FILE))
+                    .await
+                    .and_then(|m| m.modified())
+                    .ok();
+                let size = dir_size(&dir).await?;
+                unpinned.push((cached_at, dir, index.plugin_id, size));
+            }
+        }
+        let held: u64 = unpinned.iter().map(|(.., size)| size).sum();
+        if held < missing {
+            log::info!(
+                "[asset-cache] unpinned plugins hold {} bytes of the {} missing; keeping them",
+                held,
+                missing
+            );
+            return Ok(());
+        }
+        unpinned.sort_by_key(|(cached_at, ..)| *cached_at);
+        let mut freed = 0;
+        for (_, dir, plugin_id, size) in unpinned {
+            if freed >= missing {
+                break;
+            }
+            log::info!("[asset-cache] evicting {} to free space", plugin_id);
+            remove_plugin(&dir).await?;
+            freed += size;
+        }
+        Ok(())
+    }
+
+    pub async fn evict(&self, plugin_id: &str) -> Result<(), String> {
+        let _guard = self.lock_plugin(&hash(plugin_id)).await;
+        remove_plugin(&self.plugin_dir(plugin_id)).await
+    }
+
+    /// The `dxos-plugin://` URL serving `url` of `plugin_id`, if it is cached.
+    pub async fn resolve(&self, plugin_id: &str, url: &str) -> Result<Option<String>, String> {
+        let bytes_path = asset_path(&self.plugin_dir(plugin_id), url)?;
+        if tokio::fs::metadata(&bytes_path).await.is_ok() {
+            Ok(Some(cached_url(plugin_id, url)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `resolve` for many URLs of one plugin, answered in the order asked.
+    pub async fn resolve_many(
+        &self,
+        plugin_id: &str,
+        urls: &[String],
+    ) -> Result<Vec<Option<String>>, String> {
+        let mut resolved = Vec::with_capacity(urls.len());
+        for url in urls {
+            resolved.push(self.resolve(plugin_id, url).await?);
+        }
+        Ok(resolved)
+    }
+
+    /// An import map sending every cached file of `plugin_id` (of every cached plugin, if `None`)
+    /// from its original URL to the `dxos-plugin://` URL serving it. Files listed but missing are
+    /// left out, so they keep loading from their origin.
+    pub async fn import_map(&self, plugin_id: Option<&str>) -> Result<ImportMap, String> {
+        let keys = self.ready().await?;
+        let plugins = match plugin_id {
+            Some(plugin_id) => {
+                let dir = self.plugin_dir(plugin_id);
+                let index = read_index(&keys, &dir)
+                    .await
+                    .ok_or_else(|| format!("plugin {} is not cached", plugin_id))?;
+                vec![(dir, index)]
+            }
+            None => indexed_plugins(&keys, &self.root).await?,
+        };
+        let mut map = ImportMap::default();
+        for (dir, index) in plugins {
+            for url in &index.urls {
+                if tokio::fs::metadata(asset_path(&dir, url)?).await.is_ok() {
+                    map.imports
+                        .insert(url.clone(), cached_url(&index.plugin_id, url)?);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Ids of every cached plugin.
+    pub async fn plugins(&self) -> Result<Vec<String>, String> {
+        let keys = self.ready().await?;
+        let plugins = indexed_plugins(&keys, &self.root).await?;
+        Ok(plugins
+            .into_iter()
+            .map(|(_, index)| index.plugin_id)
+            .collect())
+    }
+
+    /// Replaces a cached plugin's revalidation rules (see `RevalidateRule`).
+    pub async fn set_revalidation(
+        &self,
+        plugin_id: &str,
+        rules: Vec<RevalidateRule>,
+    ) -> Result<(), String> {
+        self.update_index(plugin_id, |index| index.revalidate = rules)
+            .await
+    }
+
+    pub async fn set_pinned(&self, plugin_id: &str, pinned: bool) -> Result<(), String> {
+        self.update_index(plugin_id, |index| index.pinned = pinned)
+            .await
+    }
+
+    async fn update_index(
+        &self,
+        plugin_id: &str,
+        update: impl FnOnce(&mut Index),
+    ) -> Result<(), String> {
+        let _guard = self.lock_plugin(&hash(plugin_id)).await;
+        let keys = self.ready().await?;
+        let dir = self.plugin_dir(plugin_id);
+        let mut index = read_index(&keys, &dir)
+            .await
+            .ok_or_else(|| format!("plugin {} is not cached", plugin_id))?;
+        update(&mut index);
+        write_index(&keys, &dir, &index).await
+    }
+
+    /// Seeds the cache from plugin bundles shipped with the app: each directory under `bundles`
+    /// holds one plugin as `plugin-cache export` writes it. A bundle is identified by the digest of
+    /// its content (see `content_digest`), recorded in the index of the plugin it seeds. It replaces
+    /// a cached copy only while that copy is still the content last seeded, so whatever the user
+    /// fetched from the origin since is never rolled back; revalidation confirming a file unchanged
+    /// doesn't count as fetching it anew. Each bundle is considered once. Returns the ids of the
+    /// plugins seeded.
+    pub async fn seed(&self, bundles: &Path) -> Result<Vec<String>, String> {
+        let _guard = self.cache_lock.write().await;
+        let keys = self.ready().await?;
+        let mut seeded = Vec::new();
+        for bundle in plugin_dirs(bundles).await? {
+            match self.seed_one(&keys, &bundle).await {
+                Ok(Some(plugin_id)) => seeded.push(plugin_id),
+                Ok(None) => {}
+                Err(e) => log::warn!("[asset-cache] seed from {}: {}", bundle.display(), e),
+            }
+        }
+        Ok(seeded)
+    }
+
+    async fn seed_one(&self, keys: &Keys, bundle: &Path) -> Result<Option<String>, String> {
+        // Bundles are plaintext, and built by the same release that reads them.
+        let plaintext = Keys::default();
+        let bundled = read_index(&plaintext, bundle)
+            .await
+            .ok_or_else(|| "no readable index".to_string())?;
+        if bundled.format != FORMAT_VERSION {
+            return Err(format!(
+                "bundle is format v{}, not v{}",
+                bundled.format, FORMAT_VERSION
+            ));
+        }
+        let mut files = Vec::new();
+        for url in &bundled.urls {
+            let meta = read_meta(&plaintext, &meta_path(bundle, url)?).await;
+            let bytes = tokio::fs::read(asset_path(bundle, url)?).await;
+            match (meta, bytes) {
+                (Some(meta), Ok(bytes)) if meta.url == *url => files.push((meta, bytes)),
+                _ => return Err(format!("{} is missing from the bundle", url)),
+            }
+        }
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let version = content_digest(
+            files
+                .iter()
+                .map(|(meta, bytes)| (meta.url.clone(), digest(bytes))),
+        );
+
+        let dir = self.plugin_dir(&bundled.plugin_id);
+        let cached = read_index(keys, &dir).await;
+        if let Some(cached) = cached.as_ref() {
+            if cached.seed_digest.as_ref() == Some(&version) {
+                return Ok(None);
+            }
+            // Only a copy still exactly as last seeded is the app's to replace.
+            let mut digests = Vec::new();
+            for url in &cached.urls {
+                let sha256 = read_meta(keys, &meta_path(&dir, url)?)
+                    .await
+                    .and_then(|meta| meta.sha256);
+                digests.push((url.clone(), sha256.unwrap_or_default()));
+            }
+            if cached.seed_digest.as_ref() != Some(&content_digest(digests.into_iter())) {
+                let index = Index {
+                    seed_digest: Some(version),
+                    ..cached.clone()
+                };
+                return write_index(keys, &dir, &index).await.map(|_| None);
+            }
+        }
+
+        for (meta, bytes) in &files {
+            let bytes_path = asset_path(&dir, &meta.url)?;
+            if let Some(parent) = bytes_path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            write_atomic(&bytes_path, &keys.seal(bytes)?).await?;
+            write_meta(keys, &dir, meta).await?;
+        }
+        // Revalidation rules and pinning stay the user's; the URL list is the bundle's.
+        let index = Index {
+            seed_digest: Some(version),
+            revalidate: cached
+                .as_ref()
+                .map_or(bundled.revalidate, |index| index.revalidate.clone()),
+            pinned: cached.is_some_and(|index| index.pinned),
+            ..bundled
+        };
+        write_index(keys, &dir, &index).await?;
+
+        // What the previous copy held beyond the bundle's list goes, read-through files included.
+        let mut listed = HashSet::from([dir.join(INDEX_FILE)]);
+        for (meta, _) in &files {
+            listed.insert(asset_path(&dir, &meta.url)?);
+            listed.insert(meta_path(&dir, &meta.url)?);
+        }
+        for path in files_under(&dir).await? {
+            if !listed.contains(&path) {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(Some(index.plugin_id))
+    }
+
+    /// Startup maintenance: clears debris left by interrupted writes, then restores pinned
+    /// plugins' missing files, retrying with backoff until the network lets every fetch through
+    /// or `RESTORE_ATTEMPTS` passes have run; the next launch tries again.
+    pub async fn startup(&self) {
+        if let Err(e) = self.collect_garbage().await {
+            log::warn!("[asset-cache] garbage collection: {}", e);
+        }
+        let mut delay = RESTORE_RETRY_MIN;
+        for attempt in 1..=RESTORE_ATTEMPTS {
+            if self.check_storage().await {
+                log::info!("[asset-cache] restoring pinned plugins waits for free space");
+            } else {
+                match self.restore_pinned().await {
+                    Ok(0) => return,
+                    Ok(missing) => log::info!(
+                        "[asset-cache] {} pinned file(s) still missing; retrying",
+                        missing
+                    ),
+                    Err(e) => log::warn!("[asset-cache] restore pinned plugins: {}", e),
+                }
+            }
+            if attempt == RESTORE_ATTEMPTS {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RESTORE_RETRY_MAX);
+        }
+        log::warn!("[asset-cache] giving up on restoring pinned plugins until the next launch");
+    }
+
+    /// Removes what interrupted writes leave behind: `.tmp` siblings, and plugin directories that
+    /// never got as far as an `index.json`. Pinned plugins are skipped outright.
+    async fn collect_garbage(&self) -> Result<(), String> {
+        let _guard = self.cache_lock.write().await;
+        let keys = self.ready().await?;
+        for dir in plugin_dirs(&self.root).await? {
+            // Only an index that is absent marks an abandoned directory; one that merely fails to
+            // open (wrong key, corruption) may still belong to a pinned plugin. Staged imports
+            // that never got renamed into place are abandoned whatever they hold.
+            let staged = dir
+                .extension()
+                .is_some_and(|ext| ext == super::admin::STAGING_EXTENSION);
+            if staged || tokio::fs::metadata(dir.join(INDEX_FILE)).await.is_err() {
+                log::info!("[asset-cache] removing unindexed {}", dir.display());
+                tokio::fs::remove_dir_all(&dir)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                continue;
+            }
+            if read_index(&keys, &dir)
+                .await
+                .is_some_and(|index| index.pinned)
+            {
+                continue;
+            }
+            for path in files_under(&dir).await? {
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// One pass fetching the files pinned plugins are missing. Returns how many are still missing
+    /// and worth retrying: a file its origin refused outright is logged and left out of the count.
+    ///
+    /// Fetches run unlocked, so a slow origin holds up nothing else; each file is stored under its
+    /// plugin's lock, and only if the plugin is still pinned and still lists it.
+    pub async fn restore_pinned(&self) -> Result<usize, String> {
+        let keys = self.ready().await?;
+        let mut missing = 0;
+        for (dir, index) in indexed_plugins(&keys, &self.root).await? {
+            if !index.pinned {
+                continue;
+            }
+            let host = hash(&index.plugin_id);
+            for url in &index.urls {
+                let cached = tokio::fs::metadata(asset_path(&dir, url)?).await.is_ok()
+                    && tokio::fs::metadata(meta_path(&dir, url)?).await.is_ok();
+                if cached {
+                    continue;
+                }
+                let restored = match self.fetch_one(&host, url).await {
+                    Ok(fetched) => self.store_restored(&dir, &host, url, fetched).await,
+                    Err(e) if e.permanent => {
+                        log::warn!(
+                            "[asset-cache] restore {} for {}: {}",
+                            url,
+                            index.plugin_id,
+                            e
+                        );
+                        continue;
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = restored {
+                    log::debug!(
+                        "[asset-cache] restore {} for {}: {}",
+                        url,
+                        index.plugin_id,
+                        e
+                    );
+                    missing += 1;
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Stores a file `restore_pinned` fetched for the plugin in `dir`, unless the plugin was
+    /// evicted, unpinned or re-cached without it in the meantime.
+    async fn store_restored(
+        &self,
+        dir: &Path,
+        host: &str,
+        url: &str,
+        fetched: Fetched,
+    ) -> Result<(), String> {
+        let _guard = self.lock_plugin(host).await;
+        // Under the lock, so the keys are those of any re-sealing that ran meanwhile.
+        let keys = self.ready().await?;
+        let index = read_index(&keys, dir).await;
+        let wanted = index
+            .is_some_and(|index| index.pinned && index.urls.iter().any(|listed| listed == url));
+        if !wanted {
+            return Ok(());
+        }
+        store_asset(&keys, dir, url, fetched).await
+    }
+
+    /// Turns encryption at rest on or off, converting everything already cached. Enabling
+    /// generates a key in `key_file`; disabling decrypts the cache back to plaintext and deletes it.
+    /// A conversion that was interrupted (the app quit half-way) is finished by asking again.
+    pub async fn set_encryption(&self, enabled: bool) -> Result<(), String> {
+        let _guard = self.cache_lock.write().await;
+        let current = self.ready().await?;
+        let settled = if enabled {
+            current.all_sealed()
+        } else {
+            !current.has_keys()
+        };
+        if current.enabled() == enabled && settled {
+            return Ok(());
+        }
+        // Each way, the keys converting with are on disk before anything is converted, so an
+        // interrupted conversion leaves a mixed cache that is still fully readable. Only once
+        // every file is sealed does the key file say so, and plaintext start being refused.
+        let next = if enabled {
+            if current.enabled() {
+                Keys::clone(&current)
+            } else {
+                current.rotated()
+            }
+        } else {
+            current.decrypt_only()
+        };
+        next.save(&self.key_file)?;
+        self.set_keys(next.clone())?;
+        let done = if enabled {
+            reseal_all(&self.root, &next, &next).await?;
+            next.sealed_only()
+        } else {
+            reseal_all(&self.root, &next, &Keys::default()).await?;
+            Keys::default()
+        };
+        done.save(&self.key_file)?;
+        self.set_keys(done)
+    }
+
+    /// Replaces the encryption key, re-sealing every cached file under the new one before the old
+    /// key is discarded.
+    pub async fn rotate_key(&self) -> Result<(), String> {
+        let _guard = self.cache_lock.write().await;
+        let current = self.ready().await?;
+        if !current.enabled() {
+            return Err("plugin cache encryption is not enabled".to_string());
+        }
+        let next = current.rotated();
+        next.save(&self.key_file)?;
+        self.set_keys(next.clone())?;
+        reseal_all(&self.root, &next, &next).await?;
+        let next = next.current_only().sealed_only();
+        next.save(&self.key_file)?;
+        self.set_keys(next)
+    }
+
+    /// Turns read-through serving on or off. While on, a miss for a cached plugin is fetched from
+    /// the plugin's origin rather than answered with a 404.
+    pub fn set_read_through(&self, enabled: bool) {
+        self.read_through.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Serves `plugin_id` straight from `dir` while set, in place of anything cached for it; `None`
+    /// goes back to the cache. Nothing under `dir` is copied, sealed or revalidated, so a rebuild
+    /// shows up on the next load.
+    pub fn set_dev_source(&self, plugin_id: &str, dir: Option<PathBuf>) -> Result<(), String> {
+        let mut sources = self.dev_sources.write().map_err(|e| e.to_string())?;
+        match dir {
+            Some(dir) => sources.insert(hash(plugin_id), dir),
+            None => sources.remove(&hash(plugin_id)),
+        };
+        Ok(())
+    }
+
+    /// Answers a `dxos-plugin://<plugin_hash>/<url-path>` request.
+    ///
+    /// Answers like a well-behaved static file server so the webview's HTTP cache does the work:
+    /// every hit carries a strong `ETag` (the content digest) and a `Cache-Control` policy, a
+    /// matching `If-None-Match` gets a bodiless 304, `HEAD` gets the headers alone, and CORS
+    /// preflights are answered without touching disk. A hit past its TTL is served as-is and
+    /// returned alongside for the caller to `revalidate` in the background.
+    pub async fn serve(
+        &self,
+        request: &http::Request<Vec<u8>>,
+    ) -> (http::Response<Vec<u8>>, Option<Stale>) {
+        let method = request.method();
+        if method == http::Method::OPTIONS {
+            return (preflight(request), None);
+        }
+        if method != http::Method::GET && method != http::Method::HEAD {
+            return (method_not_allowed(), None);
+        }
+
+        let uri = request.uri();
+        let host = uri.host().unwrap_or("");
+        let path = uri.path().trim_start_matches('/');
+
+        let dev_dir = self
+            .dev_sources
+            .read()
+            .ok()
+            .and_then(|sources| sources.get(host).cloned());
+        if let Some(dir) = dev_dir {
+            return (serve_dev(request, &dir, path).await, None);
+        }
+
+        let Ok(plugin_root) = confine(&self.root, [host]) else {
+            return (self.not_found(BAD_HOSTS, NotFound::BadHost), None);
+        };
+        let Ok(bytes_path) = local_path(&plugin_root, path) else {
+            return (self.not_found(host, NotFound::BadPath), None);
+        };
+        let mut meta_path_buf = bytes_path.clone();
+        meta_path_buf.as_mut_os_string().push(".meta");
+
+        let Ok(keys) = self.ready().await else {
+            return (self.not_found(host, NotFound::Unavailable), None);
+        };
+
+        let cached = tokio::fs::metadata(&bytes_path).await.is_ok();
+        if !cached {
+            self.stats.miss(host);
+            if self.read_through.load(Ordering::Relaxed) {
+                if let Err(e) = self.read_through(&keys, &plugin_root, host, path).await {
+                    log::warn!("[asset-cache] read-through {}/{}: {}", host, path, e);
+                }
+            }
+        }
+
+        // A file that fails to open (tampered with, or sealed under a discarded key) is as good as
+        // missing: serving ciphertext would only surface as a confusing parse error downstream.
+        let bytes = match tokio::fs::read(&bytes_path).await {
+            Ok(raw) => keys.open(raw).ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return (self.not_found(host, NotFound::NotCached), None);
+            }
+            Err(_) => None,
+        };
+        let Some(bytes) = bytes else {
+            return (self.not_found(host, NotFound::Unreadable), None);
+        };
+        let meta = read_meta(&keys, &meta_path_buf).await;
+        // Another path whose file name was shortened to the same one (see `disk_path`).
+        if meta
+            .as_ref()
+            .is_some_and(|meta| !same_path(&meta.url, path))
+        {
+            return (self.not_found(host, NotFound::OtherPath), None);
+        }
+
+        // Entries written before the sidecar learned charsets (or whose sidecar went missing) are
+        // normalized here too, so a module script is never served as an opaque blob.
+        let mime = meta
+            .as_ref()
+            .map(|m| m.mime.clone())
+            .filter(|mime| mime_essence(mime) != OCTET_STREAM)
+            .map(|mime| with_charset(&mime))
+            .unwrap_or_else(|| detect_mime(path, &bytes));
+        let etag = format!(
+            "\"{}\"",
+            meta.as_ref()
+                .and_then(|m| m.sha256.clone())
+                .unwrap_or_else(|| digest(&bytes))
+        );
+
+        // Serve what we have now; a stale copy is refreshed behind the response for the next load.
+        let mut stale = None;
+        if let Some(meta) = meta {
+            let rules = read_index(&keys, &plugin_root)
+                .await
+                .map(|index| index.revalidate)
+                .unwrap_or_default();
+            if revalidate_ttl(&rules, path)
+                .is_some_and(|ttl| now_secs().saturating_sub(meta.fetched_at) >= ttl)
+            {
+                stale = Some(Stale {
+                    plugin_root,
+                    host: host.to_string(),
+                    path: path.to_string(),
+                    meta,
+                });
+            }
+        }
+
+        let response = respond(request, bytes, &mime, &etag, cache_control(path));
+        if cached {
+            self.stats.hit(host);
+        }
+        self.stats.served(host, response.body().len());
+        (response, stale)
+    }
+
+    /// A 404, counted under `reason`.
+    fn not_found(&self, host: &str, reason: NotFound) -> http::Response<Vec<u8>> {
+        self.stats.not_found(host, reason);
+        not_found()
+    }
+
+    /// Refetches a stale entry conditionally and stores the result for the next load. Returns
+    /// what changed, if the content did. Failures (typically: offline) just leave the cached copy
+    /// in place until the next attempt, as does low storage (see `check_storage`).
+    pub async fn revalidate(&self, stale: Stale) -> Option<AssetUpdated> {
+        if self.check_storage().await {
+            return None;
+        }
+        let Stale {
+            plugin_root,
+            host,
+            path,
+            meta,
+        } = stale;
+        let Ok(key) = local_path(&plugin_root, &path) else {
+            return None;
+        };
+        let started = self
+            .revalidating
+            .lock()
+            .is_ok_and(|mut revalidating| revalidating.insert(key.clone()));
+        if !started {
+            return None;
+        }
+
+        let result = match self.ready().await {
+            Ok(keys) => {
+                let changed = self
+                    .revalidate_entry(&keys, &plugin_root, &host, meta.clone())
+                    .await;
+                changed.map(|changed| (keys, changed))
+            }
+            Err(e) => Err(e),
+        };
+        let updated = match result {
+            Ok((keys, true)) => {
+                let plugin_id = read_index(&keys, &plugin_root)
+                    .await
+                    .map(|index| index.plugin_id)
+                    .unwrap_or_default();
+                let cached_url = format!("{}://{}/{}", URI_SCHEME, host, path);
+                Some(AssetUpdated {
+                    plugin_id,
+                    url: meta.url,
+                    cached_url,
+                })
+            }
+            Ok((_, false)) => None,
+            Err(e) => {
+                log::warn!("[asset-cache] revalidate {}/{}: {}", host, path, e);
+                None
+            }
+        };
+
+        if let Ok(mut revalidating) = self.revalidating.lock() {
+            revalidating.remove(&key);
+        }
+        updated
+    }
+
+    /// Conditionally refetches one entry and stores the outcome under the plugin's lock. Returns
+    /// whether the content changed.
+    async fn revalidate_entry(
+        &self,
+        keys: &Keys,
+        plugin_root: &Path,
+        host: &str,
+        meta: AssetMeta,
+    ) -> Result<bool, String> {
+        let validators = Validators {
+            etag: meta.etag.clone(),
+            last_modified: meta.last_modified.clone(),
+        };
+        let fetched = self.fetch(host, &meta.url, Some(&validators)).await?;
+        let _guard = self.lock_plugin(host).await;
+        // Storing into an evicted plugin would leave a directory without an index.
+        if read_index(keys, plugin_root).await.is_none() {
+            return Err("plugin evicted during revalidation".to_string());
+        }
+        match fetched {
+            // Still current: restart the TTL.
+            None => {
+                let touched = AssetMeta {
+                    fetched_at: now_secs(),
+                    ..meta
+                };
+                write_meta(keys, plugin_root, &touched).await.map(|_| false)
+            }
+            Some(fetched) => {
+                let changed = meta.sha256.as_deref() != Some(digest(&fetched.bytes).as_str());
+                store_asset(keys, plugin_root, &meta.url, fetched)
+                    .await
+                    .map(|_| changed)
+            }
+        }
+    }
+
+    /// Fetches a missing `<url-path>` of the plugin cached under `plugin_root` and stores it.
+    ///
+    /// Only plugins we hold an index for qualify: the index is what maps the hashed host back to
+    /// an origin. Requests that race on the same path join the fetch already under way. The fetch
+    /// itself runs unlocked — a serve shouldn't queue behind a whole `cache_plugin` — and only
+    /// storing the result takes the plugin's lock, giving up if the plugin was evicted meanwhile.
+    async fn read_through(
+        &self,
+        keys: &Keys,
+        plugin_root: &Path,
+        host: &str,
+        path: &str,
+    ) -> Result<(), String> {
+        let index = read_index(keys, plugin_root)
+            .await
+            .ok_or_else(|| "plugin not cached".to_string())?;
+        let url =
+            origin_url(&index, path).ok_or_else(|| format!("no origin for {}", index.plugin_id))?;
+
+        let key = local_path(plugin_root, path)?;
+        let cell = self
+            .in_flight
+            .lock()
+            .map_err(|e| e.to_string())?
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = cell
+            .get_or_init(|| async {
+                // A fetch that finished between our miss and joining its cell has stored the file.
+                if tokio::fs::metadata(&key).await.is_ok() {
+                    return Ok(());
+                }
+                let fetched = self.fetch_one(host, &url).await?;
+                // Held to the reserve like `cache_plugin`, now that the size is known.
+                let size = HashMap::from([(url.clone(), fetched.bytes.len() as u64)]);
+                if let Err(e) = self.check_fits(&[url.as_str()], &size) {
+                    self.check_storage().await;
+                    return Err(e.to_string());
+                }
+                let _guard = self.lock_plugin(host).await;
+                // Storing into an evicted plugin would leave a directory without an index.
+                if read_index(keys, plugin_root).await.is_none() {
+                    return Err("plugin evicted during fetch".to_string());
+                }
+                store_asset(keys, plugin_root, &url, fetched).await
+            })
+            .await
+            .clone();
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            // Only the cell we joined: a later miss (e.g. after an eviction) must fetch afresh.
+            if in_flight
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &cell))
+            {
+                in_flight.remove(&key);
+            }
+        }
+        result
+    }
+}
+
+/// Those of `urls` without both their file and its sidecar cached in `dir`.
+async fn missing_urls<'a>(dir: &Path, urls: &'a [String]) -> Result<Vec<&'a str>, String> {
+    let mut missing = Vec::new();
+    for url in urls {
+        let cached = tokio::fs::metadata(asset_path(dir, url)?).await.is_ok()
+            && tokio::fs::metadata(meta_path(dir, url)?).await.is_ok();
+        if !cached {
+            missing.push(url.as_str());
+        }
+    }
+    Ok(missing)
+}
+
+/// The `dxos-plugin://` URL `url` of `plugin_id` is served at once cached.
+fn cached_url(plugin_id: &str, url: &str) -> Result<String, String> {
+    Ok(format!(
+        "{}://{}/{}",
+        URI_SCHEME,
+        hash(plugin_id),
+        url_path(url)?
+    ))
+}
+
+/// Digest identifying a plugin's content: its URLs with their files' `sha256`, in URL order.
+fn content_digest(files: impl Iterator<Item = (String, String)>) -> String {
+    let mut files: Vec<_> = files.collect();
+    files.sort();
+    let listing: String = files
+        .iter()
+        .map(|(url, sha256)| format!("{} {}\n", sha256, url))
+        .collect();
+    digest(listing.as_bytes())
+}
+
+/// Answers a request for a dev plugin from its directory. Always revalidated: the file may be
+/// rebuilt at any moment.
+async fn serve_dev(
+    request: &http::Request<Vec<u8>>,
+    dir: &Path,
+    path: &str,
+) -> http::Response<Vec<u8>> {
+    let Some(file) = plain_path(dir, path) else {
+        return not_found();
+    };
+    let Ok(bytes) = tokio::fs::read(&file).await else {
+        return not_found();
+    };
+    let mime = detect_mime(path, &bytes);
+    let etag = format!("\"{}\"", digest(&bytes));
+    respond(request, bytes, &mime, &etag, "no-cache")
+}
+
+/// A hit for `bytes`: 304 when `If-None-Match` names `etag`, headers alone for `HEAD`, otherwise
+/// the full body.
+fn respond(
+    request: &http::Request<Vec<u8>>,
+    bytes: Vec<u8>,
+    mime: &str,
+    etag: &str,
+    cache_control: &str,
+) -> http::Response<Vec<u8>> {
+    let builder = http::Response::builder()
+        .header("etag", etag)
+        .header("cache-control", cache_control)
+        .header("access-control-allow-origin", "*")
+        .header("access-control-expose-headers", "etag");
+
+    let not_modified = request
+        .headers()
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, etag));
+    let response = if not_modified {
+        builder.status(304).body(Vec::new())
+    } else {
+        let builder = builder
+            .status(200)
+            .header("content-type", mime)
+            .header("content-length", bytes.len());
+        if request.method() == http::Method::HEAD {
+            builder.body(Vec::new())
+        } else {
+            builder.body(bytes)
+        }
+    };
+    response.unwrap_or_else(|_| not_found())
+}
+
+/// Rewrites every file under `root`, opened with `from` and sealed with `to`. A file that can't
+/// be opened is deleted rather than failing the whole pass: it is refetched like any other
+/// missing entry, whereas keeping it would pin a key we are trying to discard. Only plugin
+/// directories are walked: the root's `FORMAT_FILE` marker is never sealed.
+async fn reseal_all(root: &Path, from: &Keys, to: &Keys) -> Result<(), String> {
+    for dir in plugin_dirs(root).await? {
+        for path in files_under(&dir).await? {
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                continue;
+            }
+            let stored = tokio::fs::read(&path).await.map_err(|e| e.to_string())?;
+            match from.open(stored) {
+                Ok(plaintext) => write_atomic(&path, &to.seal(&plaintext)?).await?,
+                Err(e) => {
+                    log::warn!("[asset-cache] dropping {}: {}", path.display(), e);
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fetch::{FetchFuture, HttpFetcher, MemoryFetcher, SizeFuture};
+    use super::super::testing::Origin;
+    use super::*;
+    use tempfile::TempDir;
+
+    const PLUGIN: &str = "example-plugin";
+    const MANIFEST: &str = "https://plugins.example.com/p/manifest.json";
+    const CHUNK: &str = "https://plugins.example.com/p/chunks/index-1a2b3c4d.js";
+
+    /// A cache in a temporary directory that is removed with the returned guard, filled from
+    /// `fetcher`.
+    fn cache(fetcher: Arc<dyn Fetcher>) -> (AssetCache, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let cache = AssetCache::new(
+            dir.path().join("plugin-cache"),
+            dir.path().join("plugin-cache.key"),
+            fetcher,
+        );
+        (cache, dir)
+    }
+
+    fn origin() -> Arc<MemoryFetcher> {
+        let fetcher = Arc::new(MemoryFetcher::default());
+        fetcher.insert(MANIFEST, r#"{"name":"example"}"#);
+        fetcher.insert(CHUNK, "export {}");
+        fetcher
+    }
+
+    fn request(method: &str, path: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method(method)
+            .uri(format!("{}://{}/{}", URI_SCHEME, hash(PLUGIN), path))
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    async fn get(cache: &AssetCache, path: &str) -> http::Response<Vec<u8>> {
+        cache.serve(&request("GET", path)).await.0
+    }
+
+    fn header<'a>(response: &'a http::Response<Vec<u8>>, name: &str) -> &'a str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    /// Makes a cached entry look fetched long ago.
+    async fn backdate(cache: &AssetCache, url: &str) {
+        let keys = cache.ready().await.unwrap();
+        let dir = cache.plugin_dir(PLUGIN);
+        let meta = read_meta(&keys, &meta_path(&dir, url).unwrap())
+            .await
+            .unwrap();
+        write_meta(
+            &keys,
+            &dir,
+            &AssetMeta {
+                fetched_at: 0,
+                ..meta
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn caches_and_serves_plugins() {
+        let (cache, _dir) = cache(origin());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string(), CHUNK.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(cache.plugins().await.unwrap(), [PLUGIN]);
+        assert_eq!(
+            cache.resolve(PLUGIN, CHUNK).await.unwrap(),
+            Some(format!(
+                "{}://{}/p/chunks/index-1a2b3c4d.js",
+                URI_SCHEME,
+                hash(PLUGIN)
+            ))
+        );
+
+        let response = get(&cache, "p/chunks/index-1a2b3c4d.js").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"export {}");
+        assert_eq!(
+            header(&response, "content-type"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            header(&response, "cache-control"),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(get(&cache, "p/missing.js").await.status(), 404);
+
+        cache.evict(PLUGIN).await.unwrap();
+        assert!(cache.plugins().await.unwrap().is_empty());
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.status(),
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn refetches_only_incomplete_entries() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        let urls = vec![MANIFEST.to_string(), CHUNK.to_string()];
+        cache.cache_plugin(PLUGIN, urls.clone()).await.unwrap();
+        cache.cache_plugin(PLUGIN, urls.clone()).await.unwrap();
+        assert_eq!(fetcher.requests(), 2);
+
+        // A body without its sidecar is an interrupted write, not a cached entry.
+        std::fs::remove_file(meta_path(&cache.plugin_dir(PLUGIN), CHUNK).unwrap()).unwrap();
+        cache.cache_plugin(PLUGIN, urls).await.unwrap();
+        assert_eq!(fetcher.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn answers_like_a_static_file_server() {
+        let (cache, _dir) = cache(origin());
+        cache
+            .cache_plugin(PLUGIN, vec![CHUNK.to_string()])
+            .await
+            .unwrap();
+
+        let response = get(&cache, "p/chunks/index-1a2b3c4d.js").await;
+        let etag = header(&response, "etag").to_string();
+        let mut conditional = request("GET", "p/chunks/index-1a2b3c4d.js");
+        conditional
+            .headers_mut()
+            .insert(http::header::IF_NONE_MATCH, etag.parse().unwrap());
+        let (response, _) = cache.serve(&conditional).await;
+        assert_eq!(response.status(), 304);
+        assert!(response.body().is_empty());
+
+        let (response, _) = cache
+            .serve(&request("HEAD", "p/chunks/index-1a2b3c4d.js"))
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(header(&response, "content-length"), "9");
+        assert!(response.body().is_empty());
+
+        assert_eq!(
+            cache
+                .serve(&request("OPTIONS", "p/chunks/index-1a2b3c4d.js"))
+                .await
+                .0
+                .status(),
+            204
+        );
+        assert_eq!(
+            cache
+                .serve(&request("POST", "p/chunks/index-1a2b3c4d.js"))
+                .await
+                .0
+                .status(),
+            405
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_through_misses_once() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.status(),
+            404
+        );
+
+        cache.set_read_through(true);
+        let (first, second) = tokio::join!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js"),
+            get(&cache, "p/chunks/index-1a2b3c4d.js")
+        );
+        assert_eq!(
+            (first.status().as_u16(), second.status().as_u16()),
+            (200, 200)
+        );
+        assert_eq!(second.body(), b"export {}");
+        assert_eq!(fetcher.requests(), 2);
+
+        // What is read through counts against the reserve too.
+        fetcher.insert("https://plugins.example.com/p/logo.svg", "<svg/>");
+        cache.set_storage_reserve(u64::MAX);
+        assert_eq!(get(&cache, "p/logo.svg").await.status(), 404);
+        assert_eq!(fetcher.requests(), 3);
+        assert!(cache.low_storage());
+        cache.set_storage_reserve(0);
+        assert_eq!(get(&cache, "p/logo.svg").await.status(), 200);
+    }
+
+    /// Answers from `inner` once `gate` lets a request through, so a test can act while a fetch is
+    /// under way.
+    struct Gated {
+        inner: Arc<MemoryFetcher>,
+        gate: tokio::sync::Semaphore,
+    }
+
+    impl Fetcher for Gated {
+        fn fetch<'a>(&'a self, url: &'a str, cached: Option<&'a Validators>) -> FetchFuture<'a> {
+            Box::pin(async move {
+                self.gate
+                    .acquire()
+                    .await
+                    .map_err(|e| FetchError::transient(e.to_string()))?
+                    .forget();
+                self.inner.fetch(url, cached).await
+            })
+        }
+
+        fn size<'a>(&'a self, url: &'a str) -> SizeFuture<'a> {
+            self.inner.size(url)
+        }
+    }
+
+    #[tokio::test]
+    async fn read_through_stores_nothing_for_a_plugin_evicted_meanwhile() {
+        let fetcher = Arc::new(Gated {
+            inner: origin(),
+            gate: tokio::sync::Semaphore::new(1),
+        });
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        cache.set_read_through(true);
+
+        let evict = async {
+            cache.evict(PLUGIN).await.unwrap();
+            fetcher.gate.add_permits(1);
+        };
+        let (response, ()) = tokio::join!(get(&cache, "p/chunks/index-1a2b3c4d.js"), evict);
+        assert_eq!(response.status(), 404);
+        assert!(!cache.plugin_dir(PLUGIN).exists());
+    }
+
+    #[tokio::test]
+    async fn revalidation_stores_nothing_for_a_plugin_evicted_meanwhile() {
+        let fetcher = Arc::new(Gated {
+            inner: origin(),
+            gate: tokio::sync::Semaphore::new(1),
+        });
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        backdate(&cache, MANIFEST).await;
+        fetcher
+            .inner
+            .insert(MANIFEST, r#"{"name":"example","version":2}"#);
+        let stale = cache
+            .serve(&request("GET", "p/manifest.json"))
+            .await
+            .1
+            .unwrap();
+
+        let evict = async {
+            cache.evict(PLUGIN).await.unwrap();
+            fetcher.gate.add_permits(1);
+        };
+        let (updated, ()) = tokio::join!(cache.revalidate(stale), evict);
+        assert!(updated.is_none());
+        assert!(!cache.plugin_dir(PLUGIN).exists());
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_entries() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        assert!(cache
+            .serve(&request("GET", "p/manifest.json"))
+            .await
+            .1
+            .is_none());
+
+        // Unchanged at the origin: nothing to report, and the TTL restarts.
+        backdate(&cache, MANIFEST).await;
+        let stale = cache
+            .serve(&request("GET", "p/manifest.json"))
+            .await
+            .1
+            .unwrap();
+        assert!(cache.revalidate(stale).await.is_none());
+        assert!(cache
+            .serve(&request("GET", "p/manifest.json"))
+            .await
+            .1
+            .is_none());
+
+        // Changed: served stale once, then updated.
+        fetcher.insert(MANIFEST, r#"{"name":"example","version":2}"#);
+        backdate(&cache, MANIFEST).await;
+        let (response, stale) = cache.serve(&request("GET", "p/manifest.json")).await;
+        assert_eq!(response.body(), br#"{"name":"example"}"#);
+        let updated = cache.revalidate(stale.unwrap()).await.unwrap();
+        assert_eq!(
+            (updated.plugin_id.as_str(), updated.url.as_str()),
+            (PLUGIN, MANIFEST)
+        );
+        assert_eq!(
+            get(&cache, "p/manifest.json").await.body(),
+            br#"{"name":"example","version":2}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_pinned_plugins_and_collects_garbage() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string(), CHUNK.to_string()])
+            .await
+            .unwrap();
+        cache.set_pinned(PLUGIN, true).await.unwrap();
+        let chunk = asset_path(&cache.plugin_dir(PLUGIN), CHUNK).unwrap();
+        std::fs::remove_file(&chunk).unwrap();
+
+        fetcher.remove(CHUNK);
+        assert_eq!(cache.restore_pinned().await.unwrap(), 1);
+        fetcher.insert(CHUNK, "export {}");
+        let abandoned = cache.root.join(hash("abandoned"));
+        std::fs::create_dir_all(&abandoned).unwrap();
+        cache.startup().await;
+        assert!(chunk.exists());
+        assert!(!abandoned.exists());
+
+        cache.set_pinned(PLUGIN, false).await.unwrap();
+        std::fs::remove_file(&chunk).unwrap();
+        assert_eq!(cache.restore_pinned().await.unwrap(), 0);
+        assert!(!chunk.exists());
+
+        // A file the origin no longer has isn't worth retrying.
+        cache.set_pinned(PLUGIN, true).await.unwrap();
+        fetcher.refuse(CHUNK);
+        assert_eq!(cache.restore_pinned().await.unwrap(), 0);
+        assert!(!chunk.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_plugins_that_would_not_fit() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        let urls = vec![MANIFEST.to_string(), CHUNK.to_string()];
+        let huge = HashMap::from([(CHUNK.to_string(), u64::MAX / 2)]);
+        let error = cache
+            .cache_plugin_with(PLUGIN, urls.clone(), &huge, &Delta::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, CacheError::InsufficientStorage { needed, .. } if needed > u64::MAX / 2)
+        );
+        assert_eq!(fetcher.requests(), 0);
+        assert!(cache.plugins().await.unwrap().is_empty());
+
+        // Sizes not declared are asked of the origin; the reserve counts against what's free.
+        cache.set_storage_reserve(u64::MAX);
+        let error = cache.cache_plugin(PLUGIN, urls.clone()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            CacheError::InsufficientStorage { available: 0, .. }
+        ));
+        assert_eq!(fetcher.requests(), 0);
+
+        cache.set_storage_reserve(0);
+        cache.cache_plugin(PLUGIN, urls).await.unwrap();
+        assert_eq!(cache.plugins().await.unwrap(), [PLUGIN]);
+    }
+
+    #[tokio::test]
+    async fn low_storage_evicts_unpinned_plugins_and_pauses_fetches() {
+        let fetcher = origin();
+        let (cache, dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        cache.set_pinned(PLUGIN, true).await.unwrap();
+        cache
+            .cache_plugin("other-plugin", vec![CHUNK.to_string()])
+            .await
+            .unwrap();
+        assert!(!cache.check_storage().await);
+
+        // No amount of eviction frees this much, so nothing is evicted for it.
+        cache.set_storage_reserve(u64::MAX);
+        assert!(cache.check_storage().await);
+        assert!(cache.low_storage());
+        assert_eq!(cache.plugins().await.unwrap().len(), 2);
+
+        // A byte short: unpinned plugins go, pinned ones stay.
+        cache.set_storage_reserve(available_space(dir.path()).unwrap() + 1);
+        cache.check_storage().await;
+        assert_eq!(cache.plugins().await.unwrap(), [PLUGIN]);
+        cache.set_storage_reserve(u64::MAX);
+
+        backdate(&cache, MANIFEST).await;
+        let stale = cache
+            .serve(&request("GET", "p/manifest.json"))
+            .await
+            .1
+            .unwrap();
+        let requests = fetcher.requests();
+        assert!(cache.revalidate(stale).await.is_none());
+        assert_eq!(fetcher.requests(), requests);
+
+        cache.set_storage_reserve(0);
+        assert!(!cache.check_storage().await);
+        assert!(!cache.low_storage());
+    }
+
+    #[tokio::test]
+    async fn counts_hits_misses_and_fetches() {
+        let fetcher = origin();
+        let (cache, _dir) = cache(fetcher.clone());
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string()])
+            .await
+            .unwrap();
+        fetcher.remove(CHUNK);
+        cache
+            .cache_plugin("other-plugin", vec![CHUNK.to_string()])
+            .await
+            .unwrap_err();
+
+        assert_eq!(get(&cache, "p/manifest.json").await.status(), 200);
+        assert_eq!(
+            cache
+                .serve(&request("HEAD", "p/manifest.json"))
+                .await
+                .0
+                .status(),
+            200
+        );
+        assert_eq!(get(&cache, "p/missing.js").await.status(), 404);
+        for host in ["..", "."] {
+            let uri = format!("{}://{}/secret", URI_SCHEME, host);
+            let outside = http::Request::builder().uri(uri).body(Vec::new()).unwrap();
+            assert_eq!(cache.serve(&outside).await.0.status(), 404);
+        }
+
+        let stats = cache.stats().await;
+        let counters = &stats.plugins[PLUGIN];
+        assert_eq!(
+            (counters.hits, counters.misses, counters.fetches),
+            (2, 1, 1)
+        );
+        assert_eq!(counters.bytes_served, r#"{"name":"example"}"#.len() as u64);
+        assert_eq!(counters.not_found[&NotFound::NotCached], 1);
+        // Never cached, so known by its host alone.
+        assert_eq!(stats.plugins[&hash("other-plugin")].fetch_failures, 1);
+        assert_eq!(stats.plugins[BAD_HOSTS].not_found[&NotFound::BadHost], 2);
+        assert!(!stats.plugins.contains_key(".."));
+        assert_eq!(stats.total.fetches, 2);
+
+        cache.reset_stats();
+        assert!(cache.stats().await.plugins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_from_the_previous_version() {
+        use super::super::delta::{make_patch, Patch};
+
+        let v1 = (
+            "https://plugins.example.com/v1/index.js",
+            "https://plugins.example.com/v1/lib-1a2b3c4d.js",
+        );
+        let v2 = (
+            "https://plugins.example.com/v2/index.js",
+            "https://plugins.example.com/v2/lib-1a2b3c4d.js",
+        );
+        let patch_url = "https://plugins.example.com/v2/index.js.patch";
+        let lib = "export const lib = true;\n".repeat(100);
+        let old = "export const a = 1;\n".repeat(200);
+        let new = format!("{}export const b = 2;\n", old);
+        let fetcher = Arc::new(MemoryFetcher::default());
+        for (url, body) in [(v1.0, &old), (v1.1, &lib), (v2.0, &new), (v2.1, &lib)] {
+            fetcher.insert(url, body.as_str());
+        }
+        fetcher.insert(patch_url, make_patch(old.as_bytes(), new.as_bytes()));
+        let (cache, _dir) = cache(fetcher.clone());
+        let report = cache
+            .cache_plugin(PLUGIN, vec![v1.0.to_string(), v1.1.to_string()])
+            .await
+            .unwrap();
+        assert_eq!((report.fetched, report.bytes_saved), (2, 0));
+
+        let delta = Delta {
+            digests: HashMap::from([
+                (v2.0.to_string(), digest(new.as_bytes())),
+                (v2.1.to_string(), digest(lib.as_bytes())),
+            ]),
+            previous: HashMap::from([
+                (v1.0.to_string(), digest(old.as_bytes())),
+                (v1.1.to_string(), digest(lib.as_bytes())),
+            ]),
+            patches: HashMap::from([(
+                v2.0.to_string(),
+                Patch {
+                    from: v1.0.to_string(),
+                    url: patch_url.to_string(),
+                },
+            )]),
+        };
+        let urls = vec![v2.0.to_string(), v2.1.to_string()];
+        let requests = fetcher.requests();
+        let report = cache
+            .cache_plugin_with(PLUGIN, urls.clone(), &HashMap::new(), &delta)
+            .await
+            .unwrap();
+        assert_eq!((report.fetched, report.copied, report.patched), (0, 1, 1));
+        assert_eq!(fetcher.requests(), requests + 1);
+        assert!(report.bytes_saved > (lib.len() + old.len() / 2) as u64);
+        assert_eq!(get(&cache, "v2/index.js").await.body(), new.as_bytes());
+        assert_eq!(
+            get(&cache, "v2/lib-1a2b3c4d.js").await.body(),
+            lib.as_bytes()
+        );
+        assert_eq!(
+            header(&get(&cache, "v2/index.js").await, "content-type"),
+            "text/javascript; charset=utf-8"
+        );
+
+        // A patch that doesn't produce the declared digest, or a source that changed on disk,
+        // falls back to fetching in full.
+        cache.evict(PLUGIN).await.unwrap();
+        cache
+            .cache_plugin(PLUGIN, vec![v1.0.to_string(), v1.1.to_string()])
+            .await
+            .unwrap();
+        fetcher.insert(patch_url, make_patch(lib.as_bytes(), new.as_bytes()));
+        std::fs::write(
+            asset_path(&cache.plugin_dir(PLUGIN), v1.1).unwrap(),
+            "tampered",
+        )
+        .unwrap();
+        let report = cache
+            .cache_plugin_with(PLUGIN, urls, &HashMap::new(), &delta)
+            .await
+            .unwrap();
+        assert_eq!((report.fetched, report.copied, report.patched), (2, 0, 0));
+        assert_eq!(get(&cache, "v2/index.js").await.body(), new.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn encrypts_at_rest() {
+        let (cache, _dir) = cache(origin());
+        cache
+            .cache_plugin(PLUGIN, vec![CHUNK.to_string()])
+            .await
+            .unwrap();
+        let chunk = asset_path(&cache.plugin_dir(PLUGIN), CHUNK).unwrap();
+
+        cache.set_encryption(true).await.unwrap();
+        assert_ne!(std::fs::read(&chunk).unwrap(), b"export {}");
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.body(),
+            b"export {}"
+        );
+
+        // Plaintext planted in an encrypted cache isn't served.
+        let sealed = std::fs::read(&chunk).unwrap();
+        std::fs::write(&chunk, "export const planted = true;").unwrap();
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.status(),
+            404
+        );
+        std::fs::write(&chunk, sealed).unwrap();
+        cache.rotate_key().await.unwrap();
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.body(),
+            b"export {}"
+        );
+
+        cache.set_encryption(false).await.unwrap();
+        assert_eq!(std::fs::read(&chunk).unwrap(), b"export {}");
+        assert!(cache.rotate_key().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn resolves_in_batches_and_as_an_import_map() {
+        let fetcher = origin();
+        let other_chunk = "https://other.example.com/index.js";
+        fetcher.insert(other_chunk, "export {}");
+        let (cache, _dir) = cache(fetcher);
+        cache
+            .cache_plugin(PLUGIN, vec![MANIFEST.to_string(), CHUNK.to_string()])
+            .await
+            .unwrap();
+        cache
+            .cache_plugin("other-plugin", vec![other_chunk.to_string()])
+            .await
+            .unwrap();
+
+        let missing = "https://plugins.example.com/p/chunks/missing.js";
+        let urls = [CHUNK, missing, MANIFEST].map(str::to_string);
+        let resolved = cache.resolve_many(PLUGIN, &urls).await.unwrap();
+        for (url, resolved) in urls.iter().zip(&resolved) {
+            assert_eq!(&cache.resolve(PLUGIN, url).await.unwrap(), resolved);
+        }
+        assert!(resolved[0].is_some() && resolved[1].is_none());
+
+        // A listed file gone missing is left to load from its origin.
+        std::fs::remove_file(asset_path(&cache.plugin_dir(PLUGIN), MANIFEST).unwrap()).unwrap();
+        let map = cache.import_map(Some(PLUGIN)).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&map).unwrap(),
+            serde_json::json!({ "imports": { CHUNK: resolved[0].clone().unwrap() } })
+        );
+        let all = cache.import_map(None).await.unwrap();
+        assert_eq!(all.imports.keys().collect::<Vec<_>>(), [other_chunk, CHUNK]);
+        assert!(cache.import_map(Some("not-cached")).await.is_err());
+    }
+
+    /// Writes a bundle of the test plugin as `plugin-cache export` would, listing `urls`.
+    fn bundle(dir: &Path, body: &str, urls: &[&str]) {
+        for url in urls {
+            let path = asset_path(dir, url).unwrap();
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, body).unwrap();
+            let meta = AssetMeta {
+                url: url.to_string(),
+                mime: "text/javascript; charset=utf-8".to_string(),
+                fetched_at: 1000,
+                sha256: Some(digest(body.as_bytes())),
+                etag: None,
+                last_modified: None,
+            };
+            std::fs::write(
+                meta_path(dir, url).unwrap(),
+                serde_json::to_vec(&meta).unwrap(),
+            )
+            .unwrap();
+        }
+        let index = Index {
+            plugin_id: PLUGIN.to_string(),
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            revalidate: Vec::new(),
+            pinned: false,
+            format: FORMAT_VERSION,
+            seed_digest: None,
+        };
+        std::fs::write(dir.join(INDEX_FILE), serde_json::to_vec(&index).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeds_bundled_plugins_without_rolling_back() {
+        let fetcher = origin();
+        let (cache, dir) = cache(fetcher.clone());
+        let (seeds, both) = (dir.path().join("seeds"), [MANIFEST, CHUNK]);
+        let chunk = asset_path(&cache.plugin_dir(PLUGIN), CHUNK).unwrap();
+        assert!(cache.seed(&seeds).await.unwrap().is_empty());
+
+        // First launch, offline: the bundle is all there is.
+        bundle(&seeds.join("example"), "bundled v1", &both);
+        std::fs::write(seeds.join("README.md"), "not a bundle").unwrap();
+        assert_eq!(cache.seed(&seeds).await.unwrap(), [PLUGIN]);
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.body(),
+            b"bundled v1"
+        );
+        assert!(cache.seed(&seeds).await.unwrap().is_empty());
+        assert_eq!(fetcher.requests(), 0);
+        cache.set_pinned(PLUGIN, true).await.unwrap();
+
+        // An app update's bundle replaces the copy seeded from the last one, however recently that
+        // was revalidated, keeping the user's pin and dropping files the new bundle doesn't list.
+        let keys = cache.ready().await.unwrap();
+        let mut meta = read_meta(&keys, &meta_path(&cache.plugin_dir(PLUGIN), CHUNK).unwrap())
+            .await
+            .unwrap();
+        meta.fetched_at = now_secs() + 60;
+        write_meta(&keys, &cache.plugin_dir(PLUGIN), &meta)
+            .await
+            .unwrap();
+        bundle(&seeds.join("example"), "bundled v2", &[MANIFEST]);
+        assert_eq!(cache.seed(&seeds).await.unwrap(), [PLUGIN]);
+        assert_eq!(get(&cache, "p/manifest.json").await.body(), b"bundled v2");
+        assert!(!chunk.exists());
+        assert!(
+            read_index(&keys, &cache.plugin_dir(PLUGIN))
+                .await
+                .unwrap()
+                .pinned
+        );
+
+        // Fetched from the origin since: a bundle, even one not seen before, leaves that alone.
+        cache
+            .cache_plugin(PLUGIN, both.map(str::to_string).to_vec())
+            .await
+            .unwrap();
+        bundle(&seeds.join("example"), "bundled v3", &both);
+        assert!(cache.seed(&seeds).await.unwrap().is_empty());
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.body(),
+            b"export {}"
+        );
+
+        // An incomplete bundle is refused whole.
+        cache.evict(PLUGIN).await.unwrap();
+        bundle(&seeds.join("example"), "bundled v4", &both);
+        std::fs::remove_file(asset_path(&seeds.join("example"), MANIFEST).unwrap()).unwrap();
+        assert!(cache.seed(&seeds).await.unwrap().is_empty());
+        assert!(!chunk.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_apart_paths_filesystems_would_merge() {
+        let fetcher = Arc::new(MemoryFetcher::default());
+        let upper = "https://plugins.example.com/p/Chunk.js";
+        let lower = "https://plugins.example.com/p/chunk.js";
+        let font = "https://plugins.example.com/p/My%20Font.woff2";
+        let device = "https://plugins.example.com/p/con.js";
+        for url in [upper, lower, font, device] {
+            fetcher.insert(url, url);
+        }
+        let (cache, _dir) = cache(fetcher.clone());
+        let urls = [upper, lower, font, device].map(str::to_string).to_vec();
+        cache.cache_plugin(PLUGIN, urls).await.unwrap();
+
+        assert_eq!(get(&cache, "p/Chunk.js").await.body(), upper.as_bytes());
+        assert_eq!(get(&cache, "p/chunk.js").await.body(), lower.as_bytes());
+        assert_eq!(get(&cache, "p/con.js").await.body(), device.as_bytes());
+        // However the webview spells the path, it is the file cached for the origin's spelling.
+        assert_eq!(
+            get(&cache, "p/My%20Fon%74.woff2").await.body(),
+            font.as_bytes()
+        );
+
+        // A sidecar recording another path is a collision: neither served nor overwritten.
+        let plugin_dir = cache.plugin_dir(PLUGIN);
+        std::fs::copy(
+            meta_path(&plugin_dir, lower).unwrap(),
+            meta_path(&plugin_dir, upper).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(get(&cache, "p/Chunk.js").await.status(), 404);
+        let keys = cache.ready().await.unwrap();
+        let fetched = cache.fetch_one(&hash(PLUGIN), upper).await.unwrap();
+        assert!(store_asset(&keys, &plugin_dir, upper, fetched)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_dev_sources_in_place_of_the_cache() {
+        let (cache, dir) = cache(origin());
+        cache
+            .cache_plugin(PLUGIN, vec![CHUNK.to_string()])
+            .await
+            .unwrap();
+        let build = dir.path().join("build");
+        std::fs::create_dir_all(build.join("p")).unwrap();
+        std::fs::write(build.join("p/index.js"), "export const dev = true;").unwrap();
+
+        cache.set_dev_source(PLUGIN, Some(build.clone())).unwrap();
+        let (response, stale) = cache.serve(&request("GET", "p/index.js")).await;
+        assert!(stale.is_none());
+        assert_eq!(response.body(), b"export const dev = true;");
+        assert_eq!(header(&response, "cache-control"), "no-cache");
+        assert_eq!(
+            header(&response, "content-type"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.status(),
+            404
+        );
+
+        // Rebuilt output is served on the next load.
+        std::fs::write(build.join("p/index.js"), "export const dev = 2;").unwrap();
+        assert_eq!(
+            get(&cache, "p/index.js").await.body(),
+            b"export const dev = 2;"
+        );
+
+        cache.set_dev_source(PLUGIN, None).unwrap();
+        assert_eq!(get(&cache, "p/index.js").await.status(), 404);
+        assert_eq!(
+            get(&cache, "p/chunks/index-1a2b3c4d.js").await.status(),
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn caches_over_http() {
+        let origin = Origin::start();
+        origin.set(
+            "/p/manifest.json",
+            "application/json",
+            br#"{"name":"example"}"#,
+        );
+        let (cache, _dir) = cache(Arc::new(HttpFetcher::default()));
+        cache
+            .cache_plugin(PLUGIN, vec![origin.url("/p/manifest.json")])
+            .await
+            .unwrap();
+
+        let response = get(&cache, "p/manifest.json").await;
+        assert_eq!(
+            header(&response, "content-type"),
+            "application/json; charset=utf-8"
+        );
+
+        // Revalidation replays the origin's validators: unchanged is a 304, changed is new content.
+        let url = origin.url("/p/manifest.json");
+        backdate(&cache, &url).await;
+        assert!(cache
+            .revalidate(
+                cache
+                    .serve(&request("GET", "p/manifest.json"))
+                    .await
+                    .1
+                    .unwrap()
+            )
+            .await
+            .is_none());
+        origin.set(
+            "/p/manifest.json",
+            "application/json",
+            br#"{"name":"renamed"}"#,
+        );
+        backdate(&cache, &url).await;
+        assert!(cache
+            .revalidate(
+                cache
+                    .serve(&request("GET", "p/manifest.json"))
+                    .await
+                    .1
+                    .unwrap()
+            )
+            .await
+            .is_some());
+        assert_eq!(
+            get(&cache, "p/manifest.json").await.body(),
+            br#"{"name":"renamed"}"#
+        );
+    }
+}
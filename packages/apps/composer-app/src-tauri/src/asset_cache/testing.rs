@@ -0,0 +1,118 @@
+//! Stand-ins shared by the cache's tests.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use tempfile::TempDir;
+
+use super::layout::digest;
+
+/// A stand-in HTTP origin on a loopback port, for tests: serves the files it is given with a digest
+/// `ETag`, answers a matching `If-None-Match` with 304 and anything else with 404.
+pub struct Origin {
+    base: String,
+    files: Arc<std::sync::Mutex<OriginFiles>>,
+}
+
+/// Path -> (content type, body).
+type OriginFiles = std::collections::HashMap<String, (String, Vec<u8>)>;
+
+impl Origin {
+    pub fn start() -> Self {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let files: Arc<std::sync::Mutex<OriginFiles>> = Arc::default();
+        let served = Arc::clone(&files);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                let _ = reader.read_line(&mut request_line);
+                let head = request_line.starts_with("HEAD ");
+                let path = request_line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or("/")
+                    .to_string();
+                let mut if_none_match = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("if-none-match") {
+                            if_none_match = Some(value.trim().to_string());
+                        }
+                    }
+                }
+                let file = served.lock().unwrap().get(&path).cloned();
+                let response = match file {
+                    Some((mime, body)) => {
+                        let etag = format!("\"{}\"", digest(&body));
+                        if if_none_match.as_deref() == Some(etag.as_str()) {
+                            format!("HTTP/1.1 304 Not Modified\r\netag: {}\r\nconnection: close\r\n\r\n", etag)
+                                .into_bytes()
+                        } else {
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\ncontent-type: {}\r\netag: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                                mime,
+                                etag,
+                                body.len()
+                            )
+                            .into_bytes();
+                            if !head {
+                                response.extend(body);
+                            }
+                            response
+                        }
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        Self { base, files }
+    }
+
+    /// Serves `body` at `path` from now on.
+    pub fn set(&self, path: &str, mime: &str, body: &[u8]) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), (mime.to_string(), body.to_vec()));
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+}
+
+/// Copies `fixtures/<name>` into a fresh temporary cache root, removed when the guard drops.
+pub fn fixture(name: &str) -> TempDir {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/asset_cache/fixtures")
+        .join(name);
+    let root = TempDir::new().unwrap();
+    let mut pending = vec![(source, root.path().to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        std::fs::create_dir_all(&to).unwrap();
+        for entry in std::fs::read_dir(&from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                pending.push((entry.path(), to.join(entry.file_name())));
+            } else {
+                std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+    root
+}
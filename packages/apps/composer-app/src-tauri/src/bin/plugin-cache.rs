@@ -0,0 +1,172 @@
+//! Inspects and repairs the plugin asset cache of an installed Composer, outside the app.
+//!
+//! Operates on the app's data directory (e.g. `~/Library/Application Support/<identifier>` on
+//! macOS) through `app_lib::asset_cache::admin`, the same code the app itself runs. Quit the app
+//! before changing its cache.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use app_lib::asset_cache::admin::PluginCache;
+use serde::Serialize;
+
+const USAGE: &str = "\
+Usage: plugin-cache --data-dir <dir> [--config-dir <dir>] [--json] <command>
+
+Commands:
+  list                    List cached plugins
+  show <plugin>           List a plugin's cached files
+  verify [<plugin>]       Check a plugin (default: all) for missing, corrupt or unreadable files
+  evict <plugin>          Remove a plugin from the cache
+  export <plugin> <dir>   Write a plaintext copy of a plugin to <dir>
+  import <dir>            Import a plugin written by `export`, replacing any cached copy
+  migrate                 Upgrade the cache to this build's format, as the app does at launch
+
+<plugin> is a plugin id or its hashed directory name. --config-dir holds the encryption key file
+and defaults to --data-dir, which is where both live on macOS. A cache in an older format can be
+inspected as is, but `evict` and `import` need it migrated first.";
+
+struct Args {
+    data_dir: PathBuf,
+    config_dir: PathBuf,
+    json: bool,
+    command: Vec<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let (mut data_dir, mut config_dir, mut json, mut command) = (None, None, false, Vec::new());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => {
+                data_dir = Some(PathBuf::from(
+                    args.next().ok_or("--data-dir needs a value")?,
+                ))
+            }
+            "--config-dir" => {
+                config_dir = Some(PathBuf::from(
+                    args.next().ok_or("--config-dir needs a value")?,
+                ))
+            }
+            "--json" => json = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => command.push(arg),
+        }
+    }
+    let data_dir = data_dir.ok_or("--data-dir is required")?;
+    let config_dir = config_dir.unwrap_or_else(|| data_dir.clone());
+    Ok(Args {
+        data_dir,
+        config_dir,
+        json,
+        command,
+    })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("error: {}\n", e);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match runtime.block_on(run(args)) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<ExitCode, String> {
+    let mut cache = PluginCache::open(&args.data_dir, &args.config_dir)?;
+    let command: Vec<&str> = args.command.iter().map(String::as_str).collect();
+    if cache.outdated() && command != ["migrate"] {
+        eprintln!("warning: the cache is in an older format; run `migrate` to upgrade it");
+    }
+    match command.as_slice() {
+        ["list"] => {
+            let plugins = cache.plugins().await?;
+            if args.json {
+                return print_json(&plugins);
+            }
+            for plugin in plugins {
+                let pinned = if plugin.pinned { "  pinned" } else { "" };
+                println!(
+                    "{}  {}  {} file(s), {} bytes{}",
+                    plugin.dir, plugin.plugin_id, plugin.files, plugin.bytes, pinned
+                );
+            }
+        }
+        ["show", plugin] => {
+            let assets = cache.assets(plugin).await?;
+            if args.json {
+                return print_json(&assets);
+            }
+            for asset in assets {
+                if !asset.cached {
+                    println!("{}  (missing)", asset.path);
+                    continue;
+                }
+                println!(
+                    "{}  {}  {} bytes  fetched {}",
+                    asset.path,
+                    asset.mime.unwrap_or_default(),
+                    asset.bytes.unwrap_or_default(),
+                    asset.fetched_at.unwrap_or_default()
+                );
+            }
+        }
+        ["verify"] | ["verify", _] => {
+            let problems = cache.verify(command.get(1).copied()).await?;
+            if args.json {
+                print_json(&problems)?;
+            } else {
+                for problem in &problems {
+                    println!("{}: {}: {}", problem.plugin, problem.path, problem.issue);
+                }
+            }
+            if !problems.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        ["evict", plugin] => println!("evicted {}", cache.evict(plugin).await?),
+        ["export", plugin, dest] => {
+            println!("exported {}", cache.export(plugin, dest.as_ref()).await?)
+        }
+        ["import", src] => println!("imported {}", cache.import(src.as_ref()).await?),
+        ["migrate"] => {
+            cache.migrate()?;
+            println!("migrated");
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(ExitCode::from(2));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<ExitCode, String> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).map_err(|e| e.to_string())?
+    );
+    Ok(ExitCode::SUCCESS)
+}
@@ -2,11 +2,14 @@
 
 #[cfg(target_os = "ios")]
 mod audio_input;
-mod asset_cache;
+pub mod asset_cache;
 pub mod channel;
+mod sealing;
 #[cfg(desktop)]
 mod oauth;
 #[cfg(desktop)]
+mod vault;
+#[cfg(desktop)]
 mod window_state;
 #[cfg(all(desktop, unix))]
 mod xattr_cmd;
@@ -69,7 +72,7 @@ pub fn run() {
         .register_asynchronous_uri_scheme_protocol(asset_cache::URI_SCHEME, |ctx, request, responder| {
             let app = ctx.app_handle().clone();
             tauri::async_runtime::spawn(async move {
-                let response = asset_cache::handle_uri(&app, &request);
+                let response = asset_cache::handle_uri(&app, &request).await;
                 responder.respond(response);
             });
         });
@@ -150,12 +153,37 @@ pub fn run() {
         asset_cache::cache_plugin_assets,
         asset_cache::evict_plugin,
         asset_cache::resolve_cached_url,
+        asset_cache::resolve_cached_urls,
+        asset_cache::plugin_import_map,
         asset_cache::list_cached_plugins,
+        asset_cache::plugin_cache_stats,
+        asset_cache::reset_plugin_cache_stats,
+        asset_cache::set_plugin_cache_read_through,
+        asset_cache::set_plugin_revalidation,
+        asset_cache::set_plugin_cache_encryption,
+        asset_cache::rotate_plugin_cache_key,
+        asset_cache::pin_plugin,
+        asset_cache::unpin_plugin,
+        #[cfg(debug_assertions)]
+        asset_cache::register_dev_plugin,
+        #[cfg(debug_assertions)]
+        asset_cache::unregister_dev_plugin,
         oauth::start_oauth_server,
         oauth::stop_oauth_server,
-        oauth::get_oauth_result,
+        oauth::take_oauth_result,
+        oauth::wait_for_oauth_result,
+        oauth::cancel_oauth_wait,
         oauth::get_oauth_recovery_result,
+        oauth::wait_for_oauth_recovery_result,
+        oauth::cancel_oauth_recovery_wait,
         oauth::initiate_oauth_flow,
+        oauth::start_native_oauth_flow,
+        oauth::refresh_oauth_token,
+        vault::store_credential,
+        vault::get_credential,
+        vault::list_credentials,
+        vault::delete_credential,
+        vault::credential_vault_backend,
         #[cfg(unix)]
         xattr_cmd::get_xattr,
         #[cfg(unix)]
@@ -171,7 +199,21 @@ pub fn run() {
         asset_cache::cache_plugin_assets,
         asset_cache::evict_plugin,
         asset_cache::resolve_cached_url,
+        asset_cache::resolve_cached_urls,
+        asset_cache::plugin_import_map,
         asset_cache::list_cached_plugins,
+        asset_cache::plugin_cache_stats,
+        asset_cache::reset_plugin_cache_stats,
+        asset_cache::set_plugin_cache_read_through,
+        asset_cache::set_plugin_revalidation,
+        asset_cache::set_plugin_cache_encryption,
+        asset_cache::rotate_plugin_cache_key,
+        asset_cache::pin_plugin,
+        asset_cache::unpin_plugin,
+        #[cfg(debug_assertions)]
+        asset_cache::register_dev_plugin,
+        #[cfg(debug_assertions)]
+        asset_cache::unregister_dev_plugin,
         #[cfg(target_os = "ios")]
         audio_input::list_audio_inputs,
         #[cfg(target_os = "ios")]
@@ -183,7 +225,9 @@ pub fn run() {
     ]);
 
     #[cfg(desktop)]
-    let builder = builder.manage(OAuthServerState::new());
+    let builder = builder
+        .manage(OAuthServerState::new())
+        .manage(vault::VaultState::default());
 
     builder
         .setup(move |app| {
@@ -196,6 +240,24 @@ pub fn run() {
                 )?;
             }
 
+            // The dev-plugin commands read any local directory and replace a plugin's code, so
+            // only debug builds register them, and only debug builds grant them here rather than
+            // in a static capability file.
+            #[cfg(debug_assertions)]
+            app.add_capability(
+                tauri::ipc::CapabilityBuilder::new("dev-plugins")
+                    .window("main")
+                    .permission("allow-register-dev-plugin")
+                    .permission("allow-unregister-dev-plugin"),
+            )?;
+
+            // Tidy the plugin cache and restore pinned plugins' missing files in the background.
+            tauri::async_runtime::spawn(asset_cache::startup(app.handle().clone()));
+
+            // Renew integration tokens in the vault before they expire.
+            #[cfg(desktop)]
+            oauth::start_token_refresh(app.handle());
+
             // Desktop: create window pointing at localhost plugin (production) or Vite dev server (dev).
             // SharedWorker requires HTTP origin, so desktop uses External URL.
             #[cfg(desktop)]
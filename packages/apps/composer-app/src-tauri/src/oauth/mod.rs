@@ -1,21 +1,35 @@
 //! OAuth module for handling OAuth flows via a local HTTP server.
 
+mod native;
+mod pkce;
+mod refresh;
 mod server;
+mod wait;
 
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ORIGIN};
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, State};
+use tauri::{AppHandle, Manager, State};
+use tauri_plugin_opener::OpenerExt;
 use tokio::sync::Mutex;
+use zeroize::Zeroize;
 
+use crate::vault::Renewal;
+use native::{NativeOAuthRequest, NATIVE_REDIRECT_PATH};
+use refresh::Refresher;
 use server::OAuthServer;
 
 /// Event carrying a callback URL the loopback server received, as an absolute URL string.
 pub const OAUTH_CALLBACK_EVENT: &str = "dxos:oauth-callback";
 
+/// How long `wait_for_oauth_result` waits unless told otherwise: as long as a sign-in plausibly
+/// takes, consent screens and second factors included.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 /// OAuth result returned from the callback.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OAuthResult {
     pub success: bool,
@@ -23,12 +37,32 @@ pub struct OAuthResult {
     pub access_token: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reason: Option<String>,
+    /// Only from native flows (`start_native_oauth_flow`): Edge keeps refresh tokens to itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// Lifetime of `access_token` in seconds, when the provider says.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+    /// Whether the tokens went to the credential vault (`storeInVault`), leaving `access_token`
+    /// empty here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stored: bool,
+}
+
+/// Wipes the tokens, so they don't linger in freed memory once a result is taken, has expired, or
+/// the server has stopped. Clones handed to the webview are wiped too, after serialization.
+impl Drop for OAuthResult {
+    fn drop(&mut self) {
+        self.access_token.zeroize();
+        self.refresh_token.zeroize();
+    }
 }
 
 /// Params an OAuth-recovery callback carries back (the `register` and `recovery` purposes).
 ///
 /// Kept apart from `OAuthResult`: recovery never yields an access token, and the `recovery` purpose
-/// carries no `accessTokenId` either, so there is nothing to key a per-token lookup on.
+/// carries no `accessTokenId` either, so results are looked up by the flow id `initiate_oauth_flow`
+/// returns instead.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct OAuthRecoveryResult {
@@ -42,6 +76,14 @@ pub struct OAuthRecoveryResult {
     pub error: Option<String>,
 }
 
+/// Wipes the one-time secrets, like `OAuthResult`'s tokens.
+impl Drop for OAuthRecoveryResult {
+    fn drop(&mut self) {
+        self.registration_token.zeroize();
+        self.recovery_proof.zeroize();
+    }
+}
+
 /// Request body for initiating OAuth flow.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -81,36 +123,113 @@ struct InitiateOAuthResponse {
     auth_url: String,
 }
 
+/// A flow `initiate_oauth_flow` started.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitiatedOAuthFlow {
+    /// Where the relay page sends the browser.
+    pub auth_url: String,
+    /// Id the flow's recovery result is filed under (`get_oauth_recovery_result`).
+    pub flow_id: String,
+}
+
 /// State wrapper for the OAuth server.
 pub struct OAuthServerState {
     server: Arc<Mutex<Option<OAuthServer>>>,
+    /// Renews the tokens flows left in the vault (see `refresh`).
+    refresher: Arc<Refresher>,
 }
 
 impl OAuthServerState {
     pub fn new() -> Self {
         Self {
             server: Arc::new(Mutex::new(None)),
+            refresher: Arc::new(Refresher::default()),
         }
     }
 }
 
+/// The current time in Unix seconds.
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Starts renewing vault credentials in the background as they come due (see `refresh`).
+pub fn start_token_refresh(app: &AppHandle) {
+    let refresher = Arc::clone(&app.state::<OAuthServerState>().refresher);
+    tauri::async_runtime::spawn(refresh::run(app.clone(), refresher));
+}
+
 /// Starts the OAuth callback server.
 /// Returns the port number the server is listening on.
+///
+/// `result_ttl_secs` sets how long results wait to be picked up (`server::DEFAULT_RESULT_TTL_SECS`
+/// unless given), and `idle_timeout_secs` how long the server keeps listening with nothing
+/// happening on it (`server::DEFAULT_IDLE_TIMEOUT_SECS`); both apply to a server already running
+/// too. The server stops by itself (see `server`), so call this before every flow: the port may
+/// have changed.
 #[tauri::command]
-pub async fn start_oauth_server(app: AppHandle, state: State<'_, OAuthServerState>) -> Result<u16, String> {
+pub async fn start_oauth_server(
+    app: AppHandle,
+    state: State<'_, OAuthServerState>,
+    result_ttl_secs: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+) -> Result<u16, String> {
+    if result_ttl_secs == Some(0) {
+        return Err("Result TTL must be at least a second".to_string());
+    }
+    if idle_timeout_secs == Some(0) {
+        return Err("Idle timeout must be at least a second".to_string());
+    }
     let mut server_lock = state.server.lock().await;
-
-    // If server is already running, return existing port.
-    if let Some(ref server) = *server_lock {
-        return Ok(server.port());
+    let server = ensure_started(&mut server_lock, app).await?;
+    if let Some(secs) = result_ttl_secs {
+        server.set_result_ttl(Duration::from_secs(secs));
+    }
+    if let Some(secs) = idle_timeout_secs {
+        server.set_idle_timeout(Duration::from_secs(secs));
     }
+    Ok(server.port())
+}
 
-    // Create and start new server.
-    let mut server = OAuthServer::new();
-    let port = server.start(app).await?;
-    *server_lock = Some(server);
+/// The running server, started first if it isn't. A server that stopped by itself is started
+/// again rather than replaced, so results waiting on it survive.
+async fn ensure_started(
+    server_lock: &mut Option<OAuthServer>,
+    app: AppHandle,
+) -> Result<&mut OAuthServer, String> {
+    let server = server_lock.get_or_insert_with(OAuthServer::new);
+    if !server.is_running() {
+        server.start(app).await?;
+    }
+    server.keep_alive();
+    Ok(server)
+}
 
-    Ok(port)
+/// Starts a native authorization-code flow with PKCE against a provider we talk to directly (see
+/// `native`), opening its authorization page in the system browser. Returns the authorization
+/// URL. The tokens arrive like Edge's: through `take_oauth_result` and `OAUTH_CALLBACK_EVENT`.
+#[tauri::command]
+pub async fn start_native_oauth_flow(
+    app: AppHandle,
+    state: State<'_, OAuthServerState>,
+    request: NativeOAuthRequest,
+) -> Result<String, String> {
+    let mut server_lock = state.server.lock().await;
+    let server = ensure_started(&mut server_lock, app.clone()).await?;
+
+    // An IP literal rather than `localhost`, as RFC 8252 recommends: the server only listens on IPv4.
+    let redirect_uri = format!("http://127.0.0.1:{}{}", server.port(), NATIVE_REDIRECT_PATH);
+    let flow_state = pkce::random_token();
+    let (auth_url, flow) = native::authorize(&request, &redirect_uri, &flow_state)?;
+    server.begin_native_flow(flow_state, flow).await;
+
+    app.opener()
+        .open_url(auth_url.as_str(), None::<&str>)
+        .map_err(|e| format!("Failed to open browser: {}", e))?;
+    Ok(auth_url.into())
 }
 
 /// Stops the OAuth callback server.
@@ -126,39 +245,120 @@ pub async fn stop_oauth_server(state: State<'_, OAuthServerState>) -> Result<(),
     Ok(())
 }
 
-/// Gets the OAuth result for a specific access token ID.
-/// Returns None if no result is available yet.
+/// Takes the OAuth result for a specific access token ID: it is removed as it is handed over, so
+/// nothing else can read the token afterwards. Returns None if no result is available yet, or it
+/// has expired or been taken.
 #[tauri::command]
-pub async fn get_oauth_result(
+pub async fn take_oauth_result(
     access_token_id: String,
     state: State<'_, OAuthServerState>,
 ) -> Result<Option<OAuthResult>, String> {
     let server_lock = state.server.lock().await;
 
     match &*server_lock {
-        Some(server) => Ok(server.get_result(&access_token_id).await),
+        Some(server) => Ok(server.take_result(&access_token_id).await),
         None => Err("OAuth server not running".to_string()),
     }
 }
 
-/// Gets the OAuth-recovery callback params, if one has arrived.
+/// Waits for the OAuth result for a specific access token ID and takes it, as `take_oauth_result`
+/// would. Fails after `timeout_ms` (`DEFAULT_WAIT_TIMEOUT` unless given), when cancelled with
+/// `cancel_oauth_wait`, or when the server stops.
+#[tauri::command]
+pub async fn wait_for_oauth_result(
+    access_token_id: String,
+    timeout_ms: Option<u64>,
+    state: State<'_, OAuthServerState>,
+) -> Result<OAuthResult, String> {
+    let timeout = timeout_ms.map_or(DEFAULT_WAIT_TIMEOUT, Duration::from_millis);
+    // Waits without holding the lock, so the server can be used (and stopped) meanwhile.
+    let waiting = match &*state.server.lock().await {
+        Some(server) if server.is_running() => server.wait_for_result(access_token_id, timeout),
+        // Nothing more can arrive, but a result may have before the server stopped.
+        Some(server) => {
+            return server
+                .take_result(&access_token_id)
+                .await
+                .ok_or_else(|| "OAuth server not running".to_string())
+        }
+        None => return Err("OAuth server not running".to_string()),
+    };
+    waiting.await
+}
+
+/// Fails the `wait_for_oauth_result` calls waiting on an access token ID.
+#[tauri::command]
+pub async fn cancel_oauth_wait(
+    access_token_id: String,
+    state: State<'_, OAuthServerState>,
+) -> Result<(), String> {
+    if let Some(server) = &*state.server.lock().await {
+        server.cancel_wait(&access_token_id);
+    }
+    Ok(())
+}
+
+/// Gets the OAuth-recovery callback params of a flow, if they have arrived.
 /// Returns None if no result is available yet.
 #[tauri::command]
 pub async fn get_oauth_recovery_result(
+    flow_id: String,
     state: State<'_, OAuthServerState>,
 ) -> Result<Option<OAuthRecoveryResult>, String> {
     let server_lock = state.server.lock().await;
 
     match &*server_lock {
-        Some(server) => Ok(server.get_recovery_result().await),
+        Some(server) => Ok(server.get_recovery_result(&flow_id).await),
         None => Err("OAuth server not running".to_string()),
     }
 }
 
+/// Waits for the OAuth-recovery callback of a flow and takes its params. Fails like
+/// `wait_for_oauth_result`, cancelled with `cancel_oauth_recovery_wait`.
+#[tauri::command]
+pub async fn wait_for_oauth_recovery_result(
+    flow_id: String,
+    timeout_ms: Option<u64>,
+    state: State<'_, OAuthServerState>,
+) -> Result<OAuthRecoveryResult, String> {
+    let timeout = timeout_ms.map_or(DEFAULT_WAIT_TIMEOUT, Duration::from_millis);
+    let waiting = match &*state.server.lock().await {
+        Some(server) if server.is_running() => server.wait_for_recovery_result(flow_id, timeout),
+        Some(server) => {
+            return server
+                .take_recovery_result(&flow_id)
+                .await
+                .ok_or_else(|| "OAuth server not running".to_string())
+        }
+        None => return Err("OAuth server not running".to_string()),
+    };
+    waiting.await
+}
+
+/// Fails the `wait_for_oauth_recovery_result` calls waiting on a flow.
+#[tauri::command]
+pub async fn cancel_oauth_recovery_wait(
+    flow_id: String,
+    state: State<'_, OAuthServerState>,
+) -> Result<(), String> {
+    if let Some(server) = &*state.server.lock().await {
+        server.cancel_recovery_wait(&flow_id);
+    }
+    Ok(())
+}
+
 /// Initiates OAuth flow by making request to Edge with correct Origin header.
 /// This bypasses browser restrictions on setting the Origin header.
+///
+/// The flow is registered with the callback server, started first if it isn't running, which only
+/// accepts its callback from the browser the relay page for the returned URL was opened in (see
+/// `server`). With
+/// `store_in_vault`, the token goes to the credential vault rather than to the app (see `vault`),
+/// where it is renewed through Edge before it expires (see `refresh`). Returns the URL to relay the
+/// browser to and the id the flow's recovery result, if any, is filed under.
 #[tauri::command]
 pub async fn initiate_oauth_flow(
+    app: AppHandle,
     edge_url: String,
     provider: String,
     scopes: Vec<String>,
@@ -170,7 +370,12 @@ pub async fn initiate_oauth_flow(
     purpose: Option<String>,
     register_recovery: Option<bool>,
     login_hint: Option<String>,
-) -> Result<String, String> {
+    store_in_vault: Option<bool>,
+    state: State<'_, OAuthServerState>,
+) -> Result<InitiatedOAuthFlow, String> {
+    // Before Edge is asked: a flow it creates with no server to return to could never complete.
+    ensure_started(&mut *state.server.lock().await, app.clone()).await?;
+
     let client = reqwest::Client::new();
 
     let initiate_url = format!("{}/oauth/initiate", edge_url.trim_end_matches('/'));
@@ -187,19 +392,20 @@ pub async fn initiate_oauth_flow(
             .map_err(|e| format!("Invalid origin: {}", e))?,
     );
 
-    if let Some(auth) = auth_header {
+    if let Some(auth) = &auth_header {
         headers.insert(
             AUTHORIZATION,
-            HeaderValue::from_str(&auth)
+            HeaderValue::from_str(auth)
                 .map_err(|e| format!("Invalid auth header: {}", e))?,
         );
     }
 
+    let recovery = purpose.is_some();
     let request_body = InitiateOAuthRequest {
         provider,
         scopes,
         space_id,
-        access_token_id,
+        access_token_id: access_token_id.clone(),
         native_app_redirect,
         purpose,
         register_recovery,
@@ -227,8 +433,40 @@ pub async fn initiate_oauth_flow(
         return Err(format!("OAuth initiation failed: {}", error_msg));
     }
 
-    envelope
+    let auth_url = envelope
         .data
         .map(|d| d.auth_url)
-        .ok_or_else(|| "No auth URL in response".to_string())
+        .ok_or_else(|| "No auth URL in response".to_string())?;
+
+    let mut server_lock = state.server.lock().await;
+    let server = ensure_started(&mut server_lock, app).await?;
+    let flow_id = server
+        .begin_flow(
+            access_token_id,
+            recovery,
+            auth_url.clone(),
+            store_in_vault.unwrap_or(false),
+            (!recovery).then_some(Renewal::Edge { edge_url }),
+        )
+        .await;
+    Ok(InitiatedOAuthFlow { auth_url, flow_id })
+}
+
+/// Renews the access token of a vault credential now, however long it has left. Returns when the
+/// new one expires (Unix seconds), if known. Listeners of `refresh::TOKEN_REFRESHED_EVENT` and
+/// `refresh::TOKEN_REFRESH_FAILED_EVENT` hear of it too.
+///
+/// Credentials renewed through Edge take the app's current `auth_header`; this is also how the
+/// app answers `refresh::TOKEN_REFRESH_NEEDED_EVENT`.
+#[tauri::command]
+pub async fn refresh_oauth_token(
+    app: AppHandle,
+    state: State<'_, OAuthServerState>,
+    access_token_id: String,
+    auth_header: Option<String>,
+) -> Result<Option<u64>, String> {
+    state
+        .refresher
+        .refresh(&app, &access_token_id, true, auth_header.as_deref())
+        .await
 }
@@ -0,0 +1,331 @@
+//! Authorization-code flow with PKCE, run natively against providers we talk to directly.
+//!
+//! Follows RFC 8252: the shell generates the code verifier and `state`, the system browser shows
+//! the provider's consent page, the provider redirects to the loopback server on an IP literal,
+//! and the shell exchanges the code itself. Neither the verifier nor the code ever reaches the
+//! webview; the tokens are handed over through the same `OAuthResult` path as Edge's flow.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+use url::Url;
+
+use super::pkce::Pkce;
+
+/// Path the provider redirects back to on the loopback server.
+pub const NATIVE_REDIRECT_PATH: &str = "/redirect/native-oauth";
+
+/// How long a token endpoint gets to answer, connection included, before the exchange or
+/// refresh fails rather than hanging the flow.
+pub(super) const TOKEN_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Query parameters the flow sets itself, which `extra_params` may not override.
+const RESERVED_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "code_challenge",
+    "code_challenge_method",
+];
+
+/// A provider to run the flow against.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeOAuthRequest {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    /// Id the resulting `OAuthResult` is filed under.
+    pub access_token_id: String,
+    /// Provider-specific authorization parameters (e.g. `access_type=offline`, `prompt`).
+    #[serde(default)]
+    pub extra_params: HashMap<String, String>,
+    /// Whether the tokens go to the credential vault rather than the app (see `vault`).
+    #[serde(default)]
+    pub store_in_vault: bool,
+}
+
+/// A flow waiting for its callback, by `state`.
+pub struct NativeFlow {
+    pub access_token_id: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub code_verifier: String,
+    pub store_in_vault: bool,
+}
+
+/// The token endpoint's answer (RFC 6749 section 5.1).
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// The provider's authorization URL for a new flow, and the flow to file under `state`.
+pub fn authorize(
+    request: &NativeOAuthRequest,
+    redirect_uri: &str,
+    state: &str,
+) -> Result<(Url, NativeFlow), String> {
+    let pkce = Pkce::generate();
+    let mut url = Url::parse(&request.authorization_endpoint)
+        .map_err(|e| format!("Invalid authorization endpoint: {}", e))?;
+    {
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &request.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &request.scopes.join(" "))
+            .append_pair("state", state)
+            .append_pair("code_challenge", &pkce.challenge)
+            .append_pair("code_challenge_method", "S256");
+        for (key, value) in &request.extra_params {
+            if !RESERVED_PARAMS.contains(&key.as_str()) {
+                query.append_pair(key, value);
+            }
+        }
+    }
+
+    let flow = NativeFlow {
+        access_token_id: request.access_token_id.clone(),
+        token_endpoint: request.token_endpoint.clone(),
+        client_id: request.client_id.clone(),
+        redirect_uri: redirect_uri.to_string(),
+        code_verifier: pkce.verifier,
+        store_in_vault: request.store_in_vault,
+    };
+    Ok((url, flow))
+}
+
+/// Exchanges an authorization `code` for tokens at the flow's token endpoint.
+pub async fn exchange_code(flow: &NativeFlow, code: &str) -> Result<TokenResponse, String> {
+    request_tokens(
+        &flow.token_endpoint,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &flow.redirect_uri),
+            ("client_id", &flow.client_id),
+            ("code_verifier", &flow.code_verifier),
+        ],
+    )
+    .await
+}
+
+/// Trades a refresh token for a new access token (RFC 6749 section 6). The provider may send a new
+/// refresh token along, replacing the old one.
+pub async fn refresh_tokens(
+    token_endpoint: &str,
+    client_id: &str,
+    refresh_token: &str,
+) -> Result<TokenResponse, String> {
+    request_tokens(
+        token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", client_id),
+        ],
+    )
+    .await
+}
+
+/// Posts `form` to a token endpoint.
+async fn request_tokens(
+    token_endpoint: &str,
+    form: &[(&str, &str)],
+) -> Result<TokenResponse, String> {
+    let response = reqwest::Client::new()
+        .post(token_endpoint)
+        .timeout(TOKEN_REQUEST_TIMEOUT)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(form)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach token endpoint: {}", e))?;
+
+    let status = response.status();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read token response: {}", e))?;
+    if !status.is_success() {
+        return Err(match serde_json::from_slice::<TokenError>(&body) {
+            Ok(error) => match error.error_description {
+                Some(description) => format!("{}: {}", error.error, description),
+                None => error.error,
+            },
+            Err(_) => format!("Token endpoint returned {}", status),
+        });
+    }
+    serde_json::from_slice(&body).map_err(|e| format!("Failed to parse token response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth::pkce::challenge;
+
+    fn request() -> NativeOAuthRequest {
+        NativeOAuthRequest {
+            authorization_endpoint: "https://provider.example.com/authorize?tenant=a".to_string(),
+            token_endpoint: "https://provider.example.com/token".to_string(),
+            client_id: "client".to_string(),
+            scopes: vec!["read".to_string(), "write".to_string()],
+            access_token_id: "token-id".to_string(),
+            extra_params: HashMap::from([
+                ("prompt".to_string(), "consent".to_string()),
+                ("state".to_string(), "forged".to_string()),
+            ]),
+            store_in_vault: false,
+        }
+    }
+
+    #[test]
+    fn builds_the_authorization_url() {
+        let redirect_uri = "http://127.0.0.1:4000/redirect/native-oauth";
+        let (url, flow) = authorize(&request(), redirect_uri, "the-state").unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["tenant"], "a");
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["redirect_uri"], redirect_uri);
+        assert_eq!(params["scope"], "read write");
+        assert_eq!(params["state"], "the-state");
+        assert_eq!(params["prompt"], "consent");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["code_challenge"], challenge(&flow.code_verifier));
+        assert_eq!(
+            url.query_pairs().filter(|(key, _)| key == "state").count(),
+            1
+        );
+    }
+
+    /// A token endpoint answering one request with `status` and `body`, returning its URL and the
+    /// request it received.
+    async fn token_endpoint(
+        status: u16,
+        body: &'static str,
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Headers, then a form body the size `Content-Length` gives.
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&received).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .and_then(|length| length.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if received.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&received).to_string()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn exchanges_the_code_with_the_verifier() {
+        let (url, received) = token_endpoint(
+            200,
+            r#"{"access_token":"at","refresh_token":"rt","expires_in":3600}"#,
+        )
+        .await;
+        let (_, flow) = authorize(
+            &NativeOAuthRequest {
+                token_endpoint: url,
+                ..request()
+            },
+            "http://127.0.0.1:1/cb",
+            "s",
+        )
+        .unwrap();
+        let tokens = exchange_code(&flow, "the-code").await.unwrap();
+        assert_eq!(tokens.access_token, "at");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("rt"));
+        assert_eq!(tokens.expires_in, Some(3600));
+
+        let received = received.await.unwrap();
+        let body = received.split("\r\n\r\n").nth(1).unwrap();
+        let form: HashMap<String, String> = url::form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(form["grant_type"], "authorization_code");
+        assert_eq!(form["code"], "the-code");
+        assert_eq!(form["code_verifier"], flow.code_verifier);
+    }
+
+    #[tokio::test]
+    async fn refreshes_tokens() {
+        let (url, received) =
+            token_endpoint(200, r#"{"access_token":"at2","expires_in":60}"#).await;
+        let tokens = refresh_tokens(&url, "client", "rt").await.unwrap();
+        assert_eq!(tokens.access_token, "at2");
+        assert_eq!(tokens.refresh_token, None);
+
+        let received = received.await.unwrap();
+        let body = received.split("\r\n\r\n").nth(1).unwrap();
+        let form: HashMap<String, String> = url::form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(form["grant_type"], "refresh_token");
+        assert_eq!(form["refresh_token"], "rt");
+        assert_eq!(form["client_id"], "client");
+    }
+
+    #[tokio::test]
+    async fn reports_token_errors() {
+        let (url, _) = token_endpoint(
+            400,
+            r#"{"error":"invalid_grant","error_description":"expired"}"#,
+        )
+        .await;
+        let (_, flow) = authorize(
+            &NativeOAuthRequest {
+                token_endpoint: url,
+                ..request()
+            },
+            "http://127.0.0.1:1/cb",
+            "s",
+        )
+        .unwrap();
+        assert_eq!(
+            exchange_code(&flow, "code").await.unwrap_err(),
+            "invalid_grant: expired"
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! Renewing the access tokens of vault credentials before they expire.
+//!
+//! A background task (`run`) renews each credential that knows both when it expires and how it is
+//! renewed (`vault::Renewal`) once it is within `REFRESH_AHEAD_SECS` of expiring, and then leaves
+//! it alone for `RETRY_AFTER_SECS` whatever came of it. In between it sleeps until the next one is
+//! due, going by the vault's record of when they expire (`vault::expiries`) and waking early when
+//! that changes.
+//! `refresh_oauth_token` renews one on demand. Either way the app hears of it through
+//! `TOKEN_REFRESHED_EVENT` or `TOKEN_REFRESH_FAILED_EVENT`, which never carry the token: it is
+//! fetched from the vault.
+//!
+//! Credentials from native flows are renewed at the provider's token endpoint with their refresh
+//! token. For flows run through Edge, Edge keeps the refresh token and renews the access token
+//! itself: `POST <edge>/oauth/refresh` with the credential's access token id, authorized as the
+//! app's Edge session. That session isn't kept here, so the background task asks the app
+//! (`TOKEN_REFRESH_NEEDED_EVENT`), which answers with `refresh_oauth_token` and its current
+//! authorization; it is asked again after `RETRY_AFTER_SECS` if it doesn't.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+
+use super::native::{refresh_tokens, TOKEN_REQUEST_TIMEOUT};
+use super::{now_secs, EdgeEnvelope};
+use crate::vault::{self, Credential, Renewal};
+
+/// Event for a renewed credential, with a `TokenRefresh` payload.
+pub const TOKEN_REFRESHED_EVENT: &str = "dxos:oauth-token-refreshed";
+
+/// Event for a credential that couldn't be renewed, with a `TokenRefresh` payload.
+pub const TOKEN_REFRESH_FAILED_EVENT: &str = "dxos:oauth-token-refresh-failed";
+
+/// Event for a credential renewed through Edge coming due, with a `TokenRefresh` payload carrying
+/// when it expires: the app renews it with `refresh_oauth_token`.
+pub const TOKEN_REFRESH_NEEDED_EVENT: &str = "dxos:oauth-token-refresh-needed";
+
+/// How long before expiry a credential is renewed, in seconds.
+const REFRESH_AHEAD_SECS: u64 = 5 * 60;
+
+/// Longest the background task sleeps at a time: its timer may not run while the machine is
+/// suspended, which would leave what came due meanwhile waiting.
+const MAX_SLEEP: Duration = Duration::from_secs(15 * 60);
+
+/// How long the background task leaves a credential alone after trying to renew it, or asking the
+/// app to, in seconds.
+const RETRY_AFTER_SECS: u64 = 5 * 60;
+
+/// Payload of the refresh events.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRefresh {
+    pub access_token_id: String,
+    /// When the new access token expires (Unix seconds), if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Request body for renewing a token through Edge.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshOAuthRequest<'a> {
+    access_token_id: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshOAuthResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Renews credentials, one at a time.
+#[derive(Default)]
+pub struct Refresher {
+    /// Held across each renewal, so a forced one and the background task's never spend the same
+    /// refresh token twice.
+    lock: Mutex<()>,
+    /// When the background task last tried to renew each credential, or asked the app to (Unix
+    /// seconds).
+    attempts: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl Refresher {
+    /// Renews the credential filed under `access_token_id`, stores it and tells the app. Unless
+    /// `force` is set, a credential that isn't due (any more) is left as it is. `auth_header`
+    /// authorizes renewals through Edge. Returns when the credential now expires, if known.
+    pub async fn refresh(
+        &self,
+        app: &AppHandle,
+        access_token_id: &str,
+        force: bool,
+        auth_header: Option<&str>,
+    ) -> Result<Option<u64>, String> {
+        let _lock = self.lock.lock().await;
+        let credential = vault::lookup(app, access_token_id)
+            .await?
+            .ok_or_else(|| format!("No credential for {}", access_token_id))?;
+        if !force && !due(&credential, now_secs()) {
+            return Ok(credential.expires_at);
+        }
+
+        let outcome = match renew(&credential, auth_header).await {
+            Ok(renewed) => {
+                let expires_at = renewed.expires_at;
+                vault::deposit(app, renewed).await.map(|()| expires_at)
+            }
+            Err(e) => Err(e),
+        };
+
+        let (event, payload) = match &outcome {
+            Ok(expires_at) => (
+                TOKEN_REFRESHED_EVENT,
+                TokenRefresh {
+                    access_token_id: access_token_id.to_string(),
+                    expires_at: *expires_at,
+                    error: None,
+                },
+            ),
+            Err(e) => (
+                TOKEN_REFRESH_FAILED_EVENT,
+                TokenRefresh {
+                    access_token_id: access_token_id.to_string(),
+                    expires_at: None,
+                    error: Some(e.clone()),
+                },
+            ),
+        };
+        let _ = app.emit(event, payload);
+        outcome
+    }
+
+    /// Renews the credential under `access_token_id` if it is due, or asks the app to if it is
+    /// renewed through Edge.
+    async fn renew_due(&self, app: &AppHandle, access_token_id: &str) {
+        if let Ok(mut attempts) = self.attempts.lock() {
+            attempts.insert(access_token_id.to_string(), now_secs());
+        }
+        match vault::lookup(app, access_token_id).await {
+            Ok(Some(credential)) if due(&credential, now_secs()) => {
+                if matches!(credential.renewal, Some(Renewal::Edge { .. })) {
+                    request(app, &credential);
+                } else {
+                    let _ = self.refresh(app, access_token_id, false, None).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// When the background task should next try to renew a credential expiring at `expires_at`.
+    fn next_attempt(&self, access_token_id: &str, expires_at: u64) -> u64 {
+        let due_at = expires_at.saturating_sub(REFRESH_AHEAD_SECS);
+        let retry_at = self.attempts.lock().ok().and_then(|attempts| {
+            attempts
+                .get(access_token_id)
+                .map(|attempted| attempted + RETRY_AFTER_SECS)
+        });
+        retry_at.map_or(due_at, |retry_at| retry_at.max(due_at))
+    }
+}
+
+/// Asks the app to renew a credential that is renewed through Edge.
+fn request(app: &AppHandle, credential: &Credential) {
+    let _ = app.emit(
+        TOKEN_REFRESH_NEEDED_EVENT,
+        TokenRefresh {
+            access_token_id: credential.access_token_id.clone(),
+            expires_at: credential.expires_at,
+            error: None,
+        },
+    );
+}
+
+/// Renews credentials as they come due, for as long as the app runs.
+pub async fn run(app: AppHandle, refresher: Arc<Refresher>) {
+    loop {
+        let expiries = match vault::expiries(&app).await {
+            Ok(expiries) => expiries,
+            Err(e) => {
+                log::warn!("Failed to list credentials to refresh: {}", e);
+                tokio::time::sleep(Duration::from_secs(RETRY_AFTER_SECS)).await;
+                continue;
+            }
+        };
+        if let Ok(mut attempts) = refresher.attempts.lock() {
+            attempts.retain(|id, _| expiries.contains_key(id));
+        }
+
+        let mut next = None;
+        for (id, expires_at) in &expiries {
+            let attempt_at = refresher.next_attempt(id, *expires_at);
+            if attempt_at <= now_secs() {
+                refresher.renew_due(&app, id).await;
+            } else {
+                next = Some(next.map_or(attempt_at, |next: u64| next.min(attempt_at)));
+            }
+        }
+
+        // Renewing just now noted new expiries, so this wakes straight away to read them.
+        let sleep = next.map_or(MAX_SLEEP, |next| {
+            Duration::from_secs(next.saturating_sub(now_secs())).min(MAX_SLEEP)
+        });
+        tokio::select! {
+            () = tokio::time::sleep(sleep) => {}
+            () = vault::expiries_changed(&app) => {}
+        }
+    }
+}
+
+/// Whether `credential` is renewable and expires within `REFRESH_AHEAD_SECS` of `now`.
+fn due(credential: &Credential, now: u64) -> bool {
+    credential.renewable()
+        && credential
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now + REFRESH_AHEAD_SECS)
+}
+
+/// `credential` with a new access token; `auth_header` authorizes renewing it through Edge.
+async fn renew(credential: &Credential, auth_header: Option<&str>) -> Result<Credential, String> {
+    match &credential.renewal {
+        Some(Renewal::Provider {
+            token_endpoint,
+            client_id,
+        }) => {
+            let refresh_token = credential
+                .refresh_token
+                .as_deref()
+                .ok_or_else(|| "No refresh token".to_string())?;
+            let tokens = refresh_tokens(token_endpoint, client_id, refresh_token).await?;
+            Ok(renewed(
+                credential,
+                tokens.access_token,
+                tokens.refresh_token,
+                tokens.expires_in,
+                now_secs(),
+            ))
+        }
+        Some(Renewal::Edge { edge_url }) => {
+            let response =
+                renew_through_edge(edge_url, auth_header, &credential.access_token_id).await?;
+            Ok(renewed(
+                credential,
+                response.access_token,
+                None,
+                response.expires_in,
+                now_secs(),
+            ))
+        }
+        None => Err("Credential can't be refreshed".to_string()),
+    }
+}
+
+/// `credential` with `access_token`, keeping its refresh token unless the provider sent a new one.
+fn renewed(
+    credential: &Credential,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+    now: u64,
+) -> Credential {
+    Credential {
+        access_token_id: credential.access_token_id.clone(),
+        access_token,
+        refresh_token: refresh_token.or_else(|| credential.refresh_token.clone()),
+        expires_at: expires_in.map(|secs| now + secs),
+        renewal: credential.renewal.clone(),
+    }
+}
+
+async fn renew_through_edge(
+    edge_url: &str,
+    auth_header: Option<&str>,
+    access_token_id: &str,
+) -> Result<RefreshOAuthResponse, String> {
+    let refresh_url = format!("{}/oauth/refresh", edge_url.trim_end_matches('/'));
+    let mut request = reqwest::Client::new()
+        .post(&refresh_url)
+        .timeout(TOKEN_REQUEST_TIMEOUT)
+        .json(&RefreshOAuthRequest { access_token_id });
+    if let Some(auth) = auth_header {
+        request = request.header(AUTHORIZATION, auth);
+    }
+    let envelope: EdgeEnvelope<RefreshOAuthResponse> = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to send request: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+    if !envelope.success {
+        let error_msg = envelope
+            .error
+            .and_then(|e| e.message)
+            .unwrap_or_else(|| "Unknown error".to_string());
+        return Err(format!("Token refresh failed: {}", error_msg));
+    }
+    envelope
+        .data
+        .ok_or_else(|| "No access token in response".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential(renewal: Option<Renewal>, expires_at: Option<u64>) -> Credential {
+        Credential {
+            access_token_id: "id".to_string(),
+            access_token: "at".to_string(),
+            refresh_token: Some("rt".to_string()),
+            expires_at,
+            renewal,
+        }
+    }
+
+    fn provider() -> Option<Renewal> {
+        Some(Renewal::Provider {
+            token_endpoint: "https://provider.example.com/token".to_string(),
+            client_id: "client".to_string(),
+        })
+    }
+
+    #[test]
+    fn renews_shortly_before_expiry() {
+        let now = 1_000_000;
+        let expiring = credential(provider(), Some(now + REFRESH_AHEAD_SECS));
+        assert!(due(&expiring, now));
+        assert!(!due(&expiring, now - 1));
+        assert!(due(&credential(provider(), Some(now - 1)), now));
+
+        // Nothing to go on.
+        assert!(!due(&credential(provider(), None), now));
+        assert!(!due(&credential(None, Some(now)), now));
+        let mut without_refresh_token = credential(provider(), Some(now));
+        without_refresh_token.refresh_token = None;
+        assert!(!due(&without_refresh_token, now));
+
+        let edge = Some(Renewal::Edge {
+            edge_url: "https://edge.example.com".to_string(),
+        });
+        let mut through_edge = credential(edge, Some(now));
+        through_edge.refresh_token = None;
+        assert!(due(&through_edge, now));
+    }
+
+    #[test]
+    fn keeps_the_refresh_token_unless_replaced() {
+        let old = credential(provider(), Some(100));
+        let kept = renewed(&old, "at2".to_string(), None, Some(60), 1_000);
+        assert_eq!(kept.access_token, "at2");
+        assert_eq!(kept.refresh_token.as_deref(), Some("rt"));
+        assert_eq!(kept.expires_at, Some(1_060));
+        assert!(matches!(kept.renewal, Some(Renewal::Provider { .. })));
+
+        let replaced = renewed(
+            &old,
+            "at3".to_string(),
+            Some("rt2".to_string()),
+            None,
+            1_000,
+        );
+        assert_eq!(replaced.refresh_token.as_deref(), Some("rt2"));
+        assert_eq!(replaced.expires_at, None);
+    }
+
+    #[test]
+    fn backs_off_after_an_attempt() {
+        let refresher = Refresher::default();
+        let expires_at = 10_000;
+        let due_at = expires_at - REFRESH_AHEAD_SECS;
+        assert_eq!(refresher.next_attempt("id", expires_at), due_at);
+
+        refresher
+            .attempts
+            .lock()
+            .unwrap()
+            .insert("id".to_string(), due_at);
+        assert_eq!(
+            refresher.next_attempt("id", expires_at),
+            due_at + RETRY_AFTER_SECS
+        );
+        // A renewed credential waits for its new expiry, however soon it was tried.
+        assert_eq!(
+            refresher.next_attempt("id", 100_000),
+            100_000 - REFRESH_AHEAD_SECS
+        );
+        assert_eq!(refresher.next_attempt("other", expires_at), due_at);
+    }
+}
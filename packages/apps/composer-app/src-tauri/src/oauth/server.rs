@@ -1,7 +1,37 @@
 //! Lightweight HTTP server for OAuth callback handling.
+//!
+//! Anything that can reach 127.0.0.1 can request a callback URL, so callbacks are only accepted
+//! for flows this app started. Edge's redirect can't carry a `state` of ours, so each flow's
+//! unguessable state travels in a cookie instead: `initiate_oauth_flow` registers the flow, the
+//! relay page the browser opens it through sets the cookie (once), and the browser sends it back
+//! with the callback. A callback without the cookie of a pending flow it matches is refused, and
+//! the state is consumed by the first callback presenting it. Native flows (see `native`) carry
+//! their `state` through the provider instead, with the same one-time consumption.
+//!
+//! Results wait for the app only so long (`DEFAULT_RESULT_TTL_SECS` unless `start_oauth_server`
+//! says otherwise), and `take_oauth_result` removes one as it hands it over. Either way the token
+//! strings are wiped as the result is dropped (see `OAuthResult`). Recovery callbacks carry no
+//! access token id to file them under, so they are filed under the id `initiate_oauth_flow` gave
+//! their flow instead, and otherwise kept the same way.
+//!
+//! Instead of polling for a result, the app can wait for it (`wait_for_oauth_result`): each
+//! callback wakes the commands waiting on its access token id (see `wait`).
+//!
+//! The server doesn't wait to be stopped either. It stops itself once the flows it was started
+//! for have completed, or when nothing has happened on it for a while (`DEFAULT_IDLE_TIMEOUT_SECS`
+//! unless `start_oauth_server` says otherwise), letting connections finish what they are serving
+//! first. Results stay available after it stops, and the next `start_oauth_server` starts it
+//! again on a new port.
+//!
+//! A flow started with `storeInVault` hands its tokens to the credential vault (see `vault`)
+//! instead: its result and callback event only say that they were stored. The vault keeps with
+//! them how they are renewed, for `refresh`.
 
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use http_body_util::Full;
 use hyper::body::Bytes;
@@ -11,9 +41,14 @@ use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use tauri::{AppHandle, Emitter};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, watch, Mutex, MutexGuard};
+use tokio::task::JoinSet;
 
-use super::{OAuthRecoveryResult, OAuthResult, OAUTH_CALLBACK_EVENT};
+use super::native::{exchange_code, NativeFlow, NATIVE_REDIRECT_PATH};
+use super::pkce::random_token;
+use super::wait::Waiters;
+use super::{now_secs, OAuthRecoveryResult, OAuthResult, OAUTH_CALLBACK_EVENT};
+use crate::vault::{self, Credential, Renewal};
 
 /// Generates the HTML for the OAuth relay page.
 fn get_relay_page_html(auth_url: &str) -> String {
@@ -42,28 +77,202 @@ fn get_error_html() -> &'static str {
     "<html><body><h1>Authentication failed</h1><p>Missing access token parameters.</p></body></html>"
 }
 
+/// Generates the HTML page for a callback no pending flow accounts for.
+fn get_unknown_flow_html() -> &'static str {
+    "<html><body><h1>Authentication failed</h1><p>This sign-in was not started here, or has already completed.</p></body></html>"
+}
+
+/// Prefix of the cookie carrying a flow's state; the state itself completes the name, so that
+/// flows running side by side in one browser don't overwrite each other's.
+const STATE_COOKIE_PREFIX: &str = "dxos-oauth-";
+
+/// How long the browser keeps a state cookie: longer than any sign-in takes.
+const STATE_COOKIE_MAX_AGE_SECS: u32 = 30 * 60;
+
+/// How long a result waits to be picked up by default.
+pub const DEFAULT_RESULT_TTL_SECS: u64 = 5 * 60;
+
+/// How often the server drops results nobody picked up, rather than waiting for the next lookup,
+/// and checks whether it should stop.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the server keeps listening with nothing happening on it, by default.
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 15 * 60;
+
+/// How long the server stays up after its last flow completes, for the app to start another on
+/// the same port.
+const LINGER: Duration = Duration::from_secs(30);
+
+/// How long connections get to finish when the server stops, before they are dropped.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A result, and when its callback arrived.
+pub struct StoredResult<T = OAuthResult> {
+    pub result: T,
+    pub received: Instant,
+}
+
+/// A flow started through Edge (`initiate_oauth_flow`), awaiting its callback.
+pub struct PendingFlow {
+    /// Id the app looks the flow's recovery result up by.
+    pub flow_id: String,
+    pub access_token_id: String,
+    /// Whether it is an account-recovery flow (answered on `/redirect/oauth-recovery`).
+    pub recovery: bool,
+    /// Where the relay page sends the browser.
+    pub auth_url: String,
+    /// Whether the relay page has handed out the state cookie already.
+    pub relayed: bool,
+    /// Whether the tokens go to the credential vault rather than the app.
+    pub store_in_vault: bool,
+    /// How the vault renews the token, if it goes there.
+    pub renewal: Option<Renewal>,
+}
+
 /// Shared state for the OAuth server.
 pub struct OAuthServerState {
-    pub results: Arc<Mutex<HashMap<String, OAuthResult>>>,
-    /// The recovery callback, if one has arrived. A single slot rather than a map: the flow runs
-    /// from the gate before any identity exists, so only one can ever be in flight.
-    pub recovery: Arc<Mutex<Option<OAuthRecoveryResult>>>,
+    /// Results awaiting pickup, by access token id. Go through `live_results`, which drops the
+    /// expired ones first.
+    pub results: Arc<Mutex<HashMap<String, StoredResult>>>,
+    /// How long a result is kept, in seconds.
+    pub result_ttl_secs: AtomicU64,
+    /// Commands waiting for a result, by access token id.
+    pub result_waiters: Waiters<String>,
+    /// Recovery callbacks awaiting pickup, by flow id. Go through `live_recovery_results`, which
+    /// drops the expired ones first.
+    pub recovery_results: Arc<Mutex<HashMap<String, StoredResult<OAuthRecoveryResult>>>>,
+    /// Commands waiting for a recovery callback, by flow id.
+    pub recovery_waiters: Waiters<String>,
+    /// Native authorization-code flows awaiting their callback, by `state`.
+    pub native_flows: Arc<Mutex<HashMap<String, NativeFlow>>>,
+    /// Flows started through Edge awaiting their callback, by state.
+    pub pending: Arc<Mutex<HashMap<String, PendingFlow>>>,
+    /// When a request last arrived or a flow was started.
+    pub last_activity: std::sync::Mutex<Instant>,
+    /// How long the server keeps listening with nothing happening on it, in seconds.
+    pub idle_timeout_secs: AtomicU64,
+    /// Whether a flow has completed since the server started.
+    pub flow_completed: AtomicBool,
 }
 
 impl OAuthServerState {
     pub fn new() -> Self {
         Self {
             results: Arc::new(Mutex::new(HashMap::new())),
-            recovery: Arc::new(Mutex::new(None)),
+            result_ttl_secs: AtomicU64::new(DEFAULT_RESULT_TTL_SECS),
+            result_waiters: Waiters::default(),
+            recovery_results: Arc::new(Mutex::new(HashMap::new())),
+            recovery_waiters: Waiters::default(),
+            native_flows: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+            idle_timeout_secs: AtomicU64::new(DEFAULT_IDLE_TIMEOUT_SECS),
+            flow_completed: AtomicBool::new(false),
+        }
+    }
+
+    /// Notes that something happened, putting off an idle shutdown.
+    fn touch(&self) {
+        if let Ok(mut last_activity) = self.last_activity.lock() {
+            *last_activity = Instant::now();
         }
     }
+
+    /// Whether the server is done: see `should_stop`.
+    async fn should_stop(&self) -> bool {
+        let idle = self
+            .last_activity
+            .lock()
+            .map_or(Duration::ZERO, |last_activity| last_activity.elapsed());
+        let flows_pending =
+            !self.pending.lock().await.is_empty() || !self.native_flows.lock().await.is_empty();
+        should_stop(
+            idle,
+            Duration::from_secs(self.idle_timeout_secs.load(Ordering::Relaxed)),
+            flows_pending,
+            self.flow_completed.load(Ordering::Relaxed),
+        )
+    }
+
+    fn result_ttl(&self) -> Duration {
+        Duration::from_secs(self.result_ttl_secs.load(Ordering::Relaxed))
+    }
+
+    /// The results map, with the results older than the TTL dropped.
+    async fn live_results(&self) -> MutexGuard<'_, HashMap<String, StoredResult>> {
+        let mut results = self.results.lock().await;
+        expire(&mut results, self.result_ttl(), Instant::now());
+        results
+    }
+
+    /// The recovery results map, with the results older than the TTL dropped.
+    async fn live_recovery_results(
+        &self,
+    ) -> MutexGuard<'_, HashMap<String, StoredResult<OAuthRecoveryResult>>> {
+        let mut results = self.recovery_results.lock().await;
+        expire(&mut results, self.result_ttl(), Instant::now());
+        results
+    }
+
+    /// Files `result` under its access token id, replacing any earlier one, and wakes whoever
+    /// waits for it.
+    async fn store_result(&self, result: OAuthResult) {
+        let access_token_id = result.access_token_id.clone();
+        let stored = StoredResult {
+            result,
+            received: Instant::now(),
+        };
+        self.live_results()
+            .await
+            .insert(access_token_id.clone(), stored);
+        self.result_waiters.wake(&access_token_id);
+        self.flow_completed.store(true, Ordering::Relaxed);
+    }
+
+    /// Files the recovery `result` under `flow_id`, and wakes whoever waits for it.
+    async fn store_recovery_result(&self, flow_id: String, result: OAuthRecoveryResult) {
+        let stored = StoredResult {
+            result,
+            received: Instant::now(),
+        };
+        self.live_recovery_results()
+            .await
+            .insert(flow_id.clone(), stored);
+        self.recovery_waiters.wake(&flow_id);
+        self.flow_completed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Waits for `woken` to fire, for at most `timeout`.
+async fn wait(woken: oneshot::Receiver<()>, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, woken).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err("Cancelled".to_string()),
+        Err(_) => Err("Timed out waiting for OAuth callback".to_string()),
+    }
+}
+
+/// Whether a server idle for `idle` should stop: once nothing has happened for `idle_timeout`, or
+/// for `LINGER` after the flows it served have all completed.
+fn should_stop(
+    idle: Duration,
+    idle_timeout: Duration,
+    flows_pending: bool,
+    flow_completed: bool,
+) -> bool {
+    idle >= idle_timeout || (flow_completed && !flows_pending && idle >= LINGER)
+}
+
+/// Drops the results in `results` older than `ttl` at `now`.
+fn expire<T>(results: &mut HashMap<String, StoredResult<T>>, ttl: Duration, now: Instant) {
+    results.retain(|_, stored| now.saturating_duration_since(stored.received) < ttl);
 }
 
 /// OAuth HTTP server.
 pub struct OAuthServer {
     port: u16,
     state: Arc<OAuthServerState>,
-    shutdown_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
     handle: Option<tokio::task::JoinHandle<()>>,
 }
 
@@ -83,7 +292,21 @@ impl OAuthServer {
         self.port
     }
 
-    /// Starts the HTTP server on a random available port, relaying callbacks to `app`.
+    /// Whether the server is listening: started, and neither stopped nor stopped by itself.
+    pub fn is_running(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Puts off an idle shutdown, for a caller about to use the server.
+    pub fn keep_alive(&self) {
+        self.state.touch();
+    }
+
+    /// Starts the HTTP server on a random available port, relaying callbacks to `app`. A server
+    /// that has stopped can be started again, keeping its results; flows begun before can't
+    /// complete on the new port and are dropped.
     pub async fn start(&mut self, app: AppHandle) -> Result<u16, String> {
         // Bind to port 0 to get a random available port.
         let listener = TcpListener::bind("127.0.0.1:0")
@@ -95,14 +318,23 @@ impl OAuthServer {
             .map_err(|e| format!("Failed to get local addr: {}", e))?;
         self.port = addr.port();
 
-        let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
         self.shutdown_tx = Some(shutdown_tx);
 
+        self.state.pending.lock().await.clear();
+        self.state.native_flows.lock().await.clear();
+        self.state.flow_completed.store(false, Ordering::Relaxed);
+        self.state.touch();
+
         let state = Arc::clone(&self.state);
         let port = self.port;
+        let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
 
         // Spawn the server task.
         let handle = tokio::spawn(async move {
+            // Dropped to ask connections to finish up.
+            let (drain_tx, drain_rx) = watch::channel(());
+            let mut connections = JoinSet::new();
             loop {
                 tokio::select! {
                     result = listener.accept() => {
@@ -111,18 +343,27 @@ impl OAuthServer {
                                 let state = Arc::clone(&state);
                                 let app = app.clone();
                                 let io = TokioIo::new(stream);
+                                let mut drain_rx = drain_rx.clone();
 
-                                tokio::spawn(async move {
+                                connections.spawn(async move {
                                     let service = service_fn(|req| {
                                         let state = Arc::clone(&state);
                                         let app = app.clone();
                                         async move { handle_request(req, state, app, port).await }
                                     });
 
-                                    if let Err(err) = http1::Builder::new()
-                                        .serve_connection(io, service)
-                                        .await
-                                    {
+                                    let connection = http1::Builder::new()
+                                        .serve_connection(io, service);
+                                    tokio::pin!(connection);
+                                    let result = tokio::select! {
+                                        result = connection.as_mut() => result,
+                                        _ = drain_rx.changed() => {
+                                            // Answers the request in flight, if any, then closes.
+                                            connection.as_mut().graceful_shutdown();
+                                            connection.await
+                                        }
+                                    };
+                                    if let Err(err) = result {
                                         eprintln!("Error serving connection: {:?}", err);
                                     }
                                 });
@@ -132,41 +373,181 @@ impl OAuthServer {
                             }
                         }
                     }
+                    // Reaps finished connections, so the set doesn't grow with every request.
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                    _ = sweep.tick() => {
+                        drop(state.live_results().await);
+                        drop(state.live_recovery_results().await);
+                        if state.should_stop().await {
+                            break;
+                        }
+                    }
                     _ = &mut shutdown_rx => {
                         break;
                     }
                 }
             }
+
+            drop(listener);
+            drop(drain_tx);
+            let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+                while connections.join_next().await.is_some() {}
+            });
+            if drained.await.is_err() {
+                connections.shutdown().await;
+            }
+            // Nothing can arrive for those still waiting.
+            state.result_waiters.cancel_all();
+            state.recovery_waiters.cancel_all();
         });
 
         self.handle = Some(handle);
         Ok(self.port)
     }
 
-    /// Stops the HTTP server.
+    /// Stops the HTTP server once its connections have finished, failing whatever waits on it.
     pub async fn stop(&mut self) -> Result<(), String> {
         if let Some(tx) = self.shutdown_tx.take() {
             let _ = tx.send(());
         }
 
         if let Some(handle) = self.handle.take() {
-            handle.abort();
             let _ = handle.await;
         }
 
         Ok(())
     }
 
-    /// Gets an OAuth result if available.
-    pub async fn get_result(&self, access_token_id: &str) -> Option<OAuthResult> {
-        let results = self.state.results.lock().await;
-        results.get(access_token_id).cloned()
+    /// Sets how long the server keeps listening with nothing happening on it.
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        self.state
+            .idle_timeout_secs
+            .store(timeout.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Sets how long results, recovery results among them, are kept from now on, results already
+    /// waiting included.
+    pub fn set_result_ttl(&self, ttl: Duration) {
+        self.state
+            .result_ttl_secs
+            .store(ttl.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Removes and returns an OAuth result if available.
+    pub async fn take_result(&self, access_token_id: &str) -> Option<OAuthResult> {
+        let mut results = self.state.live_results().await;
+        results.remove(access_token_id).map(|stored| stored.result)
+    }
+
+    /// Waits for the result for `access_token_id`, for at most `timeout`, and takes it. Doesn't
+    /// borrow the server, so the wait holds no lock on it.
+    pub fn wait_for_result(
+        &self,
+        access_token_id: String,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<OAuthResult, String>> {
+        let state = Arc::clone(&self.state);
+        async move {
+            let woken = {
+                let mut results = state.live_results().await;
+                if let Some(stored) = results.remove(&access_token_id) {
+                    return Ok(stored.result);
+                }
+                state.result_waiters.register(access_token_id.clone())
+            };
+            wait(woken, timeout).await?;
+            let mut results = state.live_results().await;
+            results
+                .remove(&access_token_id)
+                .map(|stored| stored.result)
+                .ok_or_else(|| "OAuth result already taken".to_string())
+        }
+    }
+
+    /// Fails the commands waiting for the result for `access_token_id`.
+    pub fn cancel_wait(&self, access_token_id: &str) {
+        self.state
+            .result_waiters
+            .cancel(&access_token_id.to_string());
+    }
+
+    /// Registers a flow started through Edge, whose browser leg starts at the relay page for
+    /// `auth_url`. Returns its flow id.
+    pub async fn begin_flow(
+        &self,
+        access_token_id: String,
+        recovery: bool,
+        auth_url: String,
+        store_in_vault: bool,
+        renewal: Option<Renewal>,
+    ) -> String {
+        self.state.touch();
+        let flow_id = random_token();
+        let mut pending = self.state.pending.lock().await;
+        pending.insert(
+            random_token(),
+            PendingFlow {
+                flow_id: flow_id.clone(),
+                access_token_id,
+                recovery,
+                auth_url,
+                relayed: false,
+                store_in_vault,
+                renewal,
+            },
+        );
+        flow_id
+    }
+
+    /// Files a native flow under `state` until its callback arrives.
+    pub async fn begin_native_flow(&self, state: String, flow: NativeFlow) {
+        self.state.touch();
+        let mut flows = self.state.native_flows.lock().await;
+        flows.insert(state, flow);
     }
 
-    /// Gets the OAuth-recovery callback params if one has arrived.
-    pub async fn get_recovery_result(&self) -> Option<OAuthRecoveryResult> {
-        let recovery = self.state.recovery.lock().await;
-        recovery.clone()
+    /// Gets the OAuth-recovery callback params for flow `flow_id` if they have arrived, leaving
+    /// them in place.
+    pub async fn get_recovery_result(&self, flow_id: &str) -> Option<OAuthRecoveryResult> {
+        let results = self.state.live_recovery_results().await;
+        results.get(flow_id).map(|stored| stored.result.clone())
+    }
+
+    /// Removes and returns the OAuth-recovery callback params for flow `flow_id` if they have
+    /// arrived.
+    pub async fn take_recovery_result(&self, flow_id: &str) -> Option<OAuthRecoveryResult> {
+        let mut results = self.state.live_recovery_results().await;
+        results.remove(flow_id).map(|stored| stored.result)
+    }
+
+    /// Waits for the OAuth-recovery callback of flow `flow_id`, for at most `timeout`, and takes
+    /// its params. Like `wait_for_result`, holds no lock on the server.
+    pub fn wait_for_recovery_result(
+        &self,
+        flow_id: String,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<OAuthRecoveryResult, String>> {
+        let state = Arc::clone(&self.state);
+        async move {
+            let woken = {
+                let mut results = state.live_recovery_results().await;
+                if let Some(stored) = results.remove(&flow_id) {
+                    return Ok(stored.result);
+                }
+                state.recovery_waiters.register(flow_id.clone())
+            };
+            wait(woken, timeout).await?;
+            let mut results = state.live_recovery_results().await;
+            results
+                .remove(&flow_id)
+                .map(|stored| stored.result)
+                .ok_or_else(|| "OAuth recovery result already taken".to_string())
+        }
+    }
+
+    /// Fails the commands waiting for the OAuth-recovery callback of flow `flow_id`.
+    pub fn cancel_recovery_wait(&self, flow_id: &str) {
+        self.state.recovery_waiters.cancel(&flow_id.to_string());
     }
 }
 
@@ -177,13 +558,22 @@ async fn handle_request(
     app: AppHandle,
     port: u16,
 ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    state.touch();
     let path = req.uri().path();
     let query = req.uri().query().unwrap_or("");
+    let cookies = req
+        .headers()
+        .get(hyper::header::COOKIE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
 
     match path {
-        "/oauth-relay" => handle_oauth_relay(query).await,
-        "/redirect/oauth" => handle_oauth_redirect(query, state, app, port).await,
-        "/redirect/oauth-recovery" => handle_oauth_recovery_redirect(query, state, app, port).await,
+        "/oauth-relay" => handle_oauth_relay(query, state).await,
+        "/redirect/oauth" => handle_oauth_redirect(query, cookies, state, app, port).await,
+        "/redirect/oauth-recovery" => {
+            handle_oauth_recovery_redirect(query, cookies, state, app, port).await
+        }
+        NATIVE_REDIRECT_PATH => handle_native_oauth_redirect(query, state, app, port).await,
         _ => Ok(not_found_response()),
     }
 }
@@ -199,27 +589,94 @@ fn emit_callback(app: &AppHandle, port: u16, path: &str, query: &str) {
     let _ = app.emit(OAUTH_CALLBACK_EVENT, url);
 }
 
-/// Handles the OAuth relay endpoint.
-async fn handle_oauth_relay(query: &str) -> Result<Response<Full<Bytes>>, hyper::Error> {
+/// Handles the OAuth relay endpoint: sends the browser on to a pending flow's `authUrl`, with
+/// the flow's state cookie. Each flow is relayed once; anything else is refused rather than
+/// redirected, so the page is no open redirect either.
+async fn handle_oauth_relay(
+    query: &str,
+    state: Arc<OAuthServerState>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
     let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
         .into_owned()
         .collect();
 
-    match params.get("authUrl") {
-        Some(auth_url) => {
-            let html = get_relay_page_html(auth_url);
-            Ok(html_response(html, StatusCode::OK))
-        }
-        None => Ok(html_response(
+    let Some(auth_url) = params.get("authUrl") else {
+        return Ok(html_response(
             "Missing authUrl parameter".to_string(),
             StatusCode::BAD_REQUEST,
-        )),
+        ));
+    };
+
+    let mut pending = state.pending.lock().await;
+    let flow = pending
+        .iter_mut()
+        .find(|(_, flow)| flow.auth_url == *auth_url && !flow.relayed);
+    let Some((flow_state, flow)) = flow else {
+        return Ok(html_response(
+            get_unknown_flow_html().to_string(),
+            StatusCode::FORBIDDEN,
+        ));
+    };
+    flow.relayed = true;
+
+    let mut response = html_response(get_relay_page_html(auth_url), StatusCode::OK);
+    let cookie = format!(
+        "{}{}=1; Path=/redirect; Max-Age={}; HttpOnly; SameSite=Lax",
+        STATE_COOKIE_PREFIX, flow_state, STATE_COOKIE_MAX_AGE_SECS
+    );
+    if let Ok(cookie) = hyper::header::HeaderValue::from_str(&cookie) {
+        response
+            .headers_mut()
+            .insert(hyper::header::SET_COOKIE, cookie);
     }
+    Ok(response)
+}
+
+/// Removes and returns the pending flow that `cookies` (a `Cookie` header) names and `matches`
+/// accepts, with its state.
+fn claim_flow(
+    pending: &mut HashMap<String, PendingFlow>,
+    cookies: &str,
+    matches: impl Fn(&PendingFlow) -> bool,
+) -> Option<(String, PendingFlow)> {
+    let flow_state = cookies
+        .split(';')
+        .filter_map(|cookie| {
+            cookie
+                .trim()
+                .split('=')
+                .next()?
+                .strip_prefix(STATE_COOKIE_PREFIX)
+        })
+        .find(|flow_state| pending.get(*flow_state).is_some_and(&matches))?
+        .to_string();
+    let flow = pending.remove(&flow_state)?;
+    Some((flow_state, flow))
+}
+
+/// `response`, telling the browser to drop the cookie of the flow it completed.
+fn clearing_cookie(mut response: Response<Full<Bytes>>, flow_state: &str) -> Response<Full<Bytes>> {
+    let cookie = format!(
+        "{}{}=; Path=/redirect; Max-Age=0; HttpOnly; SameSite=Lax",
+        STATE_COOKIE_PREFIX, flow_state
+    );
+    if let Ok(cookie) = hyper::header::HeaderValue::from_str(&cookie) {
+        response
+            .headers_mut()
+            .insert(hyper::header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+/// Response to a callback no pending flow accounts for.
+fn unknown_flow_response() -> Response<Full<Bytes>> {
+    html_response(get_unknown_flow_html().to_string(), StatusCode::FORBIDDEN)
 }
 
 /// Handles the OAuth redirect callback.
 async fn handle_oauth_redirect(
     query: &str,
+    cookies: &str,
     state: Arc<OAuthServerState>,
     app: AppHandle,
     port: u16,
@@ -233,23 +690,41 @@ async fn handle_oauth_redirect(
 
     match (access_token_id, access_token) {
         (Some(id), Some(token)) => {
-            let result = OAuthResult {
+            let claimed = claim_flow(&mut *state.pending.lock().await, cookies, |flow| {
+                !flow.recovery && flow.access_token_id == *id
+            });
+            let Some((flow_state, flow)) = claimed else {
+                return Ok(unknown_flow_response());
+            };
+
+            let mut result = OAuthResult {
                 success: true,
                 access_token_id: id.clone(),
                 access_token: token.clone(),
                 reason: None,
+                refresh_token: None,
+                // Sent when Edge knows; without it the vault can't tell when to renew the token.
+                expires_in: params.get("expiresIn").and_then(|secs| secs.parse().ok()),
+                stored: false,
             };
 
-            // Store the result.
-            let mut results = state.results.lock().await;
-            results.insert(id.clone(), result);
-
-            emit_callback(&app, port, "/redirect/oauth", query);
+            if flow.store_in_vault {
+                result = deposit(&app, result, flow.renewal).await;
+                emit_callback(&app, port, "/redirect/oauth", &callback_query(&result));
+            } else {
+                emit_callback(&app, port, "/redirect/oauth", query);
+            }
+            let response = if result.success {
+                html_response(get_success_html().to_string(), StatusCode::OK)
+            } else {
+                html_response(
+                    get_error_html().to_string(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            };
+            state.store_result(result).await;
 
-            Ok(html_response(
-                get_success_html().to_string(),
-                StatusCode::OK,
-            ))
+            Ok(clearing_cookie(response, &flow_state))
         }
         _ => Ok(html_response(
             get_error_html().to_string(),
@@ -258,6 +733,130 @@ async fn handle_oauth_redirect(
     }
 }
 
+/// Handles the provider's redirect at the end of a native flow: exchanges the code for tokens and
+/// delivers them as if Edge had redirected with them, so the app needs no second code path.
+///
+/// The `state` is looked up and dropped before anything else, so a callback is only ever acted on
+/// once, and only for a flow this app started.
+async fn handle_native_oauth_redirect(
+    query: &str,
+    state: Arc<OAuthServerState>,
+    app: AppHandle,
+    port: u16,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let flow = match params.get("state") {
+        Some(flow_state) => state.native_flows.lock().await.remove(flow_state),
+        None => None,
+    };
+    let Some(flow) = flow else {
+        return Ok(html_response(
+            get_error_html().to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let tokens = match (params.get("code"), params.get("error")) {
+        (_, Some(error)) => Err(match params.get("error_description") {
+            Some(description) => format!("{}: {}", error, description),
+            None => error.clone(),
+        }),
+        (Some(code), None) => exchange_code(&flow, code).await,
+        (None, None) => Err("Missing authorization code".to_string()),
+    };
+
+    let mut result = match tokens {
+        Ok(tokens) => OAuthResult {
+            success: true,
+            access_token_id: flow.access_token_id.clone(),
+            access_token: tokens.access_token,
+            reason: None,
+            refresh_token: tokens.refresh_token,
+            expires_in: tokens.expires_in,
+            stored: false,
+        },
+        Err(reason) => OAuthResult {
+            success: false,
+            access_token_id: flow.access_token_id.clone(),
+            access_token: String::new(),
+            reason: Some(reason),
+            refresh_token: None,
+            expires_in: None,
+            stored: false,
+        },
+    };
+    if flow.store_in_vault {
+        let renewal = Renewal::Provider {
+            token_endpoint: flow.token_endpoint.clone(),
+            client_id: flow.client_id.clone(),
+        };
+        result = deposit(&app, result, Some(renewal)).await;
+    }
+
+    let relayed = callback_query(&result);
+    let success = result.success;
+    state.store_result(result).await;
+    emit_callback(&app, port, "/redirect/oauth", &relayed);
+
+    if success {
+        Ok(html_response(
+            get_success_html().to_string(),
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(html_response(
+            get_error_html().to_string(),
+            StatusCode::BAD_GATEWAY,
+        ))
+    }
+}
+
+/// The query of the callback event for `result`: what Edge's redirect would have carried, minus
+/// tokens that went to the vault.
+fn callback_query(result: &OAuthResult) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query.append_pair("accessTokenId", &result.access_token_id);
+    match &result.reason {
+        Some(reason) => query.append_pair("error", reason),
+        None if result.stored => query.append_pair("stored", "true"),
+        None => query.append_pair("accessToken", &result.access_token),
+    };
+    query.finish()
+}
+
+/// Moves the tokens of a successful `result` to the credential vault, leaving a result that only
+/// says they are there. If they can't be stored the result fails instead: the flow asked for them
+/// not to reach the app. `renewal` is filed with them, for `refresh` to keep them working.
+async fn deposit(app: &AppHandle, result: OAuthResult, renewal: Option<Renewal>) -> OAuthResult {
+    if !result.success {
+        return result;
+    }
+    let now = now_secs();
+    let credential = Credential {
+        access_token_id: result.access_token_id.clone(),
+        access_token: result.access_token.clone(),
+        refresh_token: result.refresh_token.clone(),
+        expires_at: result.expires_in.map(|secs| now + secs),
+        renewal,
+    };
+    let stored = vault::deposit(app, credential).await;
+    OAuthResult {
+        success: stored.is_ok(),
+        access_token_id: result.access_token_id.clone(),
+        access_token: String::new(),
+        reason: stored
+            .as_ref()
+            .err()
+            .map(|e| format!("Failed to store credential: {}", e)),
+        refresh_token: None,
+        expires_in: result.expires_in,
+        stored: stored.is_ok(),
+    }
+}
+
 /// Handles the OAuth-recovery redirect callback (register / recovery purposes).
 ///
 /// Unlike the integration callback there is no token pair to check for: `recovery` carries only a
@@ -265,6 +864,7 @@ async fn handle_oauth_redirect(
 /// the client turns into a message. Anything else is a callback the client cannot act on.
 async fn handle_oauth_recovery_redirect(
     query: &str,
+    cookies: &str,
     state: Arc<OAuthServerState>,
     app: AppHandle,
     port: u16,
@@ -287,14 +887,25 @@ async fn handle_oauth_recovery_redirect(
         ));
     }
 
-    let mut recovery = state.recovery.lock().await;
-    *recovery = Some(result);
+    // The `recovery` purpose carries no `accessTokenId`; when one is present it must match.
+    let claimed = claim_flow(&mut *state.pending.lock().await, cookies, |flow| {
+        flow.recovery
+            && result
+                .access_token_id
+                .as_ref()
+                .map_or(true, |id| *id == flow.access_token_id)
+    });
+    let Some((flow_state, flow)) = claimed else {
+        return Ok(unknown_flow_response());
+    };
+
+    state.store_recovery_result(flow.flow_id, result).await;
 
     emit_callback(&app, port, "/redirect/oauth-recovery", query);
 
-    Ok(html_response(
-        get_success_html().to_string(),
-        StatusCode::OK,
+    Ok(clearing_cookie(
+        html_response(get_success_html().to_string(), StatusCode::OK),
+        &flow_state,
     ))
 }
 
@@ -314,3 +925,175 @@ fn not_found_response() -> Response<Full<Bytes>> {
         .body(Full::new(Bytes::from("Not Found")))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending() -> HashMap<String, PendingFlow> {
+        let flow = |access_token_id: &str, recovery| PendingFlow {
+            flow_id: format!("flow-{}", access_token_id),
+            access_token_id: access_token_id.to_string(),
+            recovery,
+            auth_url: format!("https://provider.example.com/{}", access_token_id),
+            relayed: true,
+            store_in_vault: false,
+            renewal: None,
+        };
+        HashMap::from([
+            ("s1".to_string(), flow("token-1", false)),
+            ("s2".to_string(), flow("token-2", false)),
+            ("s3".to_string(), flow("token-3", true)),
+        ])
+    }
+
+    #[test]
+    fn results_expire_after_their_ttl() {
+        let start = Instant::now();
+        let stored = |id: &str, age: u64| StoredResult {
+            result: OAuthResult {
+                success: true,
+                access_token_id: id.to_string(),
+                access_token: format!("token-for-{}", id),
+                reason: None,
+                refresh_token: None,
+                expires_in: None,
+                stored: false,
+            },
+            received: start + Duration::from_secs(100 - age),
+        };
+        let mut results = HashMap::from([
+            ("fresh".to_string(), stored("fresh", 10)),
+            ("stale".to_string(), stored("stale", 60)),
+        ]);
+
+        let (now, ttl) = (start + Duration::from_secs(100), Duration::from_secs(60));
+        expire(&mut results, ttl, now);
+        assert_eq!(results.keys().collect::<Vec<_>>(), ["fresh"]);
+        expire(&mut results, ttl, now + Duration::from_secs(49));
+        assert!(results.contains_key("fresh"));
+        expire(&mut results, ttl, now + Duration::from_secs(50));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn stops_when_idle_or_done() {
+        let timeout = Duration::from_secs(600);
+        let (brief, long) = (Duration::from_secs(1), LINGER + Duration::from_secs(1));
+        // Started, and no flow yet: only the idle timeout applies.
+        assert!(!should_stop(long, timeout, false, false));
+        assert!(should_stop(timeout, timeout, false, false));
+        // Flows completed, but another is under way.
+        assert!(!should_stop(long, timeout, true, true));
+        assert!(should_stop(timeout, timeout, true, true));
+        // All completed.
+        assert!(!should_stop(brief, timeout, false, true));
+        assert!(should_stop(long, timeout, false, true));
+    }
+
+    #[tokio::test]
+    async fn waits_are_woken_by_their_result() {
+        let server = OAuthServer::new();
+        let result = |id: &str| OAuthResult {
+            success: true,
+            access_token_id: id.to_string(),
+            access_token: "token".to_string(),
+            reason: None,
+            refresh_token: None,
+            expires_in: None,
+            stored: false,
+        };
+        let wait =
+            |id: &str| tokio::spawn(server.wait_for_result(id.to_string(), Duration::from_secs(5)));
+
+        let (a, b) = (wait("a"), wait("b"));
+        tokio::task::yield_now().await;
+        server.state.store_result(result("a")).await;
+        assert_eq!(a.await.unwrap().unwrap().access_token_id, "a");
+        // Taken by the wait.
+        assert!(server.take_result("a").await.is_none());
+
+        server.cancel_wait("b");
+        assert_eq!(b.await.unwrap().unwrap_err(), "Cancelled");
+
+        // A result that's already there is taken straight away.
+        server.state.store_result(result("c")).await;
+        assert!(wait("c").await.unwrap().is_ok());
+
+        let timed_out = server.wait_for_result("d".to_string(), Duration::from_millis(10));
+        assert!(timed_out.await.unwrap_err().starts_with("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn recovery_results_are_kept_per_flow() {
+        let server = OAuthServer::new();
+        let result = |proof: &str| {
+            let mut result = OAuthRecoveryResult::default();
+            result.recovery_proof = Some(proof.to_string());
+            result
+        };
+        let first = tokio::spawn(
+            server.wait_for_recovery_result("flow-1".to_string(), Duration::from_secs(5)),
+        );
+        tokio::task::yield_now().await;
+
+        // A second flow, as from a retry in another window, leaves the first's alone.
+        let state = &server.state;
+        state
+            .store_recovery_result("flow-2".to_string(), result("proof-2"))
+            .await;
+        state
+            .store_recovery_result("flow-1".to_string(), result("proof-1"))
+            .await;
+        let first = first.await.unwrap().unwrap();
+        assert_eq!(first.recovery_proof.as_deref(), Some("proof-1"));
+        assert!(server.get_recovery_result("flow-1").await.is_none());
+        let second = server.get_recovery_result("flow-2").await.unwrap();
+        assert_eq!(second.recovery_proof.as_deref(), Some("proof-2"));
+
+        // Expired like any other result.
+        server.set_result_ttl(Duration::ZERO);
+        assert!(server.take_recovery_result("flow-2").await.is_none());
+    }
+
+    /// The state of the flow `claim_flow` claims.
+    fn claim(
+        pending: &mut HashMap<String, PendingFlow>,
+        cookies: &str,
+        matches: impl Fn(&PendingFlow) -> bool,
+    ) -> Option<String> {
+        claim_flow(pending, cookies, matches).map(|(flow_state, _)| flow_state)
+    }
+
+    #[test]
+    fn callbacks_claim_their_own_flow_once() {
+        let mut pending = pending();
+        let cookies = "other=x; dxos-oauth-s1=1; dxos-oauth-s2=1";
+        let for_token = |id: &'static str| {
+            move |flow: &PendingFlow| !flow.recovery && flow.access_token_id == id
+        };
+
+        assert_eq!(
+            claim(&mut pending, cookies, for_token("token-2")).as_deref(),
+            Some("s2")
+        );
+        assert_eq!(claim(&mut pending, cookies, for_token("token-2")), None);
+        assert!(pending.contains_key("s1"));
+    }
+
+    #[test]
+    fn callbacks_without_a_matching_cookie_are_refused() {
+        let mut pending = pending();
+        let any = |_: &PendingFlow| true;
+        assert_eq!(claim(&mut pending, "", any), None);
+        assert_eq!(claim(&mut pending, "dxos-oauth-guess=1", any), None);
+        // A cookie for one flow doesn't carry a callback for another.
+        let recovery = |flow: &PendingFlow| flow.recovery;
+        assert_eq!(claim(&mut pending, "dxos-oauth-s1=1", recovery), None);
+        assert_eq!(
+            claim(&mut pending, "dxos-oauth-s3=1", recovery).as_deref(),
+            Some("s3")
+        );
+        assert_eq!(pending.len(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+//! Notification channels for commands waiting on a callback (`wait_for_oauth_result`).
+//!
+//! A waiter registers under its key while holding the lock of the results it checked, and a
+//! callback wakes the key's waiters after storing its result, so a result can't land unnoticed
+//! between the check and the registration. Dropping a waiter's sender (`cancel`) wakes it with
+//! an error instead.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use tokio::sync::oneshot;
+
+pub struct Waiters<K> {
+    inner: Mutex<HashMap<K, Vec<oneshot::Sender<()>>>>,
+}
+
+impl<K> Default for Waiters<K> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Waiters<K> {
+    /// A channel that fires when `key` is woken, and fails when it is cancelled.
+    pub fn register(&self, key: K) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        if let Ok(mut inner) = self.inner.lock() {
+            let senders = inner.entry(key).or_default();
+            // Waiters that timed out have dropped their receivers.
+            senders.retain(|sender| !sender.is_closed());
+            senders.push(tx);
+        }
+        rx
+    }
+
+    /// Wakes everything waiting on `key`.
+    pub fn wake(&self, key: &K) {
+        let senders = self
+            .inner
+            .lock()
+            .ok()
+            .and_then(|mut inner| inner.remove(key));
+        for sender in senders.into_iter().flatten() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Fails everything waiting on `key`.
+    pub fn cancel(&self, key: &K) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.remove(key);
+        }
+    }
+
+    /// Fails everything waiting on any key.
+    pub fn cancel_all(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wakes_and_cancels_by_key() {
+        let waiters = Waiters::default();
+        let (a1, a2) = (waiters.register("a"), waiters.register("a"));
+        let b = waiters.register("b");
+        let timed_out = waiters.register("c");
+        drop(timed_out);
+        let c = waiters.register("c");
+
+        waiters.wake(&"a");
+        assert!(a1.await.is_ok());
+        assert!(a2.await.is_ok());
+
+        waiters.cancel(&"b");
+        assert!(b.await.is_err());
+
+        assert_eq!(waiters.inner.lock().unwrap()["c"].len(), 1);
+        waiters.cancel_all();
+        assert!(c.await.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! XChaCha20-Poly1305 sealing shared by the plugin cache's encryption at rest
+//! (`asset_cache::seal`) and the credential vault's file backend (`vault::file`).
+//!
+//! Sealed layout:
+//!   header | nonce (24) | ciphertext + tag
+//!
+//! The header is the caller's: a magic telling its files apart from plaintext and from each
+//! other's, and whatever it needs to pick the key (the plugin cache's key id). Header and nonce
+//! are authenticated as associated data, so neither can be changed without failing `open`.
+//!
+//! Keys are kept hex-encoded in files of their own, written with `write_private`.
+
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+pub use chacha20poly1305::Key;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+pub const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// A fresh random key.
+pub fn generate_key() -> Key {
+    XChaCha20Poly1305::generate_key(&mut OsRng)
+}
+
+/// Encrypts `plaintext` under `key` behind `header`.
+pub fn seal(key: &Key, header: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = Vec::with_capacity(header.len() + NONCE_LEN + plaintext.len() + TAG_LEN);
+    sealed.extend_from_slice(header);
+    sealed.extend_from_slice(&nonce);
+    let ciphertext = XChaCha20Poly1305::new(key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| "encrypt failed".to_string())?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts what `seal` wrote behind a header of `header_len` bytes, which the caller has already
+/// recognized. Fails if anything was tampered with or `key` isn't the one it was sealed with.
+pub fn open(key: &Key, sealed: &[u8], header_len: usize) -> Result<Vec<u8>, String> {
+    if sealed.len() < header_len + NONCE_LEN {
+        return Err("sealed data is truncated".to_string());
+    }
+    let (aad, ciphertext) = sealed.split_at(header_len + NONCE_LEN);
+    let nonce = XNonce::from_slice(&aad[header_len..]);
+    XChaCha20Poly1305::new(key)
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| "decrypt failed".to_string())
+}
+
+/// `key` as lowercase hex, the form key files keep it in.
+pub fn encode_key(key: &Key) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The key `encode_key` wrote as `hex`, or `None` if it isn't one.
+pub fn decode_key(hex: &str) -> Option<Key> {
+    let mut key = Key::default();
+    if hex.len() != 2 * key.len() {
+        return None;
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Writes `contents` to `path` through a temporary sibling that is created readable by the
+/// current user only, so what it holds is never on disk with wider permissions, not even briefly.
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.to_path_buf();
+    tmp.as_mut_os_string().push(".tmp");
+    // A leftover from an interrupted write keeps whatever mode it was created with.
+    let _ = std::fs::remove_file(&tmp);
+    let written = (|| {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        std::io::Write::write_all(&mut options.open(&tmp)?, contents)?;
+        std::fs::rename(&tmp, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_the_header() {
+        let key = generate_key();
+        let sealed = seal(&key, b"\0head", b"secret").unwrap();
+        assert_eq!(open(&key, &sealed, 5).unwrap(), b"secret");
+        assert!(open(&generate_key(), &sealed, 5).is_err());
+
+        let mut relabelled = sealed.clone();
+        relabelled[1] ^= 1;
+        assert!(open(&key, &relabelled, 5).is_err());
+        assert!(open(&key, &sealed[..10], 5).is_err());
+    }
+
+    #[test]
+    fn round_trips_keys() {
+        let key = generate_key();
+        assert_eq!(decode_key(&encode_key(&key)), Some(key));
+        assert!(decode_key("00").is_none());
+        assert!(decode_key(&"zz".repeat(32)).is_none());
+        assert!(decode_key(&"é".repeat(32)).is_none());
+    }
+}
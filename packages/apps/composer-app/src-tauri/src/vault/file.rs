@@ -0,0 +1,214 @@
+//! Credentials in a single sealed file, for machines without a keychain.
+//!
+//! File layout (see `crate::sealing`):
+//!   MAGIC (5) | nonce (24) | XChaCha20-Poly1305 ciphertext + tag
+//!
+//! The plaintext is a JSON object of secrets by id; the header is authenticated as associated
+//! data. The key (32 bytes, hex) is generated on the first write and kept in a file of its own.
+//! Both files are readable by the current user only and replaced atomically.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use super::Backend;
+use crate::sealing::{self, decode_key, encode_key, Key};
+
+const MAGIC: &[u8] = b"\0dxcv";
+
+/// Secrets by id, wiped when dropped.
+#[derive(Default)]
+struct Secrets(BTreeMap<String, String>);
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        self.0.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+pub struct SealedFile {
+    path: PathBuf,
+    key_path: PathBuf,
+    /// Held across each read-modify-write of the file.
+    lock: Mutex<()>,
+}
+
+impl SealedFile {
+    pub fn new(path: PathBuf, key_path: PathBuf) -> Self {
+        Self {
+            path,
+            key_path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The key, generated and saved first if there is none yet and `create` is set.
+    fn key(&self, create: bool) -> Result<Option<Key>, String> {
+        match std::fs::read_to_string(&self.key_path) {
+            Ok(hex) => {
+                let hex = Zeroizing::new(hex);
+                decode_key(hex.trim())
+                    .map(Some)
+                    .ok_or_else(|| "Invalid credential vault key".to_string())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !create {
+                    return Ok(None);
+                }
+                if self.path.exists() {
+                    // Without its key the file can't be read, and a new key would overwrite it.
+                    return Err("Credential vault key is missing".to_string());
+                }
+                let key = sealing::generate_key();
+                write_private(&self.key_path, encode_key(&key).as_bytes())?;
+                Ok(Some(key))
+            }
+            Err(e) => Err(format!("Failed to read credential vault key: {}", e)),
+        }
+    }
+
+    fn read(&self) -> Result<Secrets, String> {
+        let sealed = match std::fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Secrets::default()),
+            Err(e) => return Err(format!("Failed to read credential vault: {}", e)),
+        };
+        let key = self
+            .key(false)?
+            .ok_or_else(|| "Credential vault key is missing".to_string())?;
+        let plaintext = Zeroizing::new(open(&key, &sealed)?);
+        serde_json::from_slice(&plaintext)
+            .map(Secrets)
+            .map_err(|e| format!("Unreadable credential vault: {}", e))
+    }
+
+    fn write(&self, secrets: &Secrets) -> Result<(), String> {
+        let key = self
+            .key(true)?
+            .ok_or_else(|| "Credential vault key is missing".to_string())?;
+        let plaintext = Zeroizing::new(serde_json::to_vec(&secrets.0).map_err(|e| e.to_string())?);
+        write_private(&self.path, &seal(&key, &plaintext)?)
+    }
+}
+
+impl Backend for SealedFile {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn get(&self, id: &str) -> Result<Option<String>, String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        Ok(self.read()?.0.get(id).cloned())
+    }
+
+    fn set(&self, id: &str, secret: &str) -> Result<(), String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        let mut secrets = self.read()?;
+        if let Some(mut previous) = secrets.0.insert(id.to_string(), secret.to_string()) {
+            previous.zeroize();
+        }
+        self.write(&secrets)
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        let mut secrets = self.read()?;
+        let Some(mut removed) = secrets.0.remove(id) else {
+            return Ok(false);
+        };
+        removed.zeroize();
+        self.write(&secrets)?;
+        Ok(true)
+    }
+
+    fn ids(&self) -> Result<Vec<String>, String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        Ok(self.read()?.0.keys().cloned().collect())
+    }
+}
+
+fn seal(key: &Key, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    sealing::seal(key, MAGIC, plaintext)
+        .map_err(|_| "Failed to encrypt credential vault".to_string())
+}
+
+fn open(key: &Key, sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if !sealed.starts_with(MAGIC) {
+        return Err("Not a credential vault".to_string());
+    }
+    sealing::open(key, sealed, MAGIC.len())
+        .map_err(|_| "Credential vault is corrupt or sealed with another key".to_string())
+}
+
+/// Writes `contents` to `path` readable by the current user only (see `sealing::write_private`).
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    sealing::write_private(path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn vault() -> (TempDir, SealedFile) {
+        let dir = TempDir::new().unwrap();
+        let file = SealedFile::new(
+            dir.path().join("data/credentials.vault"),
+            dir.path().join("config/key"),
+        );
+        (dir, file)
+    }
+
+    #[test]
+    fn keeps_secrets_sealed_on_disk() {
+        let (tmp, file) = vault();
+        let dir = tmp.path();
+        assert!(file.ids().unwrap().is_empty());
+        file.set("a", "secret-a").unwrap();
+        file.set("b", "secret-b").unwrap();
+        file.set("a", "secret-a2").unwrap();
+
+        let on_disk = std::fs::read(dir.join("data/credentials.vault")).unwrap();
+        assert!(on_disk.starts_with(MAGIC));
+        assert!(!String::from_utf8_lossy(&on_disk).contains("secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &str| {
+                std::fs::metadata(dir.join(path))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o777
+            };
+            assert_eq!(mode("data/credentials.vault"), 0o600);
+            assert_eq!(mode("config/key"), 0o600);
+        }
+
+        // Another instance, as after a restart.
+        let reopened = SealedFile::new(dir.join("data/credentials.vault"), dir.join("config/key"));
+        assert_eq!(reopened.get("a").unwrap().as_deref(), Some("secret-a2"));
+        assert_eq!(reopened.ids().unwrap(), ["a", "b"]);
+        assert!(reopened.remove("b").unwrap());
+        assert!(!reopened.remove("b").unwrap());
+        assert_eq!(file.ids().unwrap(), ["a"]);
+    }
+
+    #[test]
+    fn refuses_a_vault_without_its_key() {
+        let (tmp, file) = vault();
+        let dir = tmp.path();
+        file.set("a", "secret").unwrap();
+        std::fs::remove_file(dir.join("config/key")).unwrap();
+        assert!(file.get("a").is_err());
+        assert!(file.set("b", "secret").is_err());
+
+        // Sealed under another key.
+        let key = sealing::generate_key();
+        write_private(&dir.join("config/key"), encode_key(&key).as_bytes()).unwrap();
+        assert!(file.get("a").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Credentials in the OS keychain, one entry each.
+//!
+//! Keychains can't enumerate an app's entries portably, so the ids are kept in an entry of their
+//! own (`INDEX_ENTRY`) next to the credentials (`credential:<id>`).
+
+use std::sync::Mutex;
+
+use keyring::Entry;
+
+use super::Backend;
+
+/// Entry listing the ids stored, as a JSON array.
+const INDEX_ENTRY: &str = "index";
+
+pub struct Keychain {
+    service: String,
+    /// Held across each update of a credential and the index.
+    lock: Mutex<()>,
+}
+
+impl Keychain {
+    pub fn new(service: String) -> Self {
+        Self {
+            service,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn entry(&self, name: &str) -> Result<Entry, String> {
+        Entry::new(&self.service, name).map_err(|e| format!("Keychain: {}", e))
+    }
+
+    fn credential(&self, id: &str) -> Result<Entry, String> {
+        self.entry(&format!("credential:{}", id))
+    }
+
+    fn index(&self) -> Result<Vec<String>, String> {
+        match read(&self.entry(INDEX_ENTRY)?)? {
+            Some(index) => serde_json::from_str(&index).map_err(|e| e.to_string()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_index(&self, ids: &[String]) -> Result<(), String> {
+        let index = serde_json::to_string(ids).map_err(|e| e.to_string())?;
+        self.entry(INDEX_ENTRY)?
+            .set_password(&index)
+            .map_err(|e| format!("Keychain: {}", e))
+    }
+}
+
+/// The entry's secret, or `None` if there is no such entry.
+fn read(entry: &Entry) -> Result<Option<String>, String> {
+    match entry.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(format!("Keychain: {}", e)),
+    }
+}
+
+impl Backend for Keychain {
+    fn name(&self) -> &'static str {
+        "keychain"
+    }
+
+    fn get(&self, id: &str) -> Result<Option<String>, String> {
+        read(&self.credential(id)?)
+    }
+
+    fn set(&self, id: &str, secret: &str) -> Result<(), String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        self.credential(id)?
+            .set_password(secret)
+            .map_err(|e| format!("Keychain: {}", e))?;
+        let mut ids = self.index()?;
+        if !ids.iter().any(|stored| stored == id) {
+            ids.push(id.to_string());
+            self.set_index(&ids)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, String> {
+        let _lock = self.lock.lock().map_err(|e| e.to_string())?;
+        let removed = match self.credential(id)?.delete_credential() {
+            Ok(()) => true,
+            Err(keyring::Error::NoEntry) => false,
+            Err(e) => return Err(format!("Keychain: {}", e)),
+        };
+        let mut ids = self.index()?;
+        let count = ids.len();
+        ids.retain(|stored| stored != id);
+        if ids.len() != count {
+            self.set_index(&ids)?;
+        }
+        Ok(removed)
+    }
+
+    fn ids(&self) -> Result<Vec<String>, String> {
+        self.index()
+    }
+}
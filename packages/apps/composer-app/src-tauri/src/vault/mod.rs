@@ -0,0 +1,465 @@
+//! Credentials for integrations, kept by the shell instead of the webview's storage.
+//!
+//! A credential is filed under its access token id and held by a `Backend` as one opaque secret:
+//!
+//!   - `keychain`: the OS keychain (the macOS Keychain, Windows Credential Manager, the Secret
+//!     Service on Linux);
+//!   - `file`: a single file sealed with XChaCha20-Poly1305, with its key in a separate file only
+//!     the current user can read. Used on Linux when no Secret Service answers (headless
+//!     machines, minimal desktops), which is found out on first use. It keeps tokens out of
+//!     backups and copies of the data directory, not away from other programs running as the
+//!     same user.
+//!
+//! OAuth flows can deposit their tokens here directly (`storeInVault`, see `oauth`), so the
+//! webview only learns that a credential exists and asks for it when it needs it. Credentials
+//! that say how (`Renewal`) are renewed before they expire (see `oauth::refresh`).
+
+#[cfg(any(not(any(target_os = "macos", target_os = "windows")), test))]
+mod file;
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "linux"))]
+mod keychain;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::{Mutex, Notify, OnceCell};
+use zeroize::Zeroize;
+
+/// Sealed credentials, under the app data dir (file backend).
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const VAULT_FILE: &str = "credentials.vault";
+/// Their key, under the app config dir so it never travels with them.
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const KEY_FILE: &str = "credentials.key";
+
+/// A token and what is needed to keep it working.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credential {
+    pub access_token_id: String,
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When `access_token` expires (Unix seconds), if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// How `access_token` is renewed; never, without.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renewal: Option<Renewal>,
+}
+
+impl Credential {
+    /// Whether it says how its access token is renewed and holds what that takes.
+    pub fn renewable(&self) -> bool {
+        match &self.renewal {
+            Some(Renewal::Provider { .. }) => self.refresh_token.is_some(),
+            Some(Renewal::Edge { .. }) => true,
+            None => false,
+        }
+    }
+
+    /// When its access token expires, if known and it can be renewed.
+    fn renewable_until(&self) -> Option<u64> {
+        self.expires_at.filter(|_| self.renewable())
+    }
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        self.access_token.zeroize();
+        self.refresh_token.zeroize();
+    }
+}
+
+/// Where a credential's access token is renewed.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "via", rename_all = "camelCase")]
+pub enum Renewal {
+    /// At the provider's token endpoint, with the credential's refresh token (native flows).
+    #[serde(rename_all = "camelCase")]
+    Provider {
+        token_endpoint: String,
+        client_id: String,
+    },
+    /// Through Edge, which keeps the refresh token of the flows it ran. The app authorizes each
+    /// renewal itself (see `oauth::refresh`), so no Edge session is kept here.
+    #[serde(rename_all = "camelCase")]
+    Edge { edge_url: String },
+}
+
+/// Somewhere to keep secrets, by id. Calls may block (on a keychain prompt, say).
+pub trait Backend: Send + Sync {
+    /// Name reported by `credential_vault_backend`.
+    fn name(&self) -> &'static str;
+    fn get(&self, id: &str) -> Result<Option<String>, String>;
+    /// Stores `secret` under `id`, replacing any earlier one.
+    fn set(&self, id: &str, secret: &str) -> Result<(), String>;
+    /// Removes the secret under `id`; whether there was one.
+    fn remove(&self, id: &str) -> Result<bool, String>;
+    fn ids(&self) -> Result<Vec<String>, String>;
+}
+
+/// Credentials held by a backend.
+pub struct Vault {
+    backend: Box<dyn Backend>,
+}
+
+impl Vault {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Self { backend }
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    pub fn store(&self, credential: &Credential) -> Result<(), String> {
+        let mut secret = serde_json::to_string(credential).map_err(|e| e.to_string())?;
+        let stored = self.backend.set(&credential.access_token_id, &secret);
+        secret.zeroize();
+        stored
+    }
+
+    pub fn fetch(&self, access_token_id: &str) -> Result<Option<Credential>, String> {
+        let Some(mut secret) = self.backend.get(access_token_id)? else {
+            return Ok(None);
+        };
+        let credential = serde_json::from_str(&secret)
+            .map_err(|e| format!("Unreadable credential {}: {}", access_token_id, e));
+        secret.zeroize();
+        credential.map(Some)
+    }
+
+    /// Access token ids of the credentials held, sorted.
+    pub fn list(&self) -> Result<Vec<String>, String> {
+        let mut ids = self.backend.ids()?;
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn delete(&self, access_token_id: &str) -> Result<bool, String> {
+        self.backend.remove(access_token_id)
+    }
+}
+
+#[derive(Default)]
+pub struct VaultState {
+    /// The app's vault, opened on first use: its location comes from the `AppHandle`.
+    vault: OnceCell<Arc<Vault>>,
+    /// When each renewable credential expires, by access token id; read on first use (see
+    /// `expiries`).
+    expiries: Mutex<Option<HashMap<String, u64>>>,
+    /// Woken whenever `expiries` changes.
+    expiries_changed: Notify,
+}
+
+/// The app's vault, in the OS keychain where there is one and in a sealed file otherwise.
+pub async fn vault<R: Runtime>(app: &AppHandle<R>) -> Result<Arc<Vault>, String> {
+    let state = app.state::<VaultState>();
+    let vault = state
+        .vault
+        .get_or_try_init(|| {
+            // Picking the backend blocks too: on Linux it asks the Secret Service, which may
+            // prompt to unlock, and may move credentials over.
+            let app = app.clone();
+            blocking(move || Ok(Arc::new(Vault::new(backend(&app)?))))
+        })
+        .await?;
+    Ok(Arc::clone(vault))
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn backend<R: Runtime>(app: &AppHandle<R>) -> Result<Box<dyn Backend>, String> {
+    Ok(Box::new(keychain::Keychain::new(service(app))))
+}
+
+/// The Secret Service if one answers, the sealed file otherwise. Credentials sealed while there
+/// was none move into it once there is.
+#[cfg(target_os = "linux")]
+fn backend<R: Runtime>(app: &AppHandle<R>) -> Result<Box<dyn Backend>, String> {
+    let keychain = keychain::Keychain::new(service(app));
+    let file = sealed_file(app)?;
+    if let Err(e) = keychain.ids() {
+        log::info!(
+            "No Secret Service, keeping credentials in a sealed file: {}",
+            e
+        );
+        return Ok(Box::new(file));
+    }
+    if let Err(e) = move_all(&file, &keychain) {
+        log::warn!(
+            "Failed to move sealed credentials to the Secret Service: {}",
+            e
+        );
+        return Ok(Box::new(file));
+    }
+    Ok(Box::new(keychain))
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
+fn backend<R: Runtime>(app: &AppHandle<R>) -> Result<Box<dyn Backend>, String> {
+    Ok(Box::new(sealed_file(app)?))
+}
+
+/// Keychain service the credentials are filed under.
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "linux"))]
+fn service<R: Runtime>(app: &AppHandle<R>) -> String {
+    // Per identifier, so release channels installed side by side keep apart.
+    format!("{}.credentials", app.config().identifier)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn sealed_file<R: Runtime>(app: &AppHandle<R>) -> Result<file::SealedFile, String> {
+    let path = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    let key_path = app.path().app_config_dir().map_err(|e| e.to_string())?;
+    Ok(file::SealedFile::new(
+        path.join(VAULT_FILE),
+        key_path.join(KEY_FILE),
+    ))
+}
+
+/// Moves every secret `from` holds into `to`. Each one is removed from `from` only once `to` has
+/// it, so a failure part way leaves none lost.
+#[cfg(any(target_os = "linux", test))]
+fn move_all(from: &dyn Backend, to: &dyn Backend) -> Result<(), String> {
+    for id in from.ids()? {
+        if let Some(mut secret) = from.get(&id)? {
+            let stored = to.set(&id, &secret);
+            secret.zeroize();
+            stored?;
+        }
+        from.remove(&id)?;
+    }
+    Ok(())
+}
+
+/// Runs a vault operation off the async runtime: backends block.
+async fn blocking<T: Send + 'static>(
+    operation: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    tauri::async_runtime::spawn_blocking(operation)
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Stores `credential` in the app's vault, replacing any under its access token id.
+pub async fn deposit<R: Runtime>(app: &AppHandle<R>, credential: Credential) -> Result<(), String> {
+    let vault = vault(app).await?;
+    let access_token_id = credential.access_token_id.clone();
+    let expires_at = credential.renewable_until();
+    blocking(move || vault.store(&credential)).await?;
+    note_expiry(app, &access_token_id, expires_at).await;
+    Ok(())
+}
+
+/// Removes the credential under `access_token_id` from the app's vault. Returns whether there was
+/// one.
+pub async fn withdraw<R: Runtime>(
+    app: &AppHandle<R>,
+    access_token_id: &str,
+) -> Result<bool, String> {
+    let vault = vault(app).await?;
+    let id = access_token_id.to_string();
+    let removed = blocking(move || vault.delete(&id)).await?;
+    note_expiry(app, access_token_id, None).await;
+    Ok(removed)
+}
+
+/// When each renewable credential in the app's vault expires, by access token id. The vault is
+/// read once; from then on `deposit` and `withdraw` keep this up to date, so finding the next
+/// credential due never reads the vault (see `oauth::refresh`).
+pub async fn expiries<R: Runtime>(app: &AppHandle<R>) -> Result<HashMap<String, u64>, String> {
+    let state = app.state::<VaultState>();
+    // Held while the vault is read, so no deposit or withdrawal is noted before it is loaded.
+    let mut expiries = state.expiries.lock().await;
+    if expiries.is_none() {
+        let vault = vault(app).await?;
+        *expiries = Some(
+            blocking(move || {
+                let mut loaded = HashMap::new();
+                for id in vault.list()? {
+                    // An unreadable credential can't be renewed either.
+                    if let Ok(Some(credential)) = vault.fetch(&id) {
+                        if let Some(expires_at) = credential.renewable_until() {
+                            loaded.insert(id, expires_at);
+                        }
+                    }
+                }
+                Ok(loaded)
+            })
+            .await?,
+        );
+    }
+    Ok(expiries.clone().unwrap_or_default())
+}
+
+/// Waits for `expiries` to change. A change before the wait began counts, so none is missed
+/// between reading them and waiting.
+pub async fn expiries_changed<R: Runtime>(app: &AppHandle<R>) {
+    app.state::<VaultState>().expiries_changed.notified().await
+}
+
+/// Records when the credential under `access_token_id` expires, `None` for no longer renewable.
+async fn note_expiry<R: Runtime>(
+    app: &AppHandle<R>,
+    access_token_id: &str,
+    expires_at: Option<u64>,
+) {
+    let state = app.state::<VaultState>();
+    if let Some(expiries) = state.expiries.lock().await.as_mut() {
+        match expires_at {
+            Some(expires_at) => expiries.insert(access_token_id.to_string(), expires_at),
+            None => expiries.remove(access_token_id),
+        };
+    }
+    state.expiries_changed.notify_one();
+}
+
+/// The credential filed under `access_token_id` in the app's vault, if any.
+pub async fn lookup<R: Runtime>(
+    app: &AppHandle<R>,
+    access_token_id: &str,
+) -> Result<Option<Credential>, String> {
+    let vault = vault(app).await?;
+    let access_token_id = access_token_id.to_string();
+    blocking(move || vault.fetch(&access_token_id)).await
+}
+
+/// Access token ids of the credentials in the app's vault.
+pub async fn ids<R: Runtime>(app: &AppHandle<R>) -> Result<Vec<String>, String> {
+    let vault = vault(app).await?;
+    blocking(move || vault.list()).await
+}
+
+/// Stores a credential, replacing any under its access token id. It is stored without a
+/// `renewal`, whatever the webview sent: only flows the shell ran say where a credential is
+/// renewed, since renewing hands its refresh token (or the app's Edge session) to that host.
+#[tauri::command]
+pub async fn store_credential<R: Runtime>(
+    app: AppHandle<R>,
+    mut credential: Credential,
+) -> Result<(), String> {
+    credential.renewal = None;
+    deposit(&app, credential).await
+}
+
+/// The credential filed under an access token id, if any, without what only renewing it takes:
+/// the refresh token and how it is renewed stay in the shell.
+#[tauri::command]
+pub async fn get_credential<R: Runtime>(
+    app: AppHandle<R>,
+    access_token_id: String,
+) -> Result<Option<Credential>, String> {
+    let credential = lookup(&app, &access_token_id).await?;
+    Ok(credential.map(|mut credential| {
+        credential.refresh_token.zeroize();
+        credential.renewal = None;
+        credential
+    }))
+}
+
+/// Access token ids of the stored credentials; the tokens themselves take `get_credential`.
+#[tauri::command]
+pub async fn list_credentials<R: Runtime>(app: AppHandle<R>) -> Result<Vec<String>, String> {
+    ids(&app).await
+}
+
+/// Deletes the credential under an access token id. Returns whether there was one.
+#[tauri::command]
+pub async fn delete_credential<R: Runtime>(
+    app: AppHandle<R>,
+    access_token_id: String,
+) -> Result<bool, String> {
+    withdraw(&app, &access_token_id).await
+}
+
+/// Which backend holds the credentials: `keychain` or `file`.
+#[tauri::command]
+pub async fn credential_vault_backend<R: Runtime>(app: AppHandle<R>) -> Result<String, String> {
+    Ok(vault(&app).await?.backend_name().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Memory(Mutex<HashMap<String, String>>);
+
+    impl Backend for Memory {
+        fn name(&self) -> &'static str {
+            "memory"
+        }
+
+        fn get(&self, id: &str) -> Result<Option<String>, String> {
+            Ok(self.0.lock().unwrap().get(id).cloned())
+        }
+
+        fn set(&self, id: &str, secret: &str) -> Result<(), String> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), secret.to_string());
+            Ok(())
+        }
+
+        fn remove(&self, id: &str) -> Result<bool, String> {
+            Ok(self.0.lock().unwrap().remove(id).is_some())
+        }
+
+        fn ids(&self) -> Result<Vec<String>, String> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn moves_secrets_between_backends() {
+        let from = Memory::default();
+        let to = Memory::default();
+        from.set("a", "secret-a").unwrap();
+        from.set("b", "secret-b").unwrap();
+        to.set("c", "secret-c").unwrap();
+        move_all(&from, &to).unwrap();
+        assert!(from.ids().unwrap().is_empty());
+        let mut ids = to.ids().unwrap();
+        ids.sort();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(to.get("a").unwrap().as_deref(), Some("secret-a"));
+    }
+
+    #[test]
+    fn stores_credentials_by_access_token_id() {
+        let vault = Vault::new(Box::<Memory>::default());
+        let credential = |id: &str| Credential {
+            access_token_id: id.to_string(),
+            access_token: format!("token-{}", id),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(1_700_000_000),
+            renewal: Some(Renewal::Provider {
+                token_endpoint: "https://provider.example.com/token".to_string(),
+                client_id: "client".to_string(),
+            }),
+        };
+        vault.store(&credential("b")).unwrap();
+        vault.store(&credential("a")).unwrap();
+        assert_eq!(vault.list().unwrap(), ["a", "b"]);
+
+        let fetched = vault.fetch("a").unwrap().unwrap();
+        assert_eq!(fetched.access_token, "token-a");
+        assert_eq!(fetched.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(fetched.expires_at, Some(1_700_000_000));
+        assert!(matches!(fetched.renewal, Some(Renewal::Provider { .. })));
+        assert_eq!(
+            serde_json::to_value(&fetched).unwrap()["renewal"]["via"],
+            "provider"
+        );
+
+        assert!(vault.delete("a").unwrap());
+        assert!(!vault.delete("a").unwrap());
+        assert!(vault.fetch("a").unwrap().is_none());
+    }
+}
@@ -52,6 +52,16 @@ export type NativeOAuthRequest = {
   loginHint?: string;
 };
 
+/** What the shell's initiate command returns. */
+type InitiatedOAuthFlow = {
+  authUrl: string;
+  /**
+   * Id the shell files the flow's recovery result under (`get_oauth_recovery_result`). Unused here:
+   * recovery callbacks reach the app on {@link nativeOAuthCallbacks} like any other.
+   */
+  flowId: string;
+};
+
 /**
  * Begin an OAuth flow in the system browser.
  *
@@ -66,7 +76,10 @@ export const startNativeOAuth = async (request: NativeOAuthRequest): Promise<voi
   const redirectOrigin = `http://localhost:${port}`;
   log('starting native OAuth flow', { provider: request.provider, purpose: request.purpose, port });
 
-  const authUrl = await invoke<string>('initiate_oauth_flow', { ...request, redirectOrigin });
+  const { authUrl } = await invoke<InitiatedOAuthFlow>('initiate_oauth_flow', {
+    ...request,
+    redirectOrigin,
+  });
 
   // Via the relay page rather than the provider directly, so the browser reaches the provider from
   // the same origin the callback returns to.